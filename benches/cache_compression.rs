@@ -0,0 +1,75 @@
+//! CPU time vs. memory of `--cache-compression zstd` on a default sized read buffer,
+//! run with `cargo bench --bench cache_compression`
+//!
+//! A compressed buffer is decompressed as a whole on every cache hit, so the
+//! decompression time is the overhead added to each read served from memory.
+
+use std::time::{Duration, Instant};
+
+/// Default `--read-buffer-size`
+const CHUNK_SIZE: usize = 10 * 1024 * 1024;
+/// Same level as the file cache
+const COMPRESSION_LEVEL: i32 = 1;
+const ROUNDS: u32 = 10;
+
+fn text_chunk() -> Vec<u8> {
+    let mut data = Vec::with_capacity(CHUNK_SIZE);
+    let mut line = 0u64;
+    while data.len() < CHUNK_SIZE {
+        let text = format!(
+            "{:08} 2021-06-{:02} INFO aliyundrive_fuse::vfs: read file inode={} offset={}\n",
+            line,
+            line % 30 + 1,
+            line % 997,
+            line * 4096
+        );
+        data.extend_from_slice(text.as_bytes());
+        line += 1;
+    }
+    data.truncate(CHUNK_SIZE);
+    data
+}
+
+/// xorshift output, like already compressed media files
+fn random_chunk() -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut data = Vec::with_capacity(CHUNK_SIZE);
+    while data.len() < CHUNK_SIZE {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        data.extend_from_slice(&state.to_le_bytes());
+    }
+    data.truncate(CHUNK_SIZE);
+    data
+}
+
+fn time(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        f();
+    }
+    start.elapsed() / ROUNDS
+}
+
+fn bench(name: &str, chunk: &[u8]) {
+    let compressed = zstd::bulk::compress(chunk, COMPRESSION_LEVEL).unwrap();
+    let compress = time(|| {
+        zstd::bulk::compress(chunk, COMPRESSION_LEVEL).unwrap();
+    });
+    let decompress = time(|| {
+        zstd::bulk::decompress(&compressed, chunk.len()).unwrap();
+    });
+    println!(
+        "{:<8} {:>6.1}% of memory  compress {:>8.2?}  decompress per cache hit {:>8.2?}",
+        name,
+        compressed.len() as f64 * 100.0 / chunk.len() as f64,
+        compress,
+        decompress
+    );
+}
+
+fn main() {
+    bench("text", &text_chunk());
+    bench("random", &random_chunk());
+}
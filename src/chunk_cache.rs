@@ -0,0 +1,114 @@
+//! Memory-mapped ring buffer for chunks of opened files
+//!
+//! Keeps read buffers on fast local storage instead of RAM, the oldest chunks
+//! are overwritten when the ring buffer wraps around.
+use std::fs::OpenOptions;
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use bytes::Bytes;
+use memmap2::MmapMut;
+use tracing::debug;
+
+#[derive(Debug)]
+pub struct DiskChunkCache {
+    map: MmapMut,
+    /// Position of the next write
+    head: usize,
+    // (key, position, length) of cached chunks
+    chunks: Vec<(u64, usize, usize)>,
+}
+
+impl DiskChunkCache {
+    /// Create the backing file of `capacity` bytes, it must not exist yet so that
+    /// another mount's file or a planted symlink is never reused. It's removed
+    /// once mapped, the mapping stays private to this process
+    pub fn open(path: &Path, capacity: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)?;
+        // SAFETY: the file was just created by this process and is unlinked below,
+        // nothing else can truncate or modify it while it's mapped
+        let mapped = file
+            .set_len(capacity)
+            .and_then(|()| unsafe { MmapMut::map_mut(&file) });
+        std::fs::remove_file(path)?;
+        let map = mapped?;
+        Ok(Self {
+            map,
+            head: 0,
+            chunks: Vec::new(),
+        })
+    }
+
+    /// Store a chunk under `key`, replacing the previous one,
+    /// returns `false` if it doesn't fit in the ring buffer
+    pub fn write_chunk(&mut self, key: u64, data: &[u8]) -> bool {
+        self.remove(key);
+        let capacity = self.map.len();
+        if data.is_empty() || data.len() > capacity {
+            return false;
+        }
+        if self.head + data.len() > capacity {
+            self.head = 0;
+        }
+        let (start, end) = (self.head, self.head + data.len());
+        // chunks overlapping the region are the oldest ones
+        self.chunks.retain(|&(evicted, pos, len)| {
+            let keep = pos + len <= start || pos >= end;
+            if !keep {
+                debug!(key = evicted, size = len, "evict chunk");
+            }
+            keep
+        });
+        self.map[start..end].copy_from_slice(data);
+        self.chunks.push((key, start, data.len()));
+        self.head = end;
+        true
+    }
+
+    /// Read a range of the chunk stored under `key`, `None` if it was evicted
+    pub fn read(&self, key: u64, range: Range<usize>) -> Option<Bytes> {
+        let &(_, pos, len) = self.chunks.iter().find(|(k, _, _)| *k == key)?;
+        if range.start > range.end || range.end > len {
+            return None;
+        }
+        let data = &self.map[pos + range.start..pos + range.end];
+        Some(Bytes::copy_from_slice(data))
+    }
+
+    /// Read the whole chunk stored under `key`
+    pub fn get(&self, key: u64) -> Option<Bytes> {
+        let &(_, _, len) = self.chunks.iter().find(|(k, _, _)| *k == key)?;
+        self.read(key, 0..len)
+    }
+
+    pub fn remove(&mut self, key: u64) {
+        self.chunks.retain(|(k, _, _)| *k != key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_never_reuses_an_existing_file() {
+        let path = std::env::temp_dir().join(format!("chunks-test.{}.cache", std::process::id()));
+        std::fs::write(&path, b"keep").unwrap();
+        assert!(DiskChunkCache::open(&path, 4096).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"keep");
+        std::fs::remove_file(&path).unwrap();
+
+        let mut cache = DiskChunkCache::open(&path, 4096).unwrap();
+        assert!(!path.exists());
+        assert!(cache.write_chunk(1, b"hello"));
+        assert_eq!(&cache.read(1, 0..5).unwrap()[..], b"hello");
+    }
+}
@@ -0,0 +1,416 @@
+//! Disk-backed chunk cache
+//!
+//! Downloaded ranges are stored as one file per fixed size chunk, aligned to
+//! multiples of the chunk size. Cache hits update the modification time of
+//! the chunk file, so that eviction doesn't depend on atime, the least recently
+//! used chunks are evicted once the cache grows over the size limit.
+//! Chunks are stored along with the validators of their response, so that
+//! a newer version of the file can reuse them if its content didn't change.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use tracing::{debug, warn};
+
+use crate::drive::{ConditionalDownload, Validators};
+use crate::error::Error;
+
+/// Suffix of the files storing the validators of cached chunks
+const VALIDATORS_SUFFIX: &str = ".validators";
+
+/// File id, offset and size of a chunk
+type ChunkRange = (String, i64, usize);
+
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+    chunk_size: usize,
+    size_limit: u64,
+    evict_interval: Duration,
+    // (file id, offset, size) -> key of the cached chunk with validators
+    versions: Arc<Mutex<HashMap<ChunkRange, String>>>,
+}
+
+impl DiskCache {
+    pub fn new(
+        dir: PathBuf,
+        chunk_size: usize,
+        size_limit: u64,
+        evict_interval: Duration,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut versions = HashMap::new();
+        for entry in fs::read_dir(&dir)?.flatten() {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let Some(key) = name.strip_suffix(VALIDATORS_SUFFIX) else {
+                continue;
+            };
+            if let Some(range) = parse_chunk_key(key) {
+                versions.insert(range, key.to_string());
+            }
+        }
+        Ok(Self {
+            dir,
+            chunk_size: chunk_size.max(1),
+            size_limit,
+            evict_interval,
+            versions: Arc::new(Mutex::new(versions)),
+        })
+    }
+
+    fn versions(&self) -> std::sync::MutexGuard<'_, HashMap<ChunkRange, String>> {
+        self.versions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Max total size in bytes of the cached chunks
+    pub fn size_limit(&self) -> u64 {
+        self.size_limit
+    }
+
+    /// Cache key of the chunk at `offset`, a multiple of `size`,
+    /// chunks of an older version of the file are never hit
+    fn chunk_key(file_id: &str, updated_at: SystemTime, offset: i64, size: usize) -> String {
+        let version = updated_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        format!("{}-{}-{}-{}", file_id, version, offset, size)
+    }
+
+    /// Return `size` bytes at `offset` from the cached chunks covering the range,
+    /// missing chunks are fetched with `fetch(offset, size, validators)` and stored,
+    /// fewer bytes are returned at the end of the file
+    ///
+    /// If the same chunk of an older version of the file is cached, its validators
+    /// are passed to `fetch` and the old chunk is reused when it's not modified.
+    pub fn get_or_fetch(
+        &self,
+        file_id: &str,
+        updated_at: SystemTime,
+        offset: i64,
+        size: usize,
+        mut fetch: impl FnMut(i64, usize, Option<&Validators>) -> Result<ConditionalDownload, Error>,
+    ) -> Result<Bytes, Error> {
+        let chunk_size = self.chunk_size as i64;
+        let start = offset - offset.rem_euclid(chunk_size);
+        let end = offset + size as i64;
+        let mut data = BytesMut::new();
+        let mut chunk_offset = start;
+        while chunk_offset < end {
+            let chunk = self.get_or_fetch_chunk(file_id, updated_at, chunk_offset, &mut fetch)?;
+            let eof = chunk.len() < self.chunk_size;
+            data.extend_from_slice(&chunk);
+            if eof {
+                break;
+            }
+            chunk_offset += chunk_size;
+        }
+        let skip = std::cmp::min((offset - start) as usize, data.len());
+        let data = data.freeze().slice(skip..);
+        Ok(data.slice(..std::cmp::min(size, data.len())))
+    }
+
+    fn get_or_fetch_chunk(
+        &self,
+        file_id: &str,
+        updated_at: SystemTime,
+        offset: i64,
+        mut fetch: impl FnMut(i64, usize, Option<&Validators>) -> Result<ConditionalDownload, Error>,
+    ) -> Result<Bytes, Error> {
+        let size = self.chunk_size;
+        let key = Self::chunk_key(file_id, updated_at, offset, size);
+        let path = self.dir.join(&key);
+        if let Ok(data) = fs::read(&path) {
+            debug!(key = %key, size = data.len(), "disk cache hit");
+            touch(&path);
+            return Ok(Bytes::from(data));
+        }
+        let previous = self.previous_version(file_id, offset, size);
+        let res = fetch(
+            offset,
+            size,
+            previous.as_ref().map(|(_, validators)| validators),
+        )?;
+        let (data, validators) = match (res, previous) {
+            (ConditionalDownload::Modified { data, validators }, _) => (data, validators),
+            (ConditionalDownload::NotModified, Some((previous_key, validators))) => {
+                match fs::read(self.dir.join(&previous_key)) {
+                    Ok(data) => {
+                        debug!(key = %key, previous_key = %previous_key, "chunk not modified, reuse previous version");
+                        self.remove(&previous_key);
+                        (Bytes::from(data), validators)
+                    }
+                    // evicted in the meantime
+                    Err(_) => match fetch(offset, size, None)? {
+                        ConditionalDownload::Modified { data, validators } => (data, validators),
+                        ConditionalDownload::NotModified => return Err(Error::ApiCallFailed),
+                    },
+                }
+            }
+            (ConditionalDownload::NotModified, None) => return Err(Error::ApiCallFailed),
+        };
+        // write to a temporary file first so readers never see partial chunks
+        let tmp_path = self.dir.join(format!(".{}.tmp", key));
+        if let Err(err) = fs::write(&tmp_path, &data).and_then(|_| fs::rename(&tmp_path, &path)) {
+            warn!(key = %key, error = %err, "write disk cache failed");
+            let _ = fs::remove_file(&tmp_path);
+            return Ok(data);
+        }
+        if validators.etag.is_some() || validators.last_modified.is_some() {
+            let res = serde_json::to_vec(&validators)
+                .map_err(io::Error::other)
+                .and_then(|json| fs::write(self.validators_path(&key), json));
+            match res {
+                Ok(()) => {
+                    let range = (file_id.to_string(), offset, size);
+                    self.versions().insert(range, key);
+                }
+                Err(err) => warn!(key = %key, error = %err, "write chunk validators failed"),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Remove a chunk and its validators, returns whether both are gone
+    fn remove(&self, key: &str) -> bool {
+        if let Some(range) = parse_chunk_key(key) {
+            let mut versions = self.versions();
+            if versions.get(&range).is_some_and(|k| k == key) {
+                versions.remove(&range);
+            }
+        }
+        let mut removed = true;
+        for path in [self.dir.join(key), self.validators_path(key)] {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    warn!(path = %path.display(), error = %err, "remove disk cache file failed");
+                    removed = false;
+                }
+            }
+        }
+        removed
+    }
+
+    fn validators_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", key, VALIDATORS_SUFFIX))
+    }
+
+    /// Key and validators of the same range of another version of the file
+    fn previous_version(
+        &self,
+        file_id: &str,
+        offset: i64,
+        size: usize,
+    ) -> Option<(String, Validators)> {
+        let key = self
+            .versions()
+            .get(&(file_id.to_string(), offset, size))?
+            .clone();
+        let validators =
+            serde_json::from_slice(&fs::read(self.validators_path(&key)).ok()?).ok()?;
+        Some((key, validators))
+    }
+
+    /// Evict least recently accessed chunks in background every `evict_interval`
+    pub fn spawn_evictor(&self) {
+        let cache = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(cache.evict_interval);
+            if let Err(err) = cache.evict() {
+                warn!(dir = %cache.dir.display(), error = %err, "evict disk cache failed");
+            }
+        });
+    }
+
+    /// Remove the least recently used chunks along with their validators until the
+    /// cache fits the size limit, files that can't be read or removed are skipped
+    fn evict(&self) -> io::Result<()> {
+        // chunk key -> (last used, size of the chunk and its validators)
+        let mut chunks: HashMap<String, (SystemTime, u64)> = HashMap::new();
+        let mut validators = HashMap::new();
+        let mut total_size = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let (name, metadata) = match entry.and_then(|e| Ok((e.file_name(), e.metadata()?))) {
+                Ok((name, metadata)) if metadata.is_file() => (name, metadata),
+                Ok(_) => continue,
+                Err(err) => {
+                    warn!(dir = %self.dir.display(), error = %err, "read disk cache entry failed");
+                    continue;
+                }
+            };
+            let Ok(name) = name.into_string() else {
+                continue;
+            };
+            total_size += metadata.len();
+            if let Some(key) = name.strip_suffix(VALIDATORS_SUFFIX) {
+                validators.insert(key.to_string(), metadata.len());
+                continue;
+            }
+            let used = metadata.modified().unwrap_or(UNIX_EPOCH);
+            chunks.entry(name).or_insert((used, 0)).1 += metadata.len();
+        }
+        for (key, size) in validators {
+            match chunks.get_mut(&key) {
+                Some((_, chunk_size)) => *chunk_size += size,
+                // the chunk was removed
+                None => {
+                    if self.remove(&key) {
+                        total_size -= size;
+                    }
+                }
+            }
+        }
+        if total_size <= self.size_limit {
+            return Ok(());
+        }
+        let mut chunks: Vec<_> = chunks.into_iter().collect();
+        chunks.sort_unstable_by_key(|(_, (used, _))| *used);
+        let mut evicted = 0;
+        for (key, (_, size)) in chunks {
+            if total_size <= self.size_limit {
+                break;
+            }
+            if self.remove(&key) {
+                total_size -= size;
+                evicted += size;
+            }
+        }
+        debug!(
+            evicted_bytes = evicted,
+            remaining_bytes = total_size,
+            "evict disk cache"
+        );
+        Ok(())
+    }
+}
+
+/// File id, offset and size of a chunk from its key, see [`DiskCache::chunk_key`]
+fn parse_chunk_key(key: &str) -> Option<ChunkRange> {
+    let mut parts = key.rsplitn(4, '-');
+    let size = parts.next()?.parse().ok()?;
+    let offset = parts.next()?.parse().ok()?;
+    let _version = parts.next()?;
+    let file_id = parts.next()?;
+    Some((file_id.to_string(), offset, size))
+}
+
+/// Mark a chunk as used, the modification time orders eviction
+fn touch(path: &Path) {
+    let res = fs::File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(err) = res {
+        debug!(path = %path.display(), error = %err, "update disk cache access time failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn test_cache(name: &str, size_limit: u64) -> DiskCache {
+        let dir = std::env::temp_dir().join(format!("{}.{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        DiskCache::new(dir, 4, size_limit, Duration::from_secs(60)).unwrap()
+    }
+
+    #[test]
+    fn ranges_share_aligned_chunks() {
+        let cache = test_cache("disk-cache-aligned", u64::MAX);
+        let content = b"0123456789";
+        let fetches = Cell::new(0);
+        let fetch = |offset: i64, size: usize, _: Option<&Validators>| {
+            fetches.set(fetches.get() + 1);
+            let start = offset as usize;
+            let end = std::cmp::min(start + size, content.len());
+            Ok(ConditionalDownload::Modified {
+                data: Bytes::copy_from_slice(&content[start..end]),
+                validators: Validators::default(),
+            })
+        };
+        let updated_at = SystemTime::now();
+        let data = cache.get_or_fetch("f", updated_at, 2, 4, fetch).unwrap();
+        assert_eq!(&data[..], b"2345");
+        assert_eq!(fetches.get(), 2);
+
+        let data = cache.get_or_fetch("f", updated_at, 0, 10, fetch).unwrap();
+        assert_eq!(&data[..], content);
+        // only the last chunk wasn't cached yet
+        assert_eq!(fetches.get(), 3);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn unmodified_chunk_of_previous_version_is_reused() {
+        let cache = test_cache("disk-cache-versions", u64::MAX);
+        let validators = Validators {
+            etag: Some("v1".to_string()),
+            last_modified: None,
+        };
+        let old = UNIX_EPOCH + Duration::from_secs(1);
+        cache
+            .get_or_fetch("f", old, 0, 4, |_, _, _| {
+                Ok(ConditionalDownload::Modified {
+                    data: Bytes::from_static(b"data"),
+                    validators: validators.clone(),
+                })
+            })
+            .unwrap();
+
+        // the index is rebuilt from the cache directory
+        let cache =
+            DiskCache::new(cache.dir.clone(), 4, u64::MAX, Duration::from_secs(60)).unwrap();
+        let new = UNIX_EPOCH + Duration::from_secs(2);
+        let data = cache
+            .get_or_fetch("f", new, 0, 4, |_, _, previous| {
+                assert_eq!(previous.unwrap().etag.as_deref(), Some("v1"));
+                Ok(ConditionalDownload::NotModified)
+            })
+            .unwrap();
+        assert_eq!(&data[..], b"data");
+        assert!(!cache
+            .dir
+            .join(DiskCache::chunk_key("f", old, 0, 4))
+            .exists());
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn evict_removes_least_recently_used_chunks() {
+        let cache = test_cache("disk-cache-evict", 8);
+        let now = SystemTime::now();
+        for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+            let path = cache.dir.join(key);
+            fs::write(&path, b"data").unwrap();
+            fs::write(cache.validators_path(key), b"{}").unwrap();
+            let used = now - Duration::from_secs(60 * (3 - i as u64));
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(used)
+                .unwrap();
+        }
+        touch(&cache.dir.join("a"));
+        fs::write(cache.validators_path("gone"), b"{}").unwrap();
+
+        cache.evict().unwrap();
+        assert!(cache.dir.join("a").exists());
+        assert!(!cache.dir.join("b").exists());
+        assert!(!cache.validators_path("b").exists());
+        assert!(!cache.validators_path("gone").exists());
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+//! Logging of every API request, enabled by `--log-api-calls`
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+
+use reqwest::blocking::{Request, Response};
+use reqwest::header::AUTHORIZATION;
+use tracing::info;
+
+use super::HttpClient;
+
+/// [`HttpClient`] that logs requests and responses at INFO level,
+/// so they show up without changing the log filter
+pub struct ApiCallLogger {
+    client: Arc<dyn HttpClient>,
+}
+
+impl ApiCallLogger {
+    pub fn new(client: Arc<dyn HttpClient>) -> Self {
+        Self { client }
+    }
+}
+
+impl fmt::Debug for ApiCallLogger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiCallLogger")
+            .field("client", &self.client)
+            .finish()
+    }
+}
+
+impl HttpClient for ApiCallLogger {
+    fn execute(&self, request: Request) -> reqwest::Result<Response> {
+        let method = request.method().clone();
+        let url = request.url().clone();
+        let body_size = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(|body| body.len());
+        let headers: Vec<(String, String)> = request
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                let value = if name == AUTHORIZATION {
+                    "<redacted>".to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+                (name.to_string(), value)
+            })
+            .collect();
+        info!(method = %method, url = %url, headers = ?headers, body_size = ?body_size, "api request");
+        let start = Instant::now();
+        let res = self.client.execute(request);
+        let elapsed_ms = start.elapsed().as_millis() as u64;
+        match &res {
+            Ok(response) => info!(
+                method = %method,
+                url = %url,
+                status = response.status().as_u16(),
+                body_size = ?response.content_length(),
+                elapsed_ms = elapsed_ms,
+                "api response"
+            ),
+            Err(err) => info!(
+                method = %method,
+                url = %url,
+                error = %err,
+                elapsed_ms = elapsed_ms,
+                "api request failed"
+            ),
+        }
+        res
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tracing::warn;
+
+use super::{
+    AliyunDrive, ApiCallLogger, Credentials, DriveConfig, HttpClient, RequestQueue, Semaphore,
+    ORIGIN, REFERER, UA,
+};
+
+/// Assembles an [`AliyunDrive`], parts not specified are built from the config
+pub struct AliyunDriveBuilder {
+    config: Option<DriveConfig>,
+    http_client: Option<Arc<dyn HttpClient>>,
+    credentials: Option<Credentials>,
+    token_refresher: bool,
+    drive_id: Option<String>,
+}
+
+impl Default for AliyunDriveBuilder {
+    fn default() -> Self {
+        Self {
+            config: None,
+            http_client: None,
+            credentials: None,
+            token_refresher: true,
+            drive_id: None,
+        }
+    }
+}
+
+impl AliyunDriveBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(mut self, config: DriveConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Use this HTTP client for both API and download requests
+    #[allow(dead_code)]
+    pub fn with_http_client(mut self, client: Box<dyn HttpClient>) -> Self {
+        self.http_client = Some(Arc::from(client));
+        self
+    }
+
+    pub fn with_credentials(mut self, refresh_token: String, access_token: Option<String>) -> Self {
+        self.credentials = Some(Credentials {
+            refresh_token,
+            access_token,
+            expires_at: None,
+        });
+        self
+    }
+
+    /// Whether to refresh the access token on build and periodically in background,
+    /// enabled by default
+    #[allow(dead_code)]
+    pub fn with_token_refresher(mut self, enabled: bool) -> Self {
+        self.token_refresher = enabled;
+        self
+    }
+
+    /// Default drive id, it's fetched by the token refresher if not specified
+    pub fn with_drive_id(mut self, drive_id: String) -> Self {
+        self.drive_id = Some(drive_id);
+        self
+    }
+
+    pub fn build(self) -> Result<AliyunDrive> {
+        let config = self.config.context("missing config")?;
+        let credentials = self.credentials.context("missing credentials")?;
+        let (client, download_client): (Arc<dyn HttpClient>, Arc<dyn HttpClient>) =
+            match self.http_client {
+                Some(client) => (client.clone(), client),
+                None => {
+                    let (client, download_client) = build_http_clients(&config)?;
+                    (Arc::new(client), Arc::new(download_client))
+                }
+            };
+        let client: Arc<dyn HttpClient> = if config.log_api_calls {
+            Arc::new(ApiCallLogger::new(client))
+        } else {
+            client
+        };
+        // API requests are executed by a bounded number of workers
+        let client = Arc::new(RequestQueue::new(client, config.api_concurrency));
+        let list_permits = Arc::new(Semaphore::new(config.list_concurrency.max(1)));
+        let mut drive = AliyunDrive {
+            config,
+            client,
+            download_client,
+            credentials: Arc::new(RwLock::new(credentials)),
+            path_cache: Arc::new(RwLock::new(HashMap::new())),
+            list_cache: Arc::new(Mutex::new(HashMap::new())),
+            download_urls: Arc::new(Mutex::new(HashMap::new())),
+            list_permits,
+            revoked: Arc::new(AtomicBool::new(false)),
+            refresh_lock: Arc::new(Mutex::new(())),
+            token_refresh_retries: Arc::new(AtomicU64::new(0)),
+            drive_id: self.drive_id,
+            nick_name: None,
+        };
+        if self.token_refresher {
+            drive.start_token_refresher()?;
+        }
+        Ok(drive)
+    }
+}
+
+/// Build the API and download clients
+fn build_http_clients(
+    config: &DriveConfig,
+) -> Result<(reqwest::blocking::Client, reqwest::blocking::Client)> {
+    let mut headers = HeaderMap::new();
+    headers.insert("Origin", HeaderValue::from_static(ORIGIN));
+    headers.insert("Referer", HeaderValue::from_static(REFERER));
+    for (name, value) in &config.extra_headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("invalid header name {}", name))?;
+        let value = HeaderValue::from_str(value)
+            .with_context(|| format!("invalid value of header {}", name))?;
+        headers.insert(name, value);
+    }
+    let ca_cert = match config.ca_cert.as_ref() {
+        Some(path) => {
+            let pem = fs::read(path)
+                .with_context(|| format!("read CA certificate {} failed", path.display()))?;
+            Some(reqwest::Certificate::from_pem(&pem)?)
+        }
+        None => None,
+    };
+    if config.no_verify_tls {
+        warn!("TLS certificate verification is disabled, connections are not secure");
+    }
+    let client_builder = || {
+        let mut builder = reqwest::blocking::Client::builder()
+            .user_agent(UA)
+            .default_headers(headers.clone())
+            // OSS closes idle connections after 60 seconds,
+            // so we can close idle connections ahead of time to prevent re-using them.
+            // See also https://github.com/hyperium/hyper/issues/2136
+            .pool_idle_timeout(Duration::from_secs(50))
+            .connect_timeout(Duration::from_secs(10))
+            .danger_accept_invalid_certs(config.no_verify_tls);
+        if let Some(cert) = ca_cert.clone() {
+            builder = builder.add_root_certificate(cert);
+        }
+        builder
+    };
+    let client = client_builder().timeout(config.api_timeout).build()?;
+    let download_client = client_builder().timeout(config.download_timeout).build()?;
+    Ok((client, download_client))
+}
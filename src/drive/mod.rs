@@ -1,120 +1,315 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{bail, Context, Result};
 use bytes::Bytes;
-use parking_lot::RwLock;
+use rand::Rng;
 use reqwest::{
-    header::{HeaderMap, HeaderValue},
-    StatusCode,
+    blocking::{Body, Request, Response},
+    header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+    Method, StatusCode,
 };
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use sha1_smol::Sha1;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use tracing::{debug, error, info, warn};
 
+use crate::error::Error;
+
+mod api_log;
+mod builder;
 mod model;
+mod queue;
+mod semaphore;
 
+use api_log::ApiCallLogger;
+pub use builder::AliyunDriveBuilder;
 use model::*;
-pub use model::{AliyunFile, DateTime, FileType};
+pub use model::{
+    AliyunFile, ConditionalDownload, DateTime, FileType, FileVersion, SharedDriveEntry, UserInfo,
+    Validators,
+};
+use queue::RequestQueue;
+use semaphore::Semaphore;
 
 const ORIGIN: &str = "https://www.aliyundrive.com";
 const REFERER: &str = "https://www.aliyundrive.com/";
 const UA: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/92.0.4515.131 Safari/537.36";
+/// Max number of pages fetched by a single listing
+const MAX_LIST_PAGES: usize = 10000;
+/// Max number of listing pages cached for `If-None-Match` requests
+const MAX_LIST_CACHE_ENTRIES: usize = 1000;
+/// Max number of requests in a single batch request
+const MAX_BATCH_SIZE: usize = 100;
+/// Expired download URLs are only pruned once the cache has this many entries
+const MAX_DOWNLOAD_URL_CACHE_ENTRIES: usize = 10000;
+/// Download URLs expiring sooner than this are no longer used
+const DOWNLOAD_URL_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+/// Download URLs are reused for at most this long, even if they expire later
+const DOWNLOAD_URL_CACHE_TTL: Duration = Duration::from_secs(300);
+/// Access tokens expiring sooner than this are refreshed before they're used
+const ACCESS_TOKEN_RENEW_MARGIN: Duration = Duration::from_secs(60);
+/// Size of each part of a multipart upload
+const UPLOAD_PART_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Sort field of directory listings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OrderBy {
+    Name,
+    #[value(name = "updated_at")]
+    UpdatedAt,
+    #[value(name = "created_at")]
+    CreatedAt,
+    Size,
+}
+
+impl OrderBy {
+    fn as_str(self) -> &'static str {
+        match self {
+            OrderBy::Name => "name",
+            OrderBy::UpdatedAt => "updated_at",
+            OrderBy::CreatedAt => "created_at",
+            OrderBy::Size => "size",
+        }
+    }
+}
+
+/// Sort direction of directory listings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OrderDirection {
+    Asc,
+    Desc,
+}
+
+impl OrderDirection {
+    fn as_str(self) -> &'static str {
+        match self {
+            OrderDirection::Asc => "ASC",
+            OrderDirection::Desc => "DESC",
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct DriveConfig {
     pub api_base_url: String,
     pub refresh_token_url: String,
     pub workdir: Option<PathBuf>,
+    /// Shell command printing the refresh token, run before every token refresh
+    /// instead of using the stored refresh token
+    pub refresh_token_command: Option<String>,
     pub app_id: Option<String>,
+    /// Total timeout of API requests
+    pub api_timeout: Duration,
+    /// Total timeout of file download requests
+    pub download_timeout: Duration,
+    /// Max time to wait for the first token refresh
+    pub startup_timeout: Duration,
+    /// Extra PEM-encoded root CA certificate to trust
+    pub ca_cert: Option<PathBuf>,
+    /// Disable TLS certificate verification, insecure
+    pub no_verify_tls: bool,
+    /// Custom HTTP headers sent with every request
+    pub extra_headers: HashMap<String, String>,
+    /// Max number of concurrent directory listing requests
+    pub list_concurrency: usize,
+    /// Max number of concurrent API requests
+    pub api_concurrency: usize,
+    /// Log every API request and response
+    pub log_api_calls: bool,
+    /// Move an existing file to the recycle bin once one with the same name is uploaded
+    pub trash_on_overwrite: bool,
+    /// Sort field of directory listings
+    pub order_by: OrderBy,
+    /// Sort direction of directory listings
+    pub order_direction: OrderDirection,
 }
 
 #[derive(Debug, Clone)]
 struct Credentials {
     refresh_token: String,
     access_token: Option<String>,
+    /// When the access token expires, `None` if unknown
+    expires_at: Option<Instant>,
+}
+
+/// HTTP transport of [`AliyunDrive`], can be replaced to run without network access
+pub trait HttpClient: fmt::Debug + Send + Sync {
+    fn execute(&self, request: Request) -> reqwest::Result<Response>;
+}
+
+impl HttpClient for reqwest::blocking::Client {
+    fn execute(&self, request: Request) -> reqwest::Result<Response> {
+        reqwest::blocking::Client::execute(self, request)
+    }
+}
+
+/// Drive operations used by the filesystem, implemented by [`AliyunDrive`]
+pub trait Drive: fmt::Debug + Clone + Send + Sync + 'static {
+    fn list_all(&self, parent_file_id: &str) -> Result<Vec<AliyunFile>>;
+    fn list_all_in_drive(&self, drive_id: &str, parent_file_id: &str) -> Result<Vec<AliyunFile>>;
+    fn list_all_trash(&self) -> Result<Vec<AliyunFile>>;
+    fn list_shared_with_me(&self) -> Result<Vec<SharedDriveEntry>>;
+    fn list_file_versions(&self, file_id: &str) -> Result<Vec<FileVersion>>;
+    fn get_quota(&self) -> Result<(u64, u64)>;
+    fn get_user_info(&self) -> Result<UserInfo>;
+    /// Default drive id, `None` before the first token refresh
+    fn default_drive_id(&self) -> Option<&str>;
+    /// Whether the server rejected the refresh token after all retries
+    fn is_revoked(&self) -> bool;
+    /// Number of API requests retried after being rejected with 401
+    fn token_refresh_retries(&self) -> u64;
+    fn nick_name(&self) -> Option<&str>;
+    fn get_file(&self, file_id: &str) -> Result<AliyunFile>;
+    fn get_file_in_drive(&self, drive_id: &str, file_id: &str) -> Result<AliyunFile>;
+    fn get_file_path(&self, file_id: &str) -> Result<String>;
+    fn get_download_url(&self, file_id: &str) -> Result<String>;
+    fn get_download_url_in_drive(&self, drive_id: &str, file_id: &str) -> Result<String>;
+    /// Download URLs of files in the default drive, file id -> URL
+    fn batch_get_download_url(&self, file_ids: &[&str]) -> Result<HashMap<String, String>>;
+    /// `None` if no transcoded version of the audio file has finished yet
+    fn get_audio_play_url(&self, file_id: &str) -> Result<Option<String>>;
+    fn download(&self, url: &str, start_pos: u64, size: usize) -> Result<Bytes>;
+    fn download_streaming(&self, url: &str, start_pos: u64, size: usize) -> Result<impl Read>;
+    fn download_conditional(
+        &self,
+        url: &str,
+        start_pos: u64,
+        size: usize,
+        validators: Option<&Validators>,
+    ) -> Result<ConditionalDownload>;
+    /// Upload a file and return its file id
+    fn upload_file(&self, parent_file_id: &str, name: &str, data: Vec<u8>) -> Result<String>;
+    fn move_file(
+        &self,
+        file_id: &str,
+        to_parent_file_id: &str,
+        new_name: Option<&str>,
+    ) -> Result<()>;
+    fn copy_file(
+        &self,
+        file_id: &str,
+        to_parent_file_id: &str,
+        new_name: &str,
+    ) -> Result<AliyunFile>;
+    fn trash_file(&self, file_id: &str) -> Result<()>;
+    fn star_file(&self, file_id: &str) -> Result<()>;
+    fn unstar_file(&self, file_id: &str) -> Result<()>;
+    fn update_file_attrs(&self, file_id: &str, updated_at: &DateTime) -> Result<()>;
+    fn create_folder(&self, parent_file_id: &str, name: &str) -> Result<AliyunFile>;
+    fn create_file(&self, parent_file_id: &str, name: &str) -> Result<AliyunFile>;
 }
 
 #[derive(Debug, Clone)]
 pub struct AliyunDrive {
     config: DriveConfig,
-    client: reqwest::blocking::Client,
+    client: Arc<dyn HttpClient>,
+    download_client: Arc<dyn HttpClient>,
     credentials: Arc<RwLock<Credentials>>,
+    // file_id -> full path
+    path_cache: Arc<RwLock<HashMap<String, String>>>,
+    // (drive_id, parent_file_id, marker) -> ETag and the listing page it belongs to
+    list_cache: Arc<Mutex<HashMap<ListCacheKey, (String, ListFileResponse)>>>,
+    // (drive_id, file_id) -> download URL and when it should no longer be used
+    download_urls: Arc<Mutex<HashMap<DownloadUrlKey, (String, Instant)>>>,
+    // limits concurrent `list()` requests to avoid being rate limited
+    list_permits: Arc<Semaphore>,
+    // set when the refresh token is rejected, e.g. access to the drive is revoked
+    revoked: Arc<AtomicBool>,
+    // serializes token refreshes, a refresh token can only be used once
+    refresh_lock: Arc<Mutex<()>>,
+    // API requests retried after being rejected with 401
+    token_refresh_retries: Arc<AtomicU64>,
     drive_id: Option<String>,
     pub nick_name: Option<String>,
 }
 
 impl AliyunDrive {
-    pub fn new(config: DriveConfig, refresh_token: String) -> Result<Self> {
-        let credentials = Credentials {
-            refresh_token,
-            access_token: None,
-        };
-        let mut headers = HeaderMap::new();
-        headers.insert("Origin", HeaderValue::from_static(ORIGIN));
-        headers.insert("Referer", HeaderValue::from_static(REFERER));
-        let client = reqwest::blocking::Client::builder()
-            .user_agent(UA)
-            .default_headers(headers)
-            // OSS closes idle connections after 60 seconds,
-            // so we can close idle connections ahead of time to prevent re-using them.
-            // See also https://github.com/hyperium/hyper/issues/2136
-            .pool_idle_timeout(Duration::from_secs(50))
-            .connect_timeout(Duration::from_secs(10))
-            .timeout(Duration::from_secs(30))
-            .build()?;
-        let mut drive = Self {
-            config,
-            client,
-            credentials: Arc::new(RwLock::new(credentials)),
-            drive_id: None,
-            nick_name: None,
-        };
-
+    /// Refresh the access token and fetch the default drive id,
+    /// then keep refreshing the token in background
+    fn start_token_refresher(&mut self) -> Result<()> {
+        // requests need an access token even if the drive id is specified
         let (tx, rx) = oneshot::channel();
         // schedule update token task
-        let client = drive.clone();
-        let refresh_token_from_file = if let Some(dir) = drive.config.workdir.as_ref() {
-            fs::read_to_string(dir.join("refresh_token")).ok()
-        } else {
-            None
+        let client = self.clone();
+        let refresh_token_from_file = match self.config.workdir.as_ref() {
+            Some(dir) if self.config.refresh_token_command.is_none() => {
+                fs::read_to_string(dir.join("refresh_token")).ok()
+            }
+            _ => None,
         };
         thread::spawn(move || {
             let mut delay_seconds = 7000;
             match client.do_refresh_token_with_retry(refresh_token_from_file) {
                 Ok(res) => {
-                    // token usually expires in 7200s, refresh earlier
-                    delay_seconds = res.expires_in - 200;
+                    // token usually expires in 7200s, refresh earlier at a random
+                    // time so that instances mounted together don't refresh together
+                    let earliest = res.expires_in.saturating_sub(400);
+                    let latest = res.expires_in.saturating_sub(100);
+                    delay_seconds = if earliest < latest {
+                        rand::thread_rng().gen_range(earliest..latest)
+                    } else {
+                        latest
+                    };
                     if tx.send((res.default_drive_id, res.nick_name)).is_err() {
                         error!("send default drive id failed");
                     }
                 }
                 Err(err) => {
                     error!("refresh token failed: {}", err);
-                    tx.send((String::new(), String::new())).unwrap();
+                    // the receiver is gone if startup already timed out
+                    let _ = tx.send((String::new(), String::new()));
                 }
             }
             loop {
-                thread::sleep(Duration::from_secs(delay_seconds));
+                let delay = Duration::from_secs(delay_seconds);
+                debug!(
+                    delay_seconds = delay_seconds,
+                    next_refresh = %(OffsetDateTime::now_utc() + delay)
+                        .format(&Rfc3339)
+                        .unwrap_or_default(),
+                    "schedule token refresh"
+                );
+                thread::sleep(delay);
+                let _guard = client
+                    .refresh_lock
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
                 if let Err(err) = client.do_refresh_token_with_retry(None) {
                     error!("refresh token failed: {}", err);
                 }
             }
         });
 
-        let (drive_id, nick_name) = rx.recv()?;
+        let (drive_id, nick_name) = match rx.recv_timeout(self.config.startup_timeout) {
+            Ok(res) => res,
+            Err(oneshot::RecvTimeoutError::Timeout) => bail!(
+                "refresh token timed out after {:?}, check your refresh token and network connectivity",
+                self.config.startup_timeout
+            ),
+            Err(err) => return Err(err.into()),
+        };
         if drive_id.is_empty() {
             bail!("get default drive id failed");
         }
-        info!(drive_id = %drive_id, "found default drive");
-        drive.drive_id = Some(drive_id);
-        drive.nick_name = Some(nick_name);
-
-        Ok(drive)
+        match self.drive_id.as_deref() {
+            Some(specified) => info!(drive_id = %specified, "use specified drive"),
+            None => {
+                info!(drive_id = %drive_id, "found default drive");
+                self.drive_id = Some(drive_id);
+            }
+        }
+        self.nick_name = Some(nick_name);
+        Ok(())
     }
 
     fn save_refresh_token(&self, refresh_token: &str) -> Result<()> {
@@ -133,14 +328,11 @@ impl AliyunDrive {
         if let Some(app_id) = self.config.app_id.as_ref() {
             data.insert("app_id", app_id);
         }
-        let res = self
-            .client
-            .post(&self.config.refresh_token_url)
-            .json(&data)
-            .send()?;
+        let url = reqwest::Url::parse(&self.config.refresh_token_url)?;
+        let res = self.client.execute(json_request(url, None, &data)?)?;
         match res.error_for_status_ref() {
             Ok(_) => {
-                let res = res.json::<RefreshTokenResponse>()?;
+                let res = res.json::<WarnOnUnknown<RefreshTokenResponse>>()?.0;
                 info!(
                     refresh_token = %res.refresh_token,
                     nick_name = %res.nick_name,
@@ -161,15 +353,26 @@ impl AliyunDrive {
         refresh_token_from_file: Option<String>,
     ) -> Result<RefreshTokenResponse> {
         let mut last_err = None;
-        let mut refresh_token = self.refresh_token();
+        let mut refresh_token = match self.config.refresh_token_command.as_deref() {
+            Some(command) => run_refresh_token_command(command)?,
+            None => self.refresh_token(),
+        };
         for _ in 0..10 {
             match self.do_refresh_token(&refresh_token) {
                 Ok(res) => {
-                    let mut cred = self.credentials.write();
+                    let mut cred = self
+                        .credentials
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner);
                     cred.refresh_token = res.refresh_token.clone();
                     cred.access_token = Some(res.access_token.clone());
-                    if let Err(err) = self.save_refresh_token(&res.refresh_token) {
-                        error!(error = %err, "save refresh token failed");
+                    cred.expires_at = Some(Instant::now() + Duration::from_secs(res.expires_in));
+                    self.revoked.store(false, Ordering::Relaxed);
+                    // the command is the source of truth for refresh tokens
+                    if self.config.refresh_token_command.is_none() {
+                        if let Err(err) = self.save_refresh_token(&res.refresh_token) {
+                            error!(error = %err, "save refresh token failed");
+                        }
                     }
                     return Ok(res);
                 }
@@ -207,17 +410,62 @@ impl AliyunDrive {
                 }
             }
         }
-        Err(last_err.unwrap())
+        let err = last_err.unwrap();
+        if is_rejected_refresh_token(&err) {
+            error!("refresh token rejected, the drive is no longer accessible");
+            self.revoked.store(true, Ordering::Relaxed);
+        }
+        Err(err)
     }
 
     fn refresh_token(&self) -> String {
-        let cred = self.credentials.read();
+        let cred = self
+            .credentials
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
         cred.refresh_token.clone()
     }
 
     fn access_token(&self) -> Result<String> {
-        let cred = self.credentials.read();
-        cred.access_token.clone().context("missing access_token")
+        if self.access_token_expiring() {
+            let _guard = self
+                .refresh_lock
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            // another request may have refreshed it while waiting for the lock
+            if self.access_token_expiring() {
+                // the background refresher is late, e.g. the machine was suspended
+                warn!("access token is about to expire, refresh it now");
+                if let Err(err) = self.do_refresh_token_with_retry(None) {
+                    error!("refresh token failed: {}", err);
+                }
+            }
+        }
+        self.current_access_token().context("missing access_token")
+    }
+
+    /// Access token as of now, without refreshing it
+    fn current_access_token(&self) -> Option<String> {
+        let cred = self
+            .credentials
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        cred.access_token.clone()
+    }
+
+    /// Whether the access token expires within [`ACCESS_TOKEN_RENEW_MARGIN`],
+    /// a rejected refresh token is not retried on every request
+    fn access_token_expiring(&self) -> bool {
+        if self.revoked.load(Ordering::Relaxed) {
+            return false;
+        }
+        let cred = self
+            .credentials
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        cred.expires_at.is_some_and(|expires_at| {
+            expires_at.saturating_duration_since(Instant::now()) < ACCESS_TOKEN_RENEW_MARGIN
+        })
     }
 
     fn drive_id(&self) -> Result<&str> {
@@ -229,23 +477,49 @@ impl AliyunDrive {
         T: Serialize + ?Sized,
         U: DeserializeOwned,
     {
-        let mut access_token = self.access_token()?;
+        match self.request_if_none_match(url, req, None)? {
+            ApiResponse::Modified { body, .. } => Ok(body),
+            ApiResponse::NotModified => bail!("unexpected 304 Not Modified response"),
+        }
+    }
+
+    /// Send an API request with an optional `If-None-Match` header
+    fn request_if_none_match<T, U>(
+        &self,
+        url: String,
+        req: &T,
+        etag: Option<&str>,
+    ) -> Result<ApiResponse<U>>
+    where
+        T: Serialize + ?Sized,
+        U: DeserializeOwned,
+    {
+        let build_request = |url, access_token: &str| -> Result<Request> {
+            let mut request = json_request(url, Some(access_token), req)?;
+            if let Some(etag) = etag {
+                request
+                    .headers_mut()
+                    .insert(IF_NONE_MATCH, HeaderValue::from_str(etag)?);
+            }
+            Ok(request)
+        };
         let url = reqwest::Url::parse(&url)?;
+        parse_api_response(self.request_with_retry(url, build_request)?)
+    }
+
+    /// Send an API request, a request rejected with 401 is retried once with a new
+    /// access token and one failed with a transient error once after a short wait
+    fn request_with_retry<F>(&self, url: reqwest::Url, build_request: F) -> Result<Response>
+    where
+        F: Fn(reqwest::Url, &str) -> Result<Request>,
+    {
+        let mut access_token = self.access_token()?;
         let res = self
             .client
-            .post(url.clone())
-            .bearer_auth(&access_token)
-            .json(&req)
-            .send()?
+            .execute(build_request(url.clone(), &access_token)?)?
             .error_for_status();
         match res {
-            Ok(res) => {
-                if res.status() == StatusCode::NO_CONTENT {
-                    return Ok(None);
-                }
-                let res = res.json::<U>()?;
-                Ok(Some(res))
-            }
+            Ok(res) => Ok(res),
             Err(err) => {
                 match err.status() {
                     Some(
@@ -262,26 +536,29 @@ impl AliyunDrive {
                         | StatusCode::GATEWAY_TIMEOUT),
                     ) => {
                         if status_code == StatusCode::UNAUTHORIZED {
-                            // refresh token and retry
-                            let token_res = self.do_refresh_token_with_retry(None)?;
-                            access_token = token_res.access_token;
+                            self.token_refresh_retries.fetch_add(1, Ordering::Relaxed);
+                            let _guard = self
+                                .refresh_lock
+                                .lock()
+                                .unwrap_or_else(PoisonError::into_inner);
+                            match self.current_access_token() {
+                                // another request refreshed the token since this one was sent
+                                Some(current) if current != access_token => {
+                                    debug!("access token was refreshed meanwhile, retry with it");
+                                    access_token = current;
+                                }
+                                _ => {
+                                    let token_res = self.do_refresh_token_with_retry(None)?;
+                                    access_token = token_res.access_token;
+                                }
+                            }
                         } else {
                             // wait for a while and retry
                             thread::sleep(Duration::from_secs(1));
                         }
-                        let res = self
-                            .client
-                            .post(url)
-                            .bearer_auth(&access_token)
-                            .json(&req)
-                            .send()
-                            ?
-                            .error_for_status()?;
-                        if res.status() == StatusCode::NO_CONTENT {
-                            return Ok(None);
-                        }
-                        let res = res.json::<U>()?;
-                        Ok(Some(res))
+                        let res = self.client.execute(build_request(url, &access_token)?)?;
+                        check_rate_limited(&res)?;
+                        Ok(res.error_for_status()?)
                     }
                     _ => Err(err.into()),
                 }
@@ -290,21 +567,27 @@ impl AliyunDrive {
     }
 
     pub fn list_all(&self, parent_file_id: &str) -> Result<Vec<AliyunFile>> {
-        let mut files = Vec::new();
-        let mut marker = None;
-        loop {
-            let res = self.list(parent_file_id, marker.as_deref())?;
-            files.extend(res.items.into_iter());
-            if res.next_marker.is_empty() {
-                break;
-            }
-            marker = Some(res.next_marker);
-        }
-        Ok(files)
+        self.list_all_in_drive(self.drive_id()?, parent_file_id)
     }
 
-    pub fn list(&self, parent_file_id: &str, marker: Option<&str>) -> Result<ListFileResponse> {
-        let drive_id = self.drive_id()?;
+    pub fn list_all_in_drive(
+        &self,
+        drive_id: &str,
+        parent_file_id: &str,
+    ) -> Result<Vec<AliyunFile>> {
+        self.request_with_pagination("list file", |marker| {
+            self.list(drive_id, parent_file_id, marker)
+                .map(|res| (res.items, res.next_marker))
+        })
+    }
+
+    pub fn list(
+        &self,
+        drive_id: &str,
+        parent_file_id: &str,
+        marker: Option<&str>,
+    ) -> Result<ListFileResponse> {
+        let _permit = self.list_permits.acquire();
         debug!(drive_id = %drive_id, parent_file_id = %parent_file_id, marker = ?marker, "list file");
         let req = ListFileRequest {
             drive_id,
@@ -315,12 +598,163 @@ impl AliyunDrive {
             image_url_process: "image/resize,w_1920/format,jpeg",
             video_thumbnail_process: "video/snapshot,t_0,f_jpg,ar_auto,w_300",
             fields: "*",
-            order_by: "updated_at",
+            order_by: self.config.order_by.as_str(),
+            order_direction: self.config.order_direction.as_str(),
+            marker,
+        };
+        let key = (
+            drive_id.to_string(),
+            parent_file_id.to_string(),
+            marker.map(str::to_string),
+        );
+        let cached = self
+            .list_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+            .cloned();
+        let res = self.request_if_none_match::<_, WarnOnUnknown<ListFileResponse>>(
+            format!("{}/adrive/v3/file/list", self.config.api_base_url),
+            &req,
+            cached.as_ref().map(|(etag, _)| etag.as_str()),
+        )?;
+        let mut list_cache = self
+            .list_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match (res, cached) {
+            (ApiResponse::NotModified, Some((_, mut res))) => {
+                debug!(drive_id = %drive_id, parent_file_id = %parent_file_id, "list file not modified");
+                // the listing is confirmed to be up to date
+                let now = Instant::now();
+                for item in &mut res.items {
+                    item.fetched_at = now;
+                }
+                Ok(res)
+            }
+            (ApiResponse::NotModified, None) => bail!("unexpected 304 Not Modified response"),
+            (ApiResponse::Modified { body, etag }, _) => {
+                let res = body.context("expect response")?.0;
+                match etag {
+                    Some(etag) => {
+                        if list_cache.len() >= MAX_LIST_CACHE_ENTRIES
+                            && !list_cache.contains_key(&key)
+                        {
+                            // evict an arbitrary entry
+                            if let Some(evicted) = list_cache.keys().next().cloned() {
+                                list_cache.remove(&evicted);
+                            }
+                        }
+                        list_cache.insert(key, (etag, res.clone()));
+                    }
+                    None => {
+                        list_cache.remove(&key);
+                    }
+                }
+                Ok(res)
+            }
+        }
+    }
+
+    pub fn list_all_trash(&self) -> Result<Vec<AliyunFile>> {
+        self.request_with_pagination("list trash", |marker| {
+            self.list_trash(marker)
+                .map(|res| (res.items, res.next_marker))
+        })
+    }
+
+    pub fn list_trash(&self, marker: Option<&str>) -> Result<ListFileResponse> {
+        let drive_id = self.drive_id()?;
+        debug!(drive_id = %drive_id, marker = ?marker, "list trash");
+        let req = ListTrashRequest {
+            drive_id,
+            limit: 200,
+            image_thumbnail_process: "image/resize,w_400/format,jpeg",
+            video_thumbnail_process: "video/snapshot,t_0,f_jpg,ar_auto,w_300",
+            order_by: "name",
             order_direction: "DESC",
             marker,
         };
-        self.request(format!("{}/adrive/v3/file/list", self.config.api_base_url), &req)
-            .and_then(|res| res.context("expect response"))
+        let res: WarnOnUnknown<ListFileResponse> = self
+            .request(
+                format!("{}/v2/recyclebin/list", self.config.api_base_url),
+                &req,
+            )?
+            .context("expect response")?;
+        Ok(res.0)
+    }
+
+    /// Share links created by other users, the API also lists our own links
+    pub fn list_shared_with_me(&self) -> Result<Vec<SharedDriveEntry>> {
+        let user = self.get_user_info()?;
+        let entries = self.request_with_pagination("list share link", |marker| {
+            let req = ListShareLinkRequest {
+                include_canceled: false,
+                limit: 100,
+                order_by: "created_at",
+                order_direction: "DESC",
+                marker,
+            };
+            let res: ListShareLinkResponse = self
+                .request(
+                    format!("{}/v2/share_link/list", self.config.api_base_url),
+                    &req,
+                )?
+                .context("expect response")?;
+            for entry in &res.items {
+                debug!(
+                    share_id = %entry.share_id,
+                    creator_id = %entry.creator_id,
+                    name = %entry.name,
+                    "found share link"
+                );
+            }
+            Ok((res.items, res.next_marker))
+        })?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.creator_id != user.user_id)
+            .collect())
+    }
+
+    /// Historical versions of a file, newest first
+    pub fn list_file_versions(&self, file_id: &str) -> Result<Vec<FileVersion>> {
+        let drive_id = self.drive_id()?;
+        self.request_with_pagination("list file version", |marker| {
+            debug!(file_id = %file_id, marker = ?marker, "list file versions");
+            let req = ListFileVersionRequest {
+                drive_id,
+                file_id,
+                limit: 100,
+                marker,
+            };
+            let res: ListFileVersionResponse = self
+                .request(
+                    format!("{}/v2/file/version/list", self.config.api_base_url),
+                    &req,
+                )?
+                .context("expect response")?;
+            Ok((res.items, res.next_marker))
+        })
+    }
+
+    /// Fetch pages with `fetch_page` until the returned next marker is empty
+    fn request_with_pagination<T, F>(&self, op: &str, mut fetch_page: F) -> Result<Vec<T>>
+    where
+        F: FnMut(Option<&str>) -> Result<(Vec<T>, String)>,
+    {
+        let mut items = Vec::new();
+        let mut marker: Option<String> = None;
+        for page in 0..MAX_LIST_PAGES {
+            debug!(op = %op, page = page, marker = ?marker, "request page");
+            let (page_items, next_marker) = fetch_page(marker.as_deref())?;
+            items.extend(page_items);
+            if next_marker.is_empty() {
+                return Ok(items);
+            }
+            marker = Some(next_marker);
+        }
+        bail!("{} exceeded {} pages", op, MAX_LIST_PAGES)
     }
 
     pub fn download(&self, url: &str, start_pos: u64, size: usize) -> Result<Bytes> {
@@ -329,30 +763,626 @@ impl AliyunDrive {
         let end_pos = start_pos + size as u64 - 1;
         debug!(url = %url, start = start_pos, end = end_pos, "download file");
         let range = format!("bytes={}-{}", start_pos, end_pos);
-        let res = self
-            .client
-            .get(url)
-            .header(RANGE, range)
-            .send()?
-            .error_for_status()?;
+        let mut req = Request::new(Method::GET, reqwest::Url::parse(url)?);
+        req.headers_mut()
+            .insert(RANGE, HeaderValue::from_str(&range)?);
+        let res = self.download_client.execute(req)?;
+        check_rate_limited(&res)?;
+        let res = res.error_for_status()?;
         Ok(res.bytes()?)
     }
 
-    pub fn get_download_url(&self, file_id: &str) -> Result<String> {
-        debug!(file_id = %file_id, "get download url");
-        let req = GetFileDownloadUrlRequest {
-            drive_id: self.drive_id()?,
-            file_id,
+    /// Like [`AliyunDrive::download`], but the response body is read incrementally
+    /// instead of being collected into a single buffer
+    pub fn download_streaming(&self, url: &str, start_pos: u64, size: usize) -> Result<impl Read> {
+        use reqwest::header::RANGE;
+
+        let end_pos = start_pos + size as u64 - 1;
+        debug!(url = %url, start = start_pos, end = end_pos, "download file streaming");
+        let range = format!("bytes={}-{}", start_pos, end_pos);
+        let mut req = Request::new(Method::GET, reqwest::Url::parse(url)?);
+        req.headers_mut()
+            .insert(RANGE, HeaderValue::from_str(&range)?);
+        let res = self.download_client.execute(req)?;
+        check_rate_limited(&res)?;
+        let res = res.error_for_status()?;
+        // never read past the requested range even if the server ignores it
+        Ok(res.take(size as u64))
+    }
+
+    /// Like [`AliyunDrive::download`], but sends the validators of a previous response
+    /// so that the server can answer `304 Not Modified` if the file didn't change
+    pub fn download_conditional(
+        &self,
+        url: &str,
+        start_pos: u64,
+        size: usize,
+        validators: Option<&Validators>,
+    ) -> Result<ConditionalDownload> {
+        use reqwest::header::{IF_MODIFIED_SINCE, LAST_MODIFIED, RANGE};
+
+        let end_pos = start_pos + size as u64 - 1;
+        debug!(url = %url, start = start_pos, end = end_pos, validators = ?validators, "download file conditionally");
+        let range = format!("bytes={}-{}", start_pos, end_pos);
+        let mut req = Request::new(Method::GET, reqwest::Url::parse(url)?);
+        let headers = req.headers_mut();
+        headers.insert(RANGE, HeaderValue::from_str(&range)?);
+        if let Some(validators) = validators {
+            if let Some(etag) = validators.etag.as_deref() {
+                headers.insert(IF_NONE_MATCH, HeaderValue::from_str(etag)?);
+            } else if let Some(last_modified) = validators.last_modified.as_deref() {
+                headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_str(last_modified)?);
+            }
+        }
+        let res = self.download_client.execute(req)?;
+        check_rate_limited(&res)?;
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok(ConditionalDownload::NotModified);
+        }
+        let res = res.error_for_status()?;
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(str::to_string)
         };
+        let validators = Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+        let mut data = Vec::with_capacity(size);
+        res.take(size as u64).read_to_end(&mut data)?;
+        Ok(ConditionalDownload::Modified {
+            data: Bytes::from(data),
+            validators,
+        })
+    }
+
+    pub fn get_download_url(&self, file_id: &str) -> Result<String> {
+        self.get_download_url_in_drive(self.drive_id()?, file_id)
+    }
+
+    pub fn get_download_url_in_drive(&self, drive_id: &str, file_id: &str) -> Result<String> {
+        if let Some(url) = self.cached_download_url(drive_id, file_id) {
+            return Ok(url);
+        }
+        debug!(drive_id = %drive_id, file_id = %file_id, "get download url");
+        let req = GetFileDownloadUrlRequest { drive_id, file_id };
         let res: GetFileDownloadUrlResponse = self
             .request(
                 format!("{}/v2/file/get_download_url", self.config.api_base_url),
                 &req,
             )?
             .context("expect response")?;
+        self.cache_download_url(drive_id, file_id, &res);
         Ok(res.url)
     }
 
+    /// Download URLs of files in the default drive in as few requests as possible,
+    /// URLs that are still cached aren't requested again
+    pub fn batch_get_download_url(&self, file_ids: &[&str]) -> Result<HashMap<String, String>> {
+        let drive_id = self.drive_id()?;
+        let mut urls = HashMap::new();
+        let mut missing = Vec::new();
+        for &file_id in file_ids {
+            match self.cached_download_url(drive_id, file_id) {
+                Some(url) => {
+                    urls.insert(file_id.to_string(), url);
+                }
+                None => missing.push(file_id),
+            }
+        }
+        let headers = HashMap::from([("Content-Type", "application/json")]);
+        for batch in missing.chunks(MAX_BATCH_SIZE) {
+            debug!(drive_id = %drive_id, count = batch.len(), "batch get download url");
+            let req = BatchRequest {
+                requests: batch
+                    .iter()
+                    .map(|&file_id| BatchRequestItem {
+                        body: GetFileDownloadUrlRequest { drive_id, file_id },
+                        headers: headers.clone(),
+                        id: file_id,
+                        method: "POST",
+                        url: "/file/get_download_url",
+                    })
+                    .collect(),
+                resource: "file",
+            };
+            let res: BatchResponse = self
+                .request(format!("{}/v2/batch", self.config.api_base_url), &req)?
+                .context("expect response")?;
+            for item in res.responses {
+                let body = match item.body {
+                    Some(body) if item.status == StatusCode::OK.as_u16() => body,
+                    _ => {
+                        warn!(file_id = %item.id, status = item.status, "batch get download url failed");
+                        continue;
+                    }
+                };
+                match serde_json::from_value::<GetFileDownloadUrlResponse>(body) {
+                    Ok(res) => {
+                        self.cache_download_url(drive_id, &item.id, &res);
+                        urls.insert(item.id, res.url);
+                    }
+                    Err(err) => {
+                        warn!(file_id = %item.id, error = %err, "invalid batch get download url response")
+                    }
+                }
+            }
+        }
+        Ok(urls)
+    }
+
+    /// Streaming URL of the first finished transcoded version of an audio file
+    pub fn get_audio_play_url(&self, file_id: &str) -> Result<Option<String>> {
+        let drive_id = self.drive_id()?;
+        debug!(drive_id = %drive_id, file_id = %file_id, "get audio play url");
+        let req = GetAudioPlayInfoRequest { drive_id, file_id };
+        let res: GetAudioPlayInfoResponse = self
+            .request(
+                format!("{}/v2/file/get_audio_play_info", self.config.api_base_url),
+                &req,
+            )?
+            .context("expect response")?;
+        Ok(res
+            .template_list
+            .into_iter()
+            .filter(|template| template.status == "finished")
+            .find_map(|template| template.url))
+    }
+
+    fn cached_download_url(&self, drive_id: &str, file_id: &str) -> Option<String> {
+        let mut urls = self
+            .download_urls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let key = (drive_id.to_string(), file_id.to_string());
+        match urls.get(&key) {
+            Some((url, expires_at))
+                if *expires_at > Instant::now() && !Self::download_url_is_expired(url) =>
+            {
+                Some(url.clone())
+            }
+            Some(_) => {
+                urls.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Whether a signed download URL expires within a minute,
+    /// `false` if its expiry is unknown
+    pub fn download_url_is_expired(url: &str) -> bool {
+        parse_oss_url_expiry(url)
+            .is_some_and(|expiry| expiry <= Instant::now() + DOWNLOAD_URL_EXPIRY_MARGIN)
+    }
+
+    fn cache_download_url(&self, drive_id: &str, file_id: &str, res: &GetFileDownloadUrlResponse) {
+        // the signed expiry of the URL is authoritative, the `expiration`
+        // field of the response is only used if the URL doesn't have one
+        let expiry = parse_oss_url_expiry(&res.url).or_else(|| {
+            let expiration = res.expiration.parse::<DateTime>().ok()?;
+            let ttl = expiration.duration_since(SystemTime::now()).ok()?;
+            Some(Instant::now() + ttl)
+        });
+        let Some(expiry) = expiry else {
+            return;
+        };
+        // leave a margin for the download itself
+        let ttl = expiry
+            .saturating_duration_since(Instant::now())
+            .saturating_sub(DOWNLOAD_URL_EXPIRY_MARGIN)
+            .min(DOWNLOAD_URL_CACHE_TTL);
+        if ttl.is_zero() {
+            return;
+        }
+        let mut urls = self
+            .download_urls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if urls.len() >= MAX_DOWNLOAD_URL_CACHE_ENTRIES {
+            urls.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        urls.insert(
+            (drive_id.to_string(), file_id.to_string()),
+            (res.url.clone(), now + ttl),
+        );
+    }
+
+    /// Upload a file in parts, resuming a previous upload of the same content if any
+    pub fn upload_file(&self, parent_file_id: &str, name: &str, data: Vec<u8>) -> Result<String> {
+        let data = Bytes::from(data);
+        let size = data.len() as u64;
+        debug!(parent_file_id = %parent_file_id, name = %name, size = size, "upload file");
+        // the existing file is only replaced once the upload succeeded
+        let check_name_mode = if self.config.trash_on_overwrite {
+            "auto_rename"
+        } else {
+            "refresh"
+        };
+        let session_path = self.config.workdir.as_ref().map(|dir| {
+            dir.join("uploads")
+                .join(format!("{}.json", Sha1::from(&data).digest()))
+        });
+        let resumed = session_path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|content| serde_json::from_slice::<UploadSession>(&content).ok())
+            .filter(|session| {
+                session.parent_file_id == parent_file_id
+                    && session.name == name
+                    && session.size == size
+            })
+            .and_then(|mut session| match self.refresh_upload_urls(&mut session) {
+                Ok(_) => Some(session),
+                Err(err) => {
+                    warn!(name = %name, error = %err, "resume upload failed, start over");
+                    None
+                }
+            });
+        let mut session = match resumed {
+            Some(session) => {
+                info!(
+                    name = %name,
+                    uploaded_parts = session.uploaded_parts.len(),
+                    "resume upload"
+                );
+                session
+            }
+            None => self.create_upload_session(
+                parent_file_id,
+                name,
+                size,
+                &detect_content_type(name, &data),
+                check_name_mode,
+            )?,
+        };
+        if let Some(path) = session_path.as_ref() {
+            save_upload_session(path, &session)?;
+        }
+
+        for i in 0..session.part_info_list.len() {
+            let part_number = session.part_info_list[i].part_number;
+            if session.uploaded_parts.contains(&part_number) {
+                continue;
+            }
+            let start = ((part_number - 1) * UPLOAD_PART_SIZE).min(size) as usize;
+            let end = (part_number * UPLOAD_PART_SIZE).min(size) as usize;
+            let part = data.slice(start..end);
+            let upload_url = session.part_info_list[i]
+                .upload_url
+                .clone()
+                .context("missing upload_url")?;
+            if let Err(err) = self.upload_part(&upload_url, part.clone()) {
+                // pre-signed part URLs expire, renew them and retry once
+                warn!(name = %name, part_number = part_number, error = %err, "upload part failed, retry");
+                self.refresh_upload_urls(&mut session)?;
+                let upload_url = session.part_info_list[i]
+                    .upload_url
+                    .clone()
+                    .context("missing upload_url")?;
+                self.upload_part(&upload_url, part)?;
+            }
+            session.uploaded_parts.push(part_number);
+            if let Some(path) = session_path.as_ref() {
+                save_upload_session(path, &session)?;
+            }
+        }
+
+        self.complete_upload_session(&session.file_id, &session.upload_id)?;
+        if let Some(path) = session_path.as_ref() {
+            let _ = fs::remove_file(path);
+        }
+        if self.config.trash_on_overwrite {
+            self.replace_existing_file(parent_file_id, name, &session.file_id)?;
+        }
+        // the file id is kept when overwriting, a cached URL points to the old content
+        self.download_urls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(_, file_id), _| *file_id != session.file_id);
+        Ok(session.file_id)
+    }
+
+    /// An upload with `auto_rename` got another name if the name was taken,
+    /// move the existing file to the recycle bin and give the upload its name
+    fn replace_existing_file(&self, parent_file_id: &str, name: &str, file_id: &str) -> Result<()> {
+        let uploaded = self.get_file(file_id)?;
+        if uploaded.name == name {
+            return Ok(());
+        }
+        let existing = self
+            .list_all(parent_file_id)?
+            .into_iter()
+            .find(|file| file.name == name && matches!(file.r#type, FileType::File));
+        if let Some(file) = existing {
+            info!(file_id = %file.id, name = %name, "trash overwritten file");
+            self.trash_file(&file.id)?;
+        }
+        self.move_file(file_id, parent_file_id, Some(name))
+    }
+
+    /// Create a folder, fails if a file with the same name exists
+    pub fn create_folder(&self, parent_file_id: &str, name: &str) -> Result<AliyunFile> {
+        let drive_id = self.drive_id()?;
+        debug!(drive_id = %drive_id, parent_file_id = %parent_file_id, name = %name, "create folder");
+        let req = CreateFolderRequest {
+            drive_id,
+            parent_file_id,
+            name,
+            r#type: "folder",
+            check_name_mode: "refuse",
+        };
+        let res: CreateFileResponse = self
+            .request(format!("{}/v2/file/create", self.config.api_base_url), &req)?
+            .context("expect response")?;
+        self.get_file(&res.file_id)
+    }
+
+    /// Create an empty file, fails with [`Error::AlreadyExists`] if the name is taken
+    pub fn create_file(&self, parent_file_id: &str, name: &str) -> Result<AliyunFile> {
+        debug!(parent_file_id = %parent_file_id, name = %name, "create file");
+        let session = self.create_upload_session(
+            parent_file_id,
+            name,
+            0,
+            &detect_content_type(name, &[]),
+            "refuse",
+        )?;
+        for part in &session.part_info_list {
+            let upload_url = part.upload_url.as_deref().context("missing upload_url")?;
+            self.upload_part(upload_url, Bytes::new())?;
+        }
+        self.complete_upload_session(&session.file_id, &session.upload_id)?;
+        self.get_file(&session.file_id)
+    }
+
+    /// Start a multipart upload, the session contains pre-signed URLs of all parts,
+    /// `check_name_mode` decides what happens if a file with the same name exists
+    pub fn create_upload_session(
+        &self,
+        parent_file_id: &str,
+        name: &str,
+        size: u64,
+        content_type: &str,
+        check_name_mode: &str,
+    ) -> Result<UploadSession> {
+        let drive_id = self.drive_id()?;
+        let part_count = size.div_ceil(UPLOAD_PART_SIZE).max(1);
+        debug!(drive_id = %drive_id, parent_file_id = %parent_file_id, name = %name, size = size, part_count = part_count, "create upload session");
+        let req = CreateFileRequest {
+            drive_id,
+            parent_file_id,
+            name,
+            r#type: "file",
+            check_name_mode,
+            size,
+            content_type,
+            part_info_list: (1..=part_count)
+                .map(|part_number| UploadPartInfo {
+                    part_number,
+                    upload_url: None,
+                })
+                .collect(),
+        };
+        let res: CreateFileResponse = self
+            .request(format!("{}/v2/file/create", self.config.api_base_url), &req)?
+            .context("expect response")?;
+        // other modes overwrite or rename the existing file
+        if res.exist && check_name_mode == "refuse" {
+            return Err(Error::AlreadyExists.into());
+        }
+        Ok(UploadSession {
+            parent_file_id: parent_file_id.to_string(),
+            name: name.to_string(),
+            size,
+            file_id: res.file_id,
+            upload_id: res.upload_id.context("missing upload_id")?,
+            part_info_list: res.part_info_list,
+            uploaded_parts: Vec::new(),
+        })
+    }
+
+    /// Renew the pre-signed part URLs of an upload session
+    fn refresh_upload_urls(&self, session: &mut UploadSession) -> Result<()> {
+        let drive_id = self.drive_id()?;
+        debug!(drive_id = %drive_id, file_id = %session.file_id, "get upload url");
+        let req = GetUploadUrlRequest {
+            drive_id,
+            file_id: &session.file_id,
+            upload_id: &session.upload_id,
+            part_info_list: session
+                .part_info_list
+                .iter()
+                .map(|part| UploadPartInfo {
+                    part_number: part.part_number,
+                    upload_url: None,
+                })
+                .collect(),
+        };
+        let res: GetUploadUrlResponse = self
+            .request(
+                format!("{}/v2/file/get_upload_url", self.config.api_base_url),
+                &req,
+            )?
+            .context("expect response")?;
+        session.part_info_list = res.part_info_list;
+        Ok(())
+    }
+
+    pub fn upload_part(&self, upload_url: &str, data: Bytes) -> Result<()> {
+        let mut req = Request::new(Method::PUT, reqwest::Url::parse(upload_url)?);
+        *req.body_mut() = Some(Body::from(data));
+        self.download_client.execute(req)?.error_for_status()?;
+        Ok(())
+    }
+
+    pub fn complete_upload_session(&self, file_id: &str, upload_id: &str) -> Result<()> {
+        let drive_id = self.drive_id()?;
+        debug!(drive_id = %drive_id, file_id = %file_id, upload_id = %upload_id, "complete upload");
+        let req = CompleteUploadRequest {
+            drive_id,
+            file_id,
+            upload_id,
+        };
+        let _res: Option<serde_json::Value> = self.request(
+            format!("{}/v2/file/complete", self.config.api_base_url),
+            &req,
+        )?;
+        Ok(())
+    }
+
+    pub fn get_file(&self, file_id: &str) -> Result<AliyunFile> {
+        self.get_file_in_drive(self.drive_id()?, file_id)
+    }
+
+    pub fn get_file_in_drive(&self, drive_id: &str, file_id: &str) -> Result<AliyunFile> {
+        debug!(drive_id = %drive_id, file_id = %file_id, "get file");
+        let req = GetFileRequest { drive_id, file_id };
+        self.request(format!("{}/v2/file/get", self.config.api_base_url), &req)
+            .and_then(|res| res.context("expect response"))
+    }
+
+    /// Resolve the absolute path of a file by walking up its parents,
+    /// the paths of all ancestors on the way are cached too
+    pub fn get_file_path(&self, file_id: &str) -> Result<String> {
+        // (file id, name) from the file up to the first cached ancestor
+        let mut chain = Vec::new();
+        let mut current = file_id.to_string();
+        let mut prefix = String::new();
+        while current != "root" {
+            let cached = self
+                .path_cache
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(&current)
+                .cloned();
+            if let Some(path) = cached {
+                prefix = path;
+                break;
+            }
+            let file = self.get_file(&current)?;
+            let parent = file.parent_file_id.context("missing parent_file_id")?;
+            chain.push((current, file.name));
+            current = parent;
+        }
+        let mut path = prefix;
+        let mut path_cache = self
+            .path_cache
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        for (id, name) in chain.into_iter().rev() {
+            path.push('/');
+            path.push_str(&name);
+            path_cache.insert(id, path.clone());
+        }
+        if path.is_empty() {
+            path.push('/');
+        }
+        Ok(path)
+    }
+
+    pub fn move_file(
+        &self,
+        file_id: &str,
+        to_parent_file_id: &str,
+        new_name: Option<&str>,
+    ) -> Result<()> {
+        let drive_id = self.drive_id()?;
+        debug!(drive_id = %drive_id, file_id = %file_id, to_parent_file_id = %to_parent_file_id, new_name = ?new_name, "move file");
+        let req = MoveFileRequest {
+            drive_id,
+            file_id,
+            to_parent_file_id,
+            new_name,
+        };
+        let _res: Option<serde_json::Value> =
+            self.request(format!("{}/v2/file/move", self.config.api_base_url), &req)?;
+        // paths of the moved file and all of its descendants changed
+        self.path_cache
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        Ok(())
+    }
+
+    /// Copy a file on the server side, returns the new file
+    pub fn copy_file(
+        &self,
+        file_id: &str,
+        to_parent_file_id: &str,
+        new_name: &str,
+    ) -> Result<AliyunFile> {
+        let drive_id = self.drive_id()?;
+        debug!(drive_id = %drive_id, file_id = %file_id, to_parent_file_id = %to_parent_file_id, new_name = %new_name, "copy file");
+        let req = CopyFileRequest {
+            drive_id,
+            file_id,
+            to_parent_file_id,
+            new_name,
+            auto_rename: false,
+        };
+        let res: CopyFileResponse = self
+            .request(format!("{}/v2/file/copy", self.config.api_base_url), &req)?
+            .context("expect response")?;
+        self.get_file(&res.file_id)
+    }
+
+    /// Move a file to the recycle bin
+    pub fn trash_file(&self, file_id: &str) -> Result<()> {
+        let drive_id = self.drive_id()?;
+        debug!(drive_id = %drive_id, file_id = %file_id, "trash file");
+        let req = TrashFileRequest { drive_id, file_id };
+        let _res: Option<serde_json::Value> = self.request(
+            format!("{}/v2/recyclebin/trash", self.config.api_base_url),
+            &req,
+        )?;
+        self.path_cache
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(file_id);
+        Ok(())
+    }
+
+    pub fn star_file(&self, file_id: &str) -> Result<()> {
+        self.set_starred(file_id, true)
+    }
+
+    pub fn unstar_file(&self, file_id: &str) -> Result<()> {
+        self.set_starred(file_id, false)
+    }
+
+    fn set_starred(&self, file_id: &str, starred: bool) -> Result<()> {
+        let drive_id = self.drive_id()?;
+        debug!(drive_id = %drive_id, file_id = %file_id, starred = starred, "update file");
+        let req = UpdateFileRequest {
+            drive_id,
+            file_id,
+            starred: Some(starred),
+            updated_at: None,
+        };
+        let _res: Option<serde_json::Value> =
+            self.request(format!("{}/v2/file/update", self.config.api_base_url), &req)?;
+        Ok(())
+    }
+
+    /// Update metadata of a file without uploading its content again
+    pub fn update_file_attrs(&self, file_id: &str, updated_at: &DateTime) -> Result<()> {
+        let drive_id = self.drive_id()?;
+        debug!(drive_id = %drive_id, file_id = %file_id, updated_at = ?updated_at, "update file");
+        let req = UpdateFileRequest {
+            drive_id,
+            file_id,
+            starred: None,
+            updated_at: Some(updated_at),
+        };
+        let _res: Option<serde_json::Value> =
+            self.request(format!("{}/v2/file/update", self.config.api_base_url), &req)?;
+        Ok(())
+    }
+
     pub fn get_quota(&self) -> Result<(u64, u64)> {
         let drive_id = self.drive_id()?;
         let mut data = HashMap::new();
@@ -362,4 +1392,328 @@ impl AliyunDrive {
             .context("expect response")?;
         Ok((res.used_size, res.total_size))
     }
+
+    pub fn get_user_info(&self) -> Result<UserInfo> {
+        debug!("get user info");
+        let res: UserInfo = self
+            .request(
+                format!("{}/v2/user/get", self.config.api_base_url),
+                &HashMap::<String, String>::new(),
+            )?
+            .context("expect response")?;
+        Ok(res)
+    }
+
+    /// All drives owned by the account
+    pub fn list_drives(&self) -> Result<Vec<DriveInfo>> {
+        let user = self.get_user_info()?;
+        debug!(user_id = %user.user_id, "list drives");
+        let req = ListDriveRequest {
+            owner: &user.user_id,
+        };
+        let res: ListDriveResponse = self
+            .request(format!("{}/v2/drive/list", self.config.api_base_url), &req)?
+            .context("expect response")?;
+        Ok(res.items)
+    }
+
+    /// Check that the API still accepts our token with a minimal request
+    pub fn check_auth(&self) -> Result<()> {
+        let Err(err) = self.get_user_info() else {
+            return Ok(());
+        };
+        let unauthorized = err
+            .downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status())
+            == Some(StatusCode::UNAUTHORIZED);
+        if unauthorized || self.revoked.load(Ordering::Relaxed) {
+            bail!("the refresh token has expired or been revoked, please log in again to get a new one");
+        }
+        Err(err.context("check authentication failed"))
+    }
+}
+
+impl Drive for AliyunDrive {
+    fn list_all(&self, parent_file_id: &str) -> Result<Vec<AliyunFile>> {
+        AliyunDrive::list_all(self, parent_file_id)
+    }
+
+    fn list_all_in_drive(&self, drive_id: &str, parent_file_id: &str) -> Result<Vec<AliyunFile>> {
+        AliyunDrive::list_all_in_drive(self, drive_id, parent_file_id)
+    }
+
+    fn list_all_trash(&self) -> Result<Vec<AliyunFile>> {
+        AliyunDrive::list_all_trash(self)
+    }
+
+    fn list_shared_with_me(&self) -> Result<Vec<SharedDriveEntry>> {
+        AliyunDrive::list_shared_with_me(self)
+    }
+
+    fn list_file_versions(&self, file_id: &str) -> Result<Vec<FileVersion>> {
+        AliyunDrive::list_file_versions(self, file_id)
+    }
+
+    fn get_quota(&self) -> Result<(u64, u64)> {
+        AliyunDrive::get_quota(self)
+    }
+
+    fn get_user_info(&self) -> Result<UserInfo> {
+        AliyunDrive::get_user_info(self)
+    }
+
+    fn default_drive_id(&self) -> Option<&str> {
+        self.drive_id.as_deref()
+    }
+
+    fn is_revoked(&self) -> bool {
+        self.revoked.load(Ordering::Relaxed)
+    }
+
+    fn token_refresh_retries(&self) -> u64 {
+        self.token_refresh_retries.load(Ordering::Relaxed)
+    }
+
+    fn nick_name(&self) -> Option<&str> {
+        self.nick_name.as_deref()
+    }
+
+    fn get_file(&self, file_id: &str) -> Result<AliyunFile> {
+        AliyunDrive::get_file(self, file_id)
+    }
+
+    fn get_file_in_drive(&self, drive_id: &str, file_id: &str) -> Result<AliyunFile> {
+        AliyunDrive::get_file_in_drive(self, drive_id, file_id)
+    }
+
+    fn get_file_path(&self, file_id: &str) -> Result<String> {
+        AliyunDrive::get_file_path(self, file_id)
+    }
+
+    fn get_download_url(&self, file_id: &str) -> Result<String> {
+        AliyunDrive::get_download_url(self, file_id)
+    }
+
+    fn get_download_url_in_drive(&self, drive_id: &str, file_id: &str) -> Result<String> {
+        AliyunDrive::get_download_url_in_drive(self, drive_id, file_id)
+    }
+
+    fn batch_get_download_url(&self, file_ids: &[&str]) -> Result<HashMap<String, String>> {
+        AliyunDrive::batch_get_download_url(self, file_ids)
+    }
+
+    fn get_audio_play_url(&self, file_id: &str) -> Result<Option<String>> {
+        AliyunDrive::get_audio_play_url(self, file_id)
+    }
+
+    fn download(&self, url: &str, start_pos: u64, size: usize) -> Result<Bytes> {
+        AliyunDrive::download(self, url, start_pos, size)
+    }
+
+    fn download_streaming(&self, url: &str, start_pos: u64, size: usize) -> Result<impl Read> {
+        AliyunDrive::download_streaming(self, url, start_pos, size)
+    }
+
+    fn download_conditional(
+        &self,
+        url: &str,
+        start_pos: u64,
+        size: usize,
+        validators: Option<&Validators>,
+    ) -> Result<ConditionalDownload> {
+        AliyunDrive::download_conditional(self, url, start_pos, size, validators)
+    }
+
+    fn upload_file(&self, parent_file_id: &str, name: &str, data: Vec<u8>) -> Result<String> {
+        AliyunDrive::upload_file(self, parent_file_id, name, data)
+    }
+
+    fn move_file(
+        &self,
+        file_id: &str,
+        to_parent_file_id: &str,
+        new_name: Option<&str>,
+    ) -> Result<()> {
+        AliyunDrive::move_file(self, file_id, to_parent_file_id, new_name)
+    }
+
+    fn copy_file(
+        &self,
+        file_id: &str,
+        to_parent_file_id: &str,
+        new_name: &str,
+    ) -> Result<AliyunFile> {
+        AliyunDrive::copy_file(self, file_id, to_parent_file_id, new_name)
+    }
+
+    fn trash_file(&self, file_id: &str) -> Result<()> {
+        AliyunDrive::trash_file(self, file_id)
+    }
+
+    fn star_file(&self, file_id: &str) -> Result<()> {
+        AliyunDrive::star_file(self, file_id)
+    }
+
+    fn unstar_file(&self, file_id: &str) -> Result<()> {
+        AliyunDrive::unstar_file(self, file_id)
+    }
+
+    fn update_file_attrs(&self, file_id: &str, updated_at: &DateTime) -> Result<()> {
+        AliyunDrive::update_file_attrs(self, file_id, updated_at)
+    }
+
+    fn create_folder(&self, parent_file_id: &str, name: &str) -> Result<AliyunFile> {
+        AliyunDrive::create_folder(self, parent_file_id, name)
+    }
+
+    fn create_file(&self, parent_file_id: &str, name: &str) -> Result<AliyunFile> {
+        AliyunDrive::create_file(self, parent_file_id, name)
+    }
+}
+
+/// Whether the refresh token endpoint rejected the token itself,
+/// as opposed to a network error or rate limiting
+fn is_rejected_refresh_token(err: &anyhow::Error) -> bool {
+    match err
+        .downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+    {
+        Some(status) => status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS,
+        None => false,
+    }
+}
+
+/// Expiry of a signed OSS URL from its `x-oss-expires` query parameter in unix seconds
+fn parse_oss_url_expiry(url: &str) -> Option<Instant> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let expires = url
+        .query_pairs()
+        .find(|(key, _)| key.eq_ignore_ascii_case("x-oss-expires"))?
+        .1
+        .parse::<u64>()
+        .ok()?;
+    let expires = SystemTime::UNIX_EPOCH + Duration::from_secs(expires);
+    let now = Instant::now();
+    Some(match expires.duration_since(SystemTime::now()) {
+        Ok(remaining) => now + remaining,
+        // already expired
+        Err(_) => now,
+    })
+}
+
+/// Run a shell command and use its trimmed stdout as the refresh token
+fn run_refresh_token_command(command: &str) -> Result<String> {
+    debug!(command = %command, "run refresh token command");
+    let output = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stderr(process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("run refresh token command `{}` failed", command))?;
+    if !output.status.success() {
+        bail!(
+            "refresh token command `{}` failed with {}",
+            command,
+            output.status
+        );
+    }
+    let refresh_token = String::from_utf8(output.stdout)
+        .context("refresh token command printed invalid UTF-8")?
+        .trim()
+        .to_string();
+    if refresh_token.is_empty() {
+        bail!("refresh token command `{}` printed nothing", command);
+    }
+    Ok(refresh_token)
+}
+
+/// Drive id, parent file id and page marker of a listing page
+type ListCacheKey = (String, String, Option<String>);
+
+/// Drive id and file id of a cached download URL
+type DownloadUrlKey = (String, String);
+
+/// Response of an API request, `NotModified` is only returned with `If-None-Match`
+enum ApiResponse<U> {
+    Modified {
+        body: Option<U>,
+        etag: Option<String>,
+    },
+    NotModified,
+}
+
+fn parse_api_response<U: DeserializeOwned>(res: Response) -> Result<ApiResponse<U>> {
+    match res.status() {
+        StatusCode::NOT_MODIFIED => Ok(ApiResponse::NotModified),
+        StatusCode::NO_CONTENT => Ok(ApiResponse::Modified {
+            body: None,
+            etag: None,
+        }),
+        _ => {
+            let etag = res
+                .headers()
+                .get(ETAG)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            let body = validate_api_response(&res.json::<serde_json::Value>()?)?;
+            Ok(ApiResponse::Modified {
+                body: Some(body),
+                etag,
+            })
+        }
+    }
+}
+
+/// Build a POST request with a JSON body
+fn json_request<T: Serialize + ?Sized>(
+    url: reqwest::Url,
+    access_token: Option<&str>,
+    body: &T,
+) -> Result<Request> {
+    let mut req = Request::new(Method::POST, url);
+    let headers = req.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    if let Some(access_token) = access_token {
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", access_token))?,
+        );
+    }
+    *req.body_mut() = Some(Body::from(serde_json::to_vec(body)?));
+    Ok(req)
+}
+
+fn save_upload_session(path: &Path, session: &UploadSession) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_vec(session)?)?;
+    Ok(())
+}
+
+/// Detect MIME type by sniffing file content, falls back to file extension
+fn detect_content_type(name: &str, data: &[u8]) -> String {
+    let head = &data[..std::cmp::min(data.len(), 256)];
+    match infer::get(head) {
+        Some(kind) => kind.mime_type().to_string(),
+        None => mime_guess::from_path(name)
+            .first_or_octet_stream()
+            .to_string(),
+    }
+}
+
+/// Return `Error::RateLimited` for 429 responses, honoring the `Retry-After` header
+fn check_rate_limited(res: &reqwest::blocking::Response) -> Result<()> {
+    if res.status() != StatusCode::TOO_MANY_REQUESTS {
+        return Ok(());
+    }
+    let retry_after = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(1));
+    warn!(retry_after = ?retry_after, "rate limited");
+    Err(Error::RateLimited { retry_after }.into())
 }
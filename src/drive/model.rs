@@ -1,9 +1,175 @@
-use std::ops;
-use std::time::SystemTime;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Instant, SystemTime};
+use std::{fmt, ops, str};
 
 use ::time::{format_description::well_known::Rfc3339, OffsetDateTime};
-use serde::{Deserialize, Deserializer, Serialize};
+use bytes::Bytes;
+use serde::de::{DeserializeOwned, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::{debug, enabled, Level};
 
+/// Error response returned by the API instead of the expected response shape
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiError {
+    #[serde(default)]
+    pub code: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "api error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Deserialize an API response, detecting error responses before schema mismatches
+pub fn validate_api_response<T: DeserializeOwned>(json: &serde_json::Value) -> anyhow::Result<T> {
+    if json.get("code").is_some() || json.get("message").is_some() {
+        // Only treat it as an error if it doesn't match the expected response
+        if let Ok(res) = T::deserialize(json) {
+            return Ok(res);
+        }
+        let err: ApiError = serde_json::from_value(json.clone())?;
+        return Err(err.into());
+    }
+    T::deserialize(json).map_err(|err| {
+        anyhow::Error::new(err).context("unexpected API response format, the API may have changed")
+    })
+}
+
+/// Fields the API is known to return that are deliberately not deserialized
+const IGNORED_FIELDS: &[&str] = &[
+    "content_hash",
+    "content_hash_name",
+    "content_type",
+    "crc64_hash",
+    "creator_id",
+    "domain_id",
+    "encrypt_mode",
+    "file_extension",
+    "hidden",
+    "labels",
+    "last_modifier_id",
+    "local_created_at",
+    "local_modified_at",
+    "mime_extension",
+    "mime_type",
+    "punish_flag",
+    "revision_id",
+    "status",
+    "sync_device_flag",
+    "sync_flag",
+    "sync_meta",
+    "trashed",
+    "upload_id",
+    "url",
+    "user_meta",
+    "user_tags",
+    "video_preview_metadata",
+];
+
+/// Deserializes `T` as usual, but with debug logging enabled logs fields of the
+/// response that `T` doesn't know about, each field is only reported once
+#[derive(Debug, Clone)]
+pub struct WarnOnUnknown<T>(pub T);
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for WarnOnUnknown<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if !enabled!(Level::DEBUG) {
+            return T::deserialize(deserializer).map(Self);
+        }
+        let value = serde_json::Value::deserialize(deserializer)?;
+        if let (Some(map), Some((name, fields))) = (value.as_object(), struct_fields::<T>()) {
+            let unknown = map
+                .keys()
+                .map(String::as_str)
+                .filter(|key| !fields.contains(key) && !IGNORED_FIELDS.contains(key));
+            report_unknown_fields(name, unknown);
+        }
+        T::deserialize(&value)
+            .map(Self)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Deserialize a list of `T` with [`WarnOnUnknown`]
+fn warn_on_unknown_items<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let items = Vec::<WarnOnUnknown<T>>::deserialize(deserializer)?;
+    Ok(items.into_iter().map(|item| item.0).collect())
+}
+
+fn report_unknown_fields<'a>(name: &str, fields: impl Iterator<Item = &'a str>) {
+    static REPORTED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+    let mut reported = REPORTED.lock().unwrap_or_else(PoisonError::into_inner);
+    let new_fields: Vec<&str> = fields
+        .filter(|field| reported.insert(format!("{}.{}", name, field)))
+        .collect();
+    if !new_fields.is_empty() {
+        debug!(
+            r#type = %name,
+            fields = ?new_fields,
+            "unknown fields in API response"
+        );
+    }
+}
+
+/// Name and field names of a struct with derived `Deserialize`, `None` for other types
+fn struct_fields<T: DeserializeOwned>() -> Option<(&'static str, &'static [&'static str])> {
+    // derived implementations pass their fields to `deserialize_struct`
+    struct Introspect<'a>(&'a mut Option<(&'static str, &'static [&'static str])>);
+
+    impl<'de> Deserializer<'de> for Introspect<'_> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(serde::de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = Some((name, fields));
+            Err(serde::de::Error::custom("introspection only"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+            byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map
+            enum identifier ignored_any
+        }
+    }
+
+    let mut fields = None;
+    let _ = T::deserialize(Introspect(&mut fields));
+    fields
+}
+
+/// Validators of a downloaded range, sent back to skip downloading unchanged data
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Result of a download with validators of a previous response
+#[derive(Debug)]
+pub enum ConditionalDownload {
+    Modified { data: Bytes, validators: Validators },
+    NotModified,
+}
+
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct RefreshTokenResponse {
     pub access_token: String,
@@ -12,9 +178,30 @@ pub struct RefreshTokenResponse {
     pub token_type: String,
     pub user_id: String,
     pub nick_name: String,
+    #[serde(default)]
+    pub user_name: String,
+    #[serde(default)]
+    pub avatar: String,
+    #[serde(default)]
+    pub membership_type: String,
     pub default_drive_id: String,
 }
 
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserInfo {
+    pub user_id: String,
+    #[serde(default)]
+    pub nick_name: String,
+    #[serde(default)]
+    pub user_name: String,
+    #[serde(default)]
+    pub avatar: String,
+    /// Membership level, for example `vip`
+    #[serde(default)]
+    pub membership_type: String,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct ListFileRequest<'a> {
     pub drive_id: &'a str,
@@ -32,16 +219,55 @@ pub struct ListFileRequest<'a> {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ListFileResponse {
+    #[serde(deserialize_with = "warn_on_unknown_items")]
     pub items: Vec<AliyunFile>,
     pub next_marker: String,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct ListTrashRequest<'a> {
+    pub drive_id: &'a str,
+    pub limit: u64,
+    pub image_thumbnail_process: &'a str,
+    pub video_thumbnail_process: &'a str,
+    pub order_by: &'a str,
+    pub order_direction: &'a str,
+    pub marker: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListShareLinkRequest<'a> {
+    pub include_canceled: bool,
+    pub limit: u64,
+    pub order_by: &'a str,
+    pub order_direction: &'a str,
+    pub marker: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListShareLinkResponse {
+    pub items: Vec<SharedDriveEntry>,
+    pub next_marker: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SharedDriveEntry {
+    pub share_id: String,
+    #[serde(rename = "creator")]
+    pub creator_id: String,
+    #[serde(rename = "share_name")]
+    pub name: String,
+    pub drive_id: String,
+    pub file_id: String,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct GetFileDownloadUrlRequest<'a> {
     pub drive_id: &'a str,
     pub file_id: &'a str,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct GetFileDownloadUrlResponse {
     pub url: String,
@@ -49,12 +275,232 @@ pub struct GetFileDownloadUrlResponse {
     pub expiration: String,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct GetAudioPlayInfoRequest<'a> {
+    pub drive_id: &'a str,
+    pub file_id: &'a str,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetAudioPlayInfoResponse {
+    #[serde(default)]
+    pub template_list: Vec<AudioTemplate>,
+}
+
+/// Transcoded version of an audio file
+#[derive(Debug, Clone, Deserialize)]
+pub struct AudioTemplate {
+    /// `finished` once transcoding is done
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchRequest<'a, T> {
+    pub requests: Vec<BatchRequestItem<'a, T>>,
+    pub resource: &'a str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchRequestItem<'a, T> {
+    pub body: T,
+    pub headers: HashMap<&'a str, &'a str>,
+    pub id: &'a str,
+    pub method: &'a str,
+    pub url: &'a str,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchResponse {
+    pub responses: Vec<BatchResponseItem>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchResponseItem {
+    pub id: String,
+    pub status: u16,
+    #[serde(default)]
+    pub body: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreateFileRequest<'a> {
+    pub drive_id: &'a str,
+    pub parent_file_id: &'a str,
+    pub name: &'a str,
+    pub r#type: &'a str,
+    pub check_name_mode: &'a str,
+    pub size: u64,
+    pub content_type: &'a str,
+    pub part_info_list: Vec<UploadPartInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreateFolderRequest<'a> {
+    pub drive_id: &'a str,
+    pub parent_file_id: &'a str,
+    pub name: &'a str,
+    pub r#type: &'a str,
+    pub check_name_mode: &'a str,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadPartInfo {
+    pub part_number: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateFileResponse {
+    pub file_id: String,
+    /// A file with the same name exists, only set with `check_name_mode` `refuse`
+    #[serde(default)]
+    pub exist: bool,
+    #[serde(default)]
+    pub upload_id: Option<String>,
+    #[serde(default)]
+    pub part_info_list: Vec<UploadPartInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GetUploadUrlRequest<'a> {
+    pub drive_id: &'a str,
+    pub file_id: &'a str,
+    pub upload_id: &'a str,
+    pub part_info_list: Vec<UploadPartInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetUploadUrlResponse {
+    pub part_info_list: Vec<UploadPartInfo>,
+}
+
+/// State of a multipart upload, persisted so it can be resumed after a restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub parent_file_id: String,
+    pub name: String,
+    pub size: u64,
+    pub file_id: String,
+    pub upload_id: String,
+    pub part_info_list: Vec<UploadPartInfo>,
+    /// Part numbers already uploaded
+    #[serde(default)]
+    pub uploaded_parts: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompleteUploadRequest<'a> {
+    pub drive_id: &'a str,
+    pub file_id: &'a str,
+    pub upload_id: &'a str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GetFileRequest<'a> {
+    pub drive_id: &'a str,
+    pub file_id: &'a str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateFileRequest<'a> {
+    pub drive_id: &'a str,
+    pub file_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starred: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<&'a DateTime>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MoveFileRequest<'a> {
+    pub drive_id: &'a str,
+    pub file_id: &'a str,
+    pub to_parent_file_id: &'a str,
+    pub new_name: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CopyFileRequest<'a> {
+    pub drive_id: &'a str,
+    pub file_id: &'a str,
+    pub to_parent_file_id: &'a str,
+    pub new_name: &'a str,
+    pub auto_rename: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CopyFileResponse {
+    pub file_id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashFileRequest<'a> {
+    pub drive_id: &'a str,
+    pub file_id: &'a str,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct GetDriveResponse {
     pub total_size: u64,
     pub used_size: u64,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct ListDriveRequest<'a> {
+    pub owner: &'a str,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListDriveResponse {
+    pub items: Vec<DriveInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DriveInfo {
+    pub drive_id: String,
+    /// For example `normal`
+    #[serde(default)]
+    pub drive_type: String,
+    #[serde(default)]
+    pub drive_name: String,
+    #[serde(default)]
+    pub total_size: u64,
+    #[serde(default)]
+    pub used_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListFileVersionRequest<'a> {
+    pub drive_id: &'a str,
+    pub file_id: &'a str,
+    pub limit: u64,
+    pub marker: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListFileVersionResponse {
+    pub items: Vec<FileVersion>,
+    #[serde(default)]
+    pub next_marker: String,
+}
+
+/// Historical version of a file
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileVersion {
+    #[serde(alias = "revision_id")]
+    pub version_id: String,
+    #[serde(default)]
+    pub size: u64,
+    pub created_at: DateTime,
+    /// Download URL of the content of this version
+    #[serde(default, alias = "download_url")]
+    pub url: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct DateTime(SystemTime);
 
@@ -66,6 +512,43 @@ impl<'a> Deserialize<'a> for DateTime {
     }
 }
 
+impl Serialize for DateTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let dt = OffsetDateTime::from(self.0)
+            .format(&Rfc3339)
+            .map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&dt)
+    }
+}
+
+impl DateTime {
+    pub fn now() -> Self {
+        Self(SystemTime::now())
+    }
+
+    /// Format as an RFC 3339 timestamp
+    pub fn to_rfc3339(&self) -> String {
+        OffsetDateTime::from(self.0)
+            .format(&Rfc3339)
+            .unwrap_or_default()
+    }
+}
+
+impl str::FromStr for DateTime {
+    type Err = ::time::error::Parse;
+
+    /// Parse an RFC 3339 (ISO 8601) timestamp
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(OffsetDateTime::parse(s, &Rfc3339)?.into()))
+    }
+}
+
+impl From<SystemTime> for DateTime {
+    fn from(time: SystemTime) -> Self {
+        Self(time)
+    }
+}
+
 impl ops::Deref for DateTime {
     type Target = SystemTime;
 
@@ -74,14 +557,14 @@ impl ops::Deref for DateTime {
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum FileType {
     Folder,
     File,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AliyunFile {
     pub name: String,
     #[serde(rename = "file_id")]
@@ -91,6 +574,101 @@ pub struct AliyunFile {
     pub updated_at: DateTime,
     #[serde(default)]
     pub size: u64,
+    #[serde(default)]
+    pub parent_file_id: Option<String>,
+    /// Drive the file lives in, `None` means the default drive
+    #[serde(default)]
+    pub drive_id: Option<String>,
+    /// File classification like `image`, `video`, `audio`, `doc` and `others`
+    #[serde(default)]
+    pub category: Option<String>,
+    /// Time the file was moved to the recycle bin
+    #[serde(default)]
+    pub trashed_at: Option<DateTime>,
+    #[serde(default)]
+    pub starred: bool,
+    /// Thumbnail URL of image and video files
+    #[serde(default)]
+    pub thumbnail: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub video_media_metadata: Option<VideoMediaMetadata>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub image_media_metadata: Option<ImageMediaMetadata>,
+    /// Time the metadata was fetched from the drive
+    #[serde(skip, default = "Instant::now")]
+    pub fetched_at: Instant,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VideoMediaMetadata {
+    /// Duration in seconds
+    #[serde(default, deserialize_with = "deserialize_number")]
+    pub duration: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub width: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub height: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageMediaMetadata {
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub width: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub height: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub exif_info: Option<ExifInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExifInfo {
+    #[serde(default, deserialize_with = "deserialize_number")]
+    pub gps_latitude: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_number")]
+    pub gps_longitude: Option<f64>,
+    /// Camera manufacturer
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub make: Option<String>,
+    /// Camera model
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub model: Option<String>,
+    /// Time the photo was taken, as recorded by the camera
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub date_time_original: Option<String>,
+}
+
+/// Accept both numbers and numeric strings, the API returns either,
+/// anything else like an empty string is treated as missing
+fn deserialize_number<'a, D: Deserializer<'a>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Number {
+        Float(f64),
+        String(String),
+        Other(serde::de::IgnoredAny),
+    }
+
+    Ok(match Option::<Number>::deserialize(deserializer)? {
+        Some(Number::Float(n)) => Some(n),
+        Some(Number::String(s)) => s.trim().parse().ok(),
+        Some(Number::Other(_)) | None => None,
+    })
+}
+
+/// Metadata is informational only, a value of an unexpected type is treated as
+/// missing instead of failing the whole listing, numbers may come as strings
+fn deserialize_lenient<'a, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'a>,
+    T: DeserializeOwned,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    if let Ok(parsed) = T::deserialize(&value) {
+        return Ok(Some(parsed));
+    }
+    Ok(value
+        .as_str()
+        .and_then(|s| serde_json::from_str(s.trim()).ok()))
 }
 
 impl AliyunFile {
@@ -103,6 +681,52 @@ impl AliyunFile {
             created_at: DateTime(now),
             updated_at: DateTime(now),
             size: 0,
+            parent_file_id: None,
+            drive_id: None,
+            category: None,
+            trashed_at: None,
+            starred: false,
+            thumbnail: None,
+            video_media_metadata: None,
+            image_media_metadata: None,
+            fetched_at: Instant::now(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_numeric_duration_is_missing() {
+        for (raw, expected) in [
+            (r#"{"duration": 12.5}"#, Some(12.5)),
+            (r#"{"duration": "12.5"}"#, Some(12.5)),
+            (r#"{"duration": ""}"#, None),
+            (r#"{"duration": "n/a"}"#, None),
+            (r#"{"duration": null}"#, None),
+            (r#"{}"#, None),
+        ] {
+            let metadata: VideoMediaMetadata = serde_json::from_str(raw).unwrap();
+            assert_eq!(metadata.duration, expected, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn unexpected_media_metadata_is_missing() {
+        let metadata: ImageMediaMetadata = serde_json::from_str(
+            r#"{"width": "1920", "height": "", "exif_info": "n/a", "extra": 1}"#,
+        )
+        .unwrap();
+        assert_eq!(metadata.width, Some(1920));
+        assert_eq!(metadata.height, None);
+        assert!(metadata.exif_info.is_none());
+
+        let metadata: ExifInfo =
+            serde_json::from_str(r#"{"make": 1, "model": "X100", "gps_latitude": ""}"#).unwrap();
+        assert_eq!(metadata.make, None);
+        assert_eq!(metadata.model.as_deref(), Some("X100"));
+        assert_eq!(metadata.gps_latitude, None);
+    }
+}
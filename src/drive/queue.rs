@@ -0,0 +1,93 @@
+//! Request queue executing API requests on a fixed number of worker threads
+
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Instant;
+
+use reqwest::blocking::{Request, Response};
+use tracing::{debug, error};
+
+use super::HttpClient;
+
+struct Job {
+    request: Request,
+    reply: Sender<reqwest::Result<Response>>,
+}
+
+/// [`HttpClient`] that bounds the number of in-flight requests,
+/// callers block until a worker has executed their request
+pub struct RequestQueue {
+    sender: Mutex<Sender<Job>>,
+    concurrency: usize,
+}
+
+impl RequestQueue {
+    pub fn new(client: Arc<dyn HttpClient>, concurrency: usize) -> Self {
+        let concurrency = concurrency.max(1);
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for worker in 0..concurrency {
+            let client = client.clone();
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("api-worker-{}", worker))
+                .spawn(move || run_worker(client, receiver))
+                .expect("spawn api worker thread failed");
+        }
+        Self {
+            sender: Mutex::new(sender),
+            concurrency,
+        }
+    }
+}
+
+impl fmt::Debug for RequestQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestQueue")
+            .field("concurrency", &self.concurrency)
+            .finish()
+    }
+}
+
+impl HttpClient for RequestQueue {
+    fn execute(&self, request: Request) -> reqwest::Result<Response> {
+        let (reply, response) = mpsc::channel();
+        let job = Job { request, reply };
+        self.sender
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .send(job)
+            .expect("api workers exited");
+        response.recv().expect("api worker dropped request")
+    }
+}
+
+fn run_worker(client: Arc<dyn HttpClient>, receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // the lock is released before executing the request
+        let job = receiver
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .recv();
+        let Ok(Job { request, reply }) = job else {
+            // the queue is dropped
+            return;
+        };
+        let method = request.method().clone();
+        let url = request.url().clone();
+        let start = Instant::now();
+        let res = client.execute(request);
+        debug!(
+            method = %method,
+            url = %url,
+            status = ?res.as_ref().map(|res| res.status().as_u16()).ok(),
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "api request"
+        );
+        if reply.send(res).is_err() {
+            error!(url = %url, "api response receiver dropped");
+        }
+    }
+}
@@ -1,20 +1,135 @@
+use std::fmt;
+use std::time::Duration;
+
 use libc::c_int;
+use reqwest::StatusCode;
+use tracing::{debug, error};
 
-#[derive(Debug, Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone)]
 pub enum Error {
     NoEntry,
+    NotFound,
     ParentNotFound,
     ChildNotFound,
     ApiCallFailed,
+    PermissionDenied,
+    /// The drive refused access to a file
+    AccessDenied,
+    InvalidIoctl,
+    RateLimited {
+        retry_after: Duration,
+    },
+    NetworkError(String),
+    InodeStoreFailed,
+    NoAttribute,
+    NotSupported,
+    InvalidArgument,
+    AlreadyExists,
+    TooManyOpenFiles,
+    BadFileHandle,
+    FileTooLarge,
+}
+
+/// Convert an error returned by `AliyunDrive` to a FUSE error
+pub fn classify_drive_error(err: &anyhow::Error) -> Error {
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<Error>() {
+            return e.clone();
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return match e.status() {
+                Some(StatusCode::NOT_FOUND) => Error::NotFound,
+                Some(StatusCode::FORBIDDEN) => Error::AccessDenied,
+                Some(StatusCode::TOO_MANY_REQUESTS) => Error::RateLimited {
+                    retry_after: Duration::from_secs(1),
+                },
+                Some(status) if status.is_server_error() => Error::NetworkError(e.to_string()),
+                Some(_) => Error::ApiCallFailed,
+                None => Error::NetworkError(e.to_string()),
+            };
+        }
+    }
+    Error::ApiCallFailed
 }
 
+/// Attach context to a failed drive call, log the full error chain
+/// and convert it to a FUSE error
+pub fn with_context<T, C, F>(res: anyhow::Result<T>, f: F) -> Result<T, Error>
+where
+    C: fmt::Display + Send + Sync + 'static,
+    F: FnOnce() -> C,
+{
+    res.map_err(|err| {
+        let err = err.context(f());
+        let e = classify_drive_error(&err);
+        match e {
+            // expected errors, e.g. looking up a file removed on another device
+            Error::NotFound | Error::AccessDenied => {
+                debug!(error = %format_args!("{:#}", err), "api call failed")
+            }
+            _ => error!(error = %format_args!("{:#}", err), "api call failed"),
+        }
+        e
+    })
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoEntry => write!(f, "no such entry"),
+            Error::NotFound => write!(f, "not found"),
+            Error::ParentNotFound => write!(f, "parent not found"),
+            Error::ChildNotFound => write!(f, "child not found"),
+            Error::ApiCallFailed => write!(f, "api call failed"),
+            Error::PermissionDenied => write!(f, "permission denied"),
+            Error::AccessDenied => write!(f, "access denied"),
+            Error::InvalidIoctl => write!(f, "invalid ioctl"),
+            Error::RateLimited { retry_after } => {
+                write!(f, "rate limited, retry after {:?}", retry_after)
+            }
+            Error::NetworkError(msg) => write!(f, "network error: {}", msg),
+            Error::InodeStoreFailed => write!(f, "inode store failed"),
+            Error::NoAttribute => write!(f, "no such attribute"),
+            Error::NotSupported => write!(f, "operation not supported"),
+            Error::InvalidArgument => write!(f, "invalid argument"),
+            Error::AlreadyExists => write!(f, "already exists"),
+            Error::TooManyOpenFiles => write!(f, "too many open files"),
+            Error::BadFileHandle => write!(f, "bad file handle"),
+            Error::FileTooLarge => write!(f, "file too large"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl From<Error> for c_int {
     fn from(e: Error) -> Self {
         match e {
             Error::NoEntry => libc::ENOENT,
+            Error::NotFound => libc::ENOENT,
             Error::ParentNotFound => libc::ENOENT,
             Error::ChildNotFound => libc::ENOENT,
             Error::ApiCallFailed => libc::EIO,
+            Error::PermissionDenied => libc::EPERM,
+            Error::AccessDenied => libc::EACCES,
+            Error::InvalidIoctl => libc::ENOTTY,
+            Error::RateLimited { .. } => libc::EAGAIN,
+            Error::NetworkError(msg) => {
+                error!(error = %msg, "network error");
+                libc::EIO
+            }
+            Error::InodeStoreFailed => libc::EIO,
+            #[cfg(target_os = "macos")]
+            Error::NoAttribute => libc::ENOATTR,
+            #[cfg(not(target_os = "macos"))]
+            Error::NoAttribute => libc::ENODATA,
+            Error::NotSupported => libc::ENOTSUP,
+            Error::InvalidArgument => libc::EINVAL,
+            Error::AlreadyExists => libc::EEXIST,
+            Error::TooManyOpenFiles => libc::EMFILE,
+            Error::BadFileHandle => libc::EBADF,
+            Error::FileTooLarge => libc::EFBIG,
         }
     }
 }
@@ -1,57 +1,629 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
 
-use bytes::Bytes;
-use tracing::debug;
+use bytes::{Bytes, BytesMut};
+use tracing::{debug, error, info, warn};
 
-use crate::error::Error;
-use crate::AliyunDrive;
+use crate::chunk_cache::DiskChunkCache;
+use crate::disk_cache::DiskCache;
+use crate::drive::{AliyunFile, ConditionalDownload, Drive, Validators};
+use crate::error::{with_context, Error};
+use crate::stats::Stats;
+
+/// Number of retries when a download response is shorter than requested
+const MAX_TRUNCATED_RETRIES: usize = 2;
+/// Cached chunks smaller than this are never compressed
+const MIN_COMPRESS_SIZE: usize = 4096;
+/// zstd level of cached chunks, favors speed as chunks are decompressed on every read
+const COMPRESSION_LEVEL: i32 = 1;
+/// Granularity of zero run detection with sparse reads
+const ZERO_BLOCK_SIZE: usize = 4096;
+/// Runs of zero bytes shorter than this aren't remembered
+const MIN_ZERO_RUN: u64 = 64 * 1024;
+/// Listed directories remembered for prefetching download URLs
+const MAX_LISTED_DIRS: usize = 64;
+/// Download URLs prefetched when a file of a listed directory is opened
+const MAX_PREFETCH_URLS: usize = 100;
+
+/// Compression of cached chunks in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CacheCompression {
+    None,
+    Zstd,
+}
+
+/// Storage of the read buffers of opened files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CacheBackend {
+    Memory,
+    /// Memory-mapped ring buffer file
+    Disk,
+}
+
+#[derive(Debug)]
+enum CacheMode {
+    Read,
+    /// Written data is staged in memory and uploaded on release or fsync
+    Write {
+        staged: Vec<u8>,
+        /// Length of the remote content not downloaded into `staged` yet,
+        /// it's fetched when the staged data is read or uploaded
+        unloaded: u64,
+        /// Sorted, non-overlapping ranges below `unloaded` written since the open,
+        /// they're kept when the remote content is filled in
+        written: Vec<Range<u64>>,
+        /// End offset of the last write
+        offset: u64,
+        /// Writes coalesced since the last upload, and how many of them
+        /// didn't start where the previous one ended
+        writes: u64,
+        non_contiguous_writes: u64,
+        /// Staged data hasn't changed since it was last uploaded
+        flushed: bool,
+    },
+}
 
 #[derive(Debug)]
 struct CachedFile {
     file_id: String,
+    drive_id: Option<String>,
+    name: String,
+    parent_file_id: Option<String>,
+    mode: CacheMode,
     file_size: u64,
+    updated_at: SystemTime,
     start_pos: i64,
     buffer: Bytes,
+    /// Uncompressed length of `buffer`
+    buffer_len: usize,
+    /// Whether `buffer` is compressed
+    compressed: bool,
+    /// Whether `buffer` is stored in the disk backend instead
+    on_disk: bool,
+    prefetch: Option<Prefetch>,
+    /// Effective download buffer size for this file handle
+    buffer_size: usize,
+    /// Number of contiguous reads seen since the last buffer growth
+    consecutive_hits: u32,
+    last_read_end: i64,
+    // chunk offset -> chunk being downloaded in background for sequential reads
+    readahead: BTreeMap<i64, Receiver<Option<Bytes>>>,
+    /// End offset of the last chunk scheduled for readahead
+    readahead_end: i64,
+    /// Sorted, non-overlapping runs of zero bytes found in downloaded chunks
+    zero_ranges: Vec<Range<u64>>,
+}
+
+impl CachedFile {
+    fn set_buffer(
+        &mut self,
+        fh: u64,
+        start_pos: i64,
+        chunk: Bytes,
+        compression: CacheCompression,
+        chunks: Option<&mut DiskChunkCache>,
+    ) {
+        self.start_pos = start_pos;
+        self.buffer_len = chunk.len();
+        let (buffer, compressed) = match compress_chunk(&chunk, compression) {
+            Some(compressed) => (compressed, true),
+            None => (chunk, false),
+        };
+        self.compressed = compressed;
+        self.on_disk = chunks.is_some_and(|chunks| chunks.write_chunk(fh, &buffer));
+        self.buffer = if self.on_disk { Bytes::new() } else { buffer };
+    }
+
+    fn clear_buffer(&mut self, fh: u64, chunks: Option<&mut DiskChunkCache>) {
+        if let (true, Some(chunks)) = (self.on_disk, chunks) {
+            chunks.remove(fh);
+        }
+        self.start_pos = 0;
+        self.buffer = Bytes::new();
+        self.buffer_len = 0;
+        self.compressed = false;
+        self.on_disk = false;
+        self.clear_readahead();
+    }
+
+    /// Remember the runs of zero bytes of a chunk downloaded at `offset`
+    fn record_zero_ranges(&mut self, offset: i64, chunk: &[u8]) {
+        let mut run_start = None;
+        let mut found = Vec::new();
+        for (i, block) in chunk.chunks(ZERO_BLOCK_SIZE).enumerate() {
+            let pos = offset as u64 + (i * ZERO_BLOCK_SIZE) as u64;
+            if block.iter().all(|&b| b == 0) {
+                run_start.get_or_insert(pos);
+            } else if let Some(start) = run_start.take() {
+                found.push(start..pos);
+            }
+        }
+        if let Some(start) = run_start {
+            found.push(start..offset as u64 + chunk.len() as u64);
+        }
+        found.retain(|range| range.end - range.start >= MIN_ZERO_RUN);
+        if found.is_empty() {
+            return;
+        }
+        self.zero_ranges.extend(found);
+        self.zero_ranges.sort_unstable_by_key(|range| range.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(self.zero_ranges.len());
+        for range in self.zero_ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        self.zero_ranges = merged;
+    }
+
+    /// Whether the range is known to contain only zero bytes
+    fn is_zero_range(&self, range: Range<u64>) -> bool {
+        let idx = self
+            .zero_ranges
+            .partition_point(|zero| zero.start <= range.start);
+        idx > 0 && self.zero_ranges[idx - 1].end >= range.end
+    }
+
+    /// Whether the buffer holds the range `offset..end_pos`
+    fn covers(&self, offset: i64, end_pos: i64) -> bool {
+        self.buffer_len > 0
+            && offset >= self.start_pos
+            && end_pos <= self.start_pos + self.buffer_len as i64
+    }
+
+    fn clear_readahead(&mut self) {
+        self.readahead.clear();
+        self.readahead_end = 0;
+    }
+
+    /// Keep up to `window` chunks following the buffer downloading in background,
+    /// chunks in `skip` are already being downloaded by another handle
+    fn schedule_readahead(
+        &mut self,
+        window: usize,
+        skip: &HashSet<i64>,
+        mut spawn: impl FnMut(&ChunkSource, i64) -> Receiver<Option<Bytes>>,
+    ) {
+        let buffer_end = self.start_pos + self.buffer_len as i64;
+        // chunks before the buffer were skipped by the reader
+        self.readahead.retain(|&offset, _| offset >= buffer_end);
+        self.readahead_end = self.readahead_end.max(buffer_end);
+        let source = ChunkSource::from(&*self);
+        while self.readahead.len() < window && (self.readahead_end as u64) < self.file_size {
+            let offset = self.readahead_end;
+            self.readahead_end += self.buffer_size as i64;
+            if skip.contains(&offset) {
+                continue;
+            }
+            debug!(
+                offset = offset,
+                size = self.buffer_size,
+                "schedule readahead"
+            );
+            self.readahead.insert(offset, spawn(&source, offset));
+        }
+    }
+
+    /// Wait for the readahead chunk starting at `offset` if it was scheduled
+    fn take_readahead(&mut self, offset: i64) -> Option<Bytes> {
+        self.readahead.remove(&offset)?.recv().ok().flatten()
+    }
+
+    /// Read a range of the uncompressed buffer data,
+    /// `None` if it was evicted from the disk backend
+    fn read_buffer(
+        &self,
+        fh: u64,
+        range: Range<usize>,
+        chunks: Option<&DiskChunkCache>,
+    ) -> Option<Bytes> {
+        if !self.compressed {
+            return match (self.on_disk, chunks) {
+                (true, Some(chunks)) => chunks.read(fh, range),
+                (true, None) => None,
+                (false, _) => Some(self.buffer.slice(range)),
+            };
+        }
+        let buffer = if self.on_disk {
+            chunks?.get(fh)?
+        } else {
+            self.buffer.clone()
+        };
+        // only the requested range outlives the decompressed chunk
+        match zstd::bulk::decompress(&buffer, self.buffer_len) {
+            Ok(data) if data.len() == self.buffer_len => Some(Bytes::copy_from_slice(&data[range])),
+            Ok(data) => {
+                error!(
+                    expected = self.buffer_len,
+                    actual = data.len(),
+                    "decompressed cache size mismatch"
+                );
+                None
+            }
+            Err(err) => {
+                error!(error = %err, "decompress cache failed");
+                None
+            }
+        }
+    }
 }
 
+/// Compress a chunk, returns `None` if it's not worth it
+fn compress_chunk(chunk: &[u8], compression: CacheCompression) -> Option<Bytes> {
+    if compression == CacheCompression::None || chunk.len() < MIN_COMPRESS_SIZE {
+        return None;
+    }
+    let compressed = zstd::bulk::compress(chunk, COMPRESSION_LEVEL).ok()?;
+    debug!(
+        size = chunk.len(),
+        compressed_size = compressed.len(),
+        "compress cache"
+    );
+    if compressed.len() >= chunk.len() {
+        return None;
+    }
+    Some(Bytes::from(compressed))
+}
+
+/// First chunk being downloaded in background right after `open()`
 #[derive(Debug)]
-pub struct FileCache {
-    drive: AliyunDrive,
+struct Prefetch {
+    data: Arc<Mutex<Option<Bytes>>>,
+    handle: JoinHandle<()>,
+}
+
+/// Remote state of an opened file, polled by the watcher thread
+#[derive(Debug)]
+struct WatchedFile {
+    file_id: String,
+    updated_at: SystemTime,
+    /// New file size and `updated_at` if it was modified remotely since the last read
+    changed: Option<(u64, SystemTime)>,
+}
+
+#[derive(Debug)]
+pub struct FileCache<D> {
+    drive: D,
+    stats: Arc<Stats>,
     read_buffer_size: usize,
+    max_buffer_size: usize,
+    sequential_threshold: u32,
+    // chunks larger than this are downloaded with a streaming reader
+    streaming_threshold: usize,
+    // read the requested ranges directly without buffering, for debugging
+    no_cache: bool,
+    // serve reads of known zero runs without downloading
+    sparse_reads: bool,
+    // number of chunks downloaded ahead of sequential reads
+    prefetch_window: usize,
+    // writes and truncates beyond this size fail with `EFBIG`
+    max_staged_size: u64,
+    compression: CacheCompression,
+    disk_cache: Option<DiskCache>,
+    // read buffers are stored here instead of memory if set
+    chunks: Option<DiskChunkCache>,
     // file handle -> cached file
     cache: BTreeMap<u64, CachedFile>,
+    // file id -> handles of the file opened for reading, they share buffers
+    file_id_to_fhs: HashMap<String, Vec<u64>>,
+    // file handle -> remote state of files opened for reading
+    watched: Arc<Mutex<BTreeMap<u64, WatchedFile>>>,
+    // folder id -> files of the last listing, not yet prefetched
+    listed_files: HashMap<String, Vec<String>>,
+    // started on the first download URL prefetch
+    url_prefetcher: Option<SyncSender<Vec<String>>>,
 }
 
-impl FileCache {
-    pub fn new(drive: AliyunDrive, read_buffer_size: usize) -> Self {
+impl<D: Drive> FileCache<D> {
+    pub fn new(
+        drive: D,
+        stats: Arc<Stats>,
+        read_buffer_size: usize,
+        max_buffer_size: usize,
+        sequential_threshold: u32,
+        compression: CacheCompression,
+        disk_cache: Option<DiskCache>,
+    ) -> Self {
+        if let Some(disk_cache) = &disk_cache {
+            disk_cache.spawn_evictor();
+        }
         Self {
             drive,
+            stats,
             read_buffer_size,
+            max_buffer_size: max_buffer_size.max(read_buffer_size),
+            sequential_threshold,
+            streaming_threshold: usize::MAX,
+            no_cache: false,
+            sparse_reads: false,
+            prefetch_window: 0,
+            max_staged_size: u64::MAX,
+            compression,
+            disk_cache,
+            chunks: None,
             cache: BTreeMap::new(),
+            file_id_to_fhs: HashMap::new(),
+            watched: Arc::new(Mutex::new(BTreeMap::new())),
+            listed_files: HashMap::new(),
+            url_prefetcher: None,
         }
     }
 
-    fn read_chunk(&self, file_id: &str, file_size: u64, offset: i64) -> Result<Bytes, Error> {
-        let size = std::cmp::min(
-            self.read_buffer_size,
-            file_size.saturating_sub(offset as u64) as usize,
+    /// Like [`FileCache::new`], but read buffers are stored in a memory-mapped
+    /// ring buffer file instead of memory
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_disk_backend(
+        drive: D,
+        stats: Arc<Stats>,
+        read_buffer_size: usize,
+        max_buffer_size: usize,
+        sequential_threshold: u32,
+        compression: CacheCompression,
+        disk_cache: Option<DiskCache>,
+        chunks: DiskChunkCache,
+    ) -> Self {
+        let mut cache = Self::new(
+            drive,
+            stats,
+            read_buffer_size,
+            max_buffer_size,
+            sequential_threshold,
+            compression,
+            disk_cache,
         );
-        let download_url = self
-            .drive
-            .get_download_url(file_id)
-            .map_err(|_| Error::ApiCallFailed)?;
-        let data = self
-            .drive
-            .download(&download_url, offset as _, size)
-            .map_err(|_| Error::ApiCallFailed)?;
-        Ok(data)
+        cache.chunks = Some(chunks);
+        cache
+    }
+
+    /// Download chunks larger than `threshold` bytes with a streaming reader,
+    /// disabled by default
+    pub fn with_streaming_threshold(mut self, threshold: usize) -> Self {
+        self.streaming_threshold = threshold;
+        self
+    }
+
+    /// Bypass read buffers, prefetching and the disk cache,
+    /// every read downloads exactly the requested range
+    pub fn with_no_cache(mut self, no_cache: bool) -> Self {
+        self.no_cache = no_cache;
+        self
+    }
+
+    /// Remember runs of zero bytes in downloaded chunks and serve reads
+    /// falling entirely within them without downloading, disabled by default
+    pub fn with_sparse_reads(mut self, sparse_reads: bool) -> Self {
+        self.sparse_reads = sparse_reads;
+        self
+    }
+
+    /// Download up to `window` chunks ahead of sequential reads in background,
+    /// disabled by default
+    pub fn with_prefetch_window(mut self, window: usize) -> Self {
+        self.prefetch_window = window;
+        self
+    }
+
+    /// Refuse to stage more than `size` bytes for a file opened for writing,
+    /// unlimited by default
+    pub fn with_max_staged_size(mut self, size: u64) -> Self {
+        self.max_staged_size = size;
+        self
+    }
+
+    /// Poll `updated_at` of opened files in background, files modified
+    /// on other devices have their cached data dropped on the next read
+    pub fn start_watcher(&self, interval: Duration) {
+        if interval.is_zero() {
+            return;
+        }
+        let drive = self.drive.clone();
+        let stats = self.stats.clone();
+        let watched = Arc::downgrade(&self.watched);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let watched = match watched.upgrade() {
+                Some(watched) => watched,
+                None => break,
+            };
+            let files: Vec<(u64, String, SystemTime)> = watched
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .map(|(fh, file)| (*fh, file.file_id.clone(), file.updated_at))
+                .collect();
+            for (fh, file_id, updated_at) in files {
+                let res = drive.get_file(&file_id);
+                stats.api_call(&res);
+                let file = match res {
+                    Ok(file) => file,
+                    Err(err) => {
+                        warn!(file_id = %file_id, error = %format_args!("{:#}", err), "watch opened file failed");
+                        continue;
+                    }
+                };
+                if *file.updated_at == updated_at {
+                    continue;
+                }
+                info!(fh = fh, file_id = %file_id, size = file.size, "opened file modified remotely");
+                if let Some(entry) = watched
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get_mut(&fh)
+                {
+                    entry.updated_at = *file.updated_at;
+                    entry.changed = Some((file.size, *file.updated_at));
+                }
+            }
+        });
+    }
+
+    /// Drop cached data if the file was modified remotely,
+    /// returns the new size and modification time
+    pub fn apply_remote_change(&mut self, fh: u64) -> Option<(u64, SystemTime)> {
+        let changed = self
+            .watched
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(&fh)
+            .and_then(|entry| entry.changed.take());
+        if let (Some((size, updated_at)), Some(cached)) = (changed, self.cache.get_mut(&fh)) {
+            debug!(fh = fh, size = size, "invalidate cached file");
+            cached.file_size = size;
+            cached.updated_at = updated_at;
+            cached.clear_buffer(fh, self.chunks.as_mut());
+            cached.zero_ranges.clear();
+        }
+        changed
+    }
+
+    /// Other handles reading the same version of the file
+    fn siblings(&self, fh: u64) -> Vec<u64> {
+        let Some(cached) = self.cache.get(&fh) else {
+            return Vec::new();
+        };
+        let Some(handles) = self.file_id_to_fhs.get(&cached.file_id) else {
+            return Vec::new();
+        };
+        handles
+            .iter()
+            .copied()
+            .filter(|&other| {
+                other != fh
+                    && self
+                        .cache
+                        .get(&other)
+                        .is_some_and(|sibling| sibling.updated_at == cached.updated_at)
+            })
+            .collect()
+    }
+
+    /// Buffer of another handle of the same file covering the range,
+    /// so that a file opened more than once doesn't download the same chunk again
+    fn shared_chunk(&self, fh: u64, offset: i64, size: u32) -> Option<(i64, Bytes)> {
+        let cached = self.cache.get(&fh)?;
+        let end_pos = offset + i64::from(size);
+        if !matches!(cached.mode, CacheMode::Read) || cached.covers(offset, end_pos) {
+            return None;
+        }
+        self.siblings(fh).into_iter().find_map(|other| {
+            let sibling = self.cache.get(&other)?;
+            if !sibling.covers(offset, end_pos) {
+                return None;
+            }
+            let chunk = sibling.read_buffer(other, 0..sibling.buffer_len, self.chunks.as_ref())?;
+            Some((sibling.start_pos, chunk))
+        })
+    }
+
+    /// Take over the chunk at `offset` another handle of the same file is downloading
+    /// in background, unless the handle has the range buffered or scheduled itself
+    fn sibling_readahead(
+        &mut self,
+        fh: u64,
+        offset: i64,
+        size: u32,
+    ) -> Option<Receiver<Option<Bytes>>> {
+        let cached = self.cache.get(&fh)?;
+        if cached.covers(offset, offset + i64::from(size)) || cached.readahead.contains_key(&offset)
+        {
+            return None;
+        }
+        self.siblings(fh)
+            .into_iter()
+            .find_map(|other| self.cache.get_mut(&other)?.readahead.remove(&offset))
+    }
+
+    /// Offsets of the chunks other handles of the same file are downloading in background
+    fn sibling_readahead_offsets(&self, fh: u64) -> HashSet<i64> {
+        self.siblings(fh)
+            .into_iter()
+            .filter_map(|other| self.cache.get(&other))
+            .flat_map(|sibling| sibling.readahead.keys().copied())
+            .collect()
     }
 
     pub fn read(&mut self, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
-        let cached = self.cache.get(&fh).ok_or(Error::NoEntry)?;
+        self.fill_staged(fh)?;
+        self.harvest_prefetch(fh, offset);
+        // the first chunk may have been prefetched by another handle of the same file
+        for sibling in self.siblings(fh) {
+            self.harvest_prefetch(sibling, offset);
+        }
+        self.apply_remote_change(fh);
+        let shared = if self.no_cache {
+            None
+        } else {
+            self.shared_chunk(fh, offset, size)
+        };
+        let borrowed = if self.no_cache || shared.is_some() {
+            None
+        } else {
+            self.sibling_readahead(fh, offset, size)
+        };
+        let sibling_pending = self.sibling_readahead_offsets(fh);
+        let cached = self.cache.get_mut(&fh).ok_or(Error::NoEntry)?;
+        if let CacheMode::Write { staged, .. } = &cached.mode {
+            let start = std::cmp::min(offset as usize, staged.len());
+            let end = std::cmp::min(start + size as usize, staged.len());
+            return Ok(Bytes::copy_from_slice(&staged[start..end]));
+        }
+        if self.no_cache {
+            debug!(fh = fh, offset = offset, size = size, "read file uncached");
+            let source = ChunkSource {
+                buffer_size: size as usize,
+                ..ChunkSource::from(&*cached)
+            };
+            return download_chunk(
+                &self.drive,
+                &self.stats,
+                &source,
+                offset,
+                self.streaming_threshold,
+            );
+        }
+        let sequential = offset == cached.last_read_end;
+        if sequential {
+            cached.consecutive_hits += 1;
+            if cached.consecutive_hits >= self.sequential_threshold
+                && cached.buffer_size < self.max_buffer_size
+            {
+                cached.buffer_size = (cached.buffer_size * 2).min(self.max_buffer_size);
+                cached.consecutive_hits = 0;
+                debug!(
+                    fh = fh,
+                    buffer_size = cached.buffer_size,
+                    "sequential read detected, grow buffer"
+                );
+            }
+        } else {
+            cached.consecutive_hits = 0;
+            cached.buffer_size = self.read_buffer_size;
+            // random access, chunks downloaded ahead are unlikely to be read
+            cached.clear_readahead();
+        }
+        let window = if sequential { self.prefetch_window } else { 0 };
+        let (drive, stats, disk_cache) = (&self.drive, &self.stats, self.disk_cache.as_ref());
+        let streaming_threshold = self.streaming_threshold;
+        let spawn = |source: &ChunkSource, offset| {
+            spawn_fetch_chunk(
+                drive,
+                stats,
+                disk_cache,
+                source,
+                offset,
+                streaming_threshold,
+            )
+        };
+        cached.last_read_end = offset + i64::from(size);
         let start_pos = cached.start_pos;
         let end_pos = offset + i64::from(size);
-        let buf_size = cached.buffer.len();
+        let buf_size = cached.buffer_len;
         debug!(
             fh = fh,
             offset = offset,
@@ -63,17 +635,73 @@ impl FileCache {
         if offset >= start_pos && end_pos <= start_pos + buf_size as i64 {
             let buf_start = (offset - start_pos) as usize;
             let buf_end = buf_start + size as usize;
-            let data = cached.buffer.slice(buf_start..buf_end);
-            return Ok(data);
+            if let Some(data) = cached.read_buffer(fh, buf_start..buf_end, self.chunks.as_ref()) {
+                Stats::incr(&self.stats.cache_hits, 1);
+                cached.schedule_readahead(window, &sibling_pending, spawn);
+                return Ok(data);
+            }
         }
-        let chunk = self.read_chunk(&cached.file_id, cached.file_size, offset)?;
-        let new_cached = CachedFile {
-            file_id: cached.file_id.clone(),
-            file_size: cached.file_size,
-            start_pos: offset,
-            buffer: chunk.clone(),
+        let range = offset as u64..end_pos as u64;
+        if self.sparse_reads && cached.is_zero_range(range) {
+            debug!(
+                fh = fh,
+                offset = offset,
+                size = size,
+                "read known zero range"
+            );
+            Stats::incr(&self.stats.cache_hits, 1);
+            return Ok(Bytes::from(vec![0u8; size as usize]));
+        }
+        if let Some((start_pos, chunk)) = shared {
+            debug!(
+                fh = fh,
+                offset = offset,
+                "read from buffer of another file handle"
+            );
+            Stats::incr(&self.stats.cache_hits, 1);
+            cached.set_buffer(
+                fh,
+                start_pos,
+                chunk.clone(),
+                self.compression,
+                self.chunks.as_mut(),
+            );
+            cached.schedule_readahead(window, &sibling_pending, spawn);
+            let buf_start = (offset - start_pos) as usize;
+            return Ok(chunk.slice(buf_start..buf_start + size as usize));
+        }
+        let chunk = match cached
+            .take_readahead(offset)
+            .or_else(|| borrowed.and_then(|rx| rx.recv().ok().flatten()))
+        {
+            Some(chunk) => {
+                debug!(fh = fh, offset = offset, "read from readahead chunk");
+                Stats::incr(&self.stats.cache_hits, 1);
+                chunk
+            }
+            None => {
+                Stats::incr(&self.stats.cache_misses, 1);
+                fetch_chunk(
+                    drive,
+                    stats,
+                    disk_cache,
+                    &ChunkSource::from(&*cached),
+                    offset,
+                    streaming_threshold,
+                )?
+            }
         };
-        self.cache.insert(fh, new_cached);
+        if self.sparse_reads {
+            cached.record_zero_ranges(offset, &chunk);
+        }
+        cached.set_buffer(
+            fh,
+            offset,
+            chunk.clone(),
+            self.compression,
+            self.chunks.as_mut(),
+        );
+        cached.schedule_readahead(window, &sibling_pending, spawn);
 
         // chunk size maybe less than size
         let size = if chunk.len() >= size as usize {
@@ -81,20 +709,937 @@ impl FileCache {
         } else {
             chunk.len()
         };
-        Ok(chunk.slice(..size as usize))
+        // reading the rest of a short read is still sequential
+        cached.last_read_end = offset + size as i64;
+        Ok(chunk.slice(..size))
     }
 
-    pub fn open(&mut self, fh: u64, file_id: String, file_size: u64) {
+    /// Like [`FileCache::read`], but keeps reading until `size` bytes are read
+    /// or the end of file is reached
+    pub fn read_exact(&mut self, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
+        let first = self.read(fh, offset, size)?;
+        if first.len() >= size as usize || first.is_empty() {
+            return Ok(first);
+        }
+        let mut data = BytesMut::from(&first[..]);
+        while data.len() < size as usize {
+            let read = data.len();
+            debug!(
+                fh = fh,
+                offset = offset,
+                size = size,
+                read = read,
+                "short read, continue"
+            );
+            let chunk = self.read(fh, offset + read as i64, size - read as u32)?;
+            if chunk.is_empty() {
+                break;
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data.freeze())
+    }
+
+    /// Open a file handle, a writable one starts with nothing staged and
+    /// nothing to upload, see `load_staged`
+    pub fn open(&mut self, fh: u64, file: &AliyunFile, writable: bool) {
+        let mode = if writable {
+            CacheMode::Write {
+                staged: Vec::new(),
+                unloaded: 0,
+                written: Vec::new(),
+                offset: 0,
+                writes: 0,
+                non_contiguous_writes: 0,
+                flushed: true,
+            }
+        } else {
+            CacheMode::Read
+        };
+        let updated_at = *file.updated_at;
         let file = CachedFile {
-            file_id,
-            file_size,
+            file_id: file.id.clone(),
+            drive_id: file.drive_id.clone(),
+            name: file.name.clone(),
+            parent_file_id: file.parent_file_id.clone(),
+            mode,
+            file_size: file.size,
+            updated_at,
             start_pos: 0,
             buffer: Bytes::new(),
+            buffer_len: 0,
+            compressed: false,
+            on_disk: false,
+            prefetch: None,
+            buffer_size: self.read_buffer_size,
+            consecutive_hits: 0,
+            last_read_end: 0,
+            readahead: BTreeMap::new(),
+            readahead_end: 0,
+            zero_ranges: Vec::new(),
         };
+        // `get_file` only works for the default drive
+        if !writable && file.drive_id.is_none() {
+            self.watched
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(
+                    fh,
+                    WatchedFile {
+                        file_id: file.file_id.clone(),
+                        updated_at,
+                        changed: None,
+                    },
+                );
+        }
+        if !writable {
+            self.file_id_to_fhs
+                .entry(file.file_id.clone())
+                .or_default()
+                .push(fh);
+            if let (None, Some(parent_file_id)) = (&file.drive_id, &file.parent_file_id) {
+                self.prefetch_download_urls(&parent_file_id.clone());
+            }
+        }
         self.cache.insert(fh, file);
     }
 
-    pub fn release(&mut self, fh: u64) {
-        self.cache.remove(&fh);
+    /// Download files received from `files` into the disk cache in background,
+    /// stops once the preloaded size would exceed the disk cache size limit
+    pub fn spawn_preload(&self, files: Receiver<AliyunFile>) {
+        let Some(disk_cache) = self.disk_cache.clone() else {
+            warn!("disk cache is disabled, skip preloading files");
+            return;
+        };
+        let drive = self.drive.clone();
+        let stats = self.stats.clone();
+        let buffer_size = self.read_buffer_size;
+        let streaming_threshold = self.streaming_threshold;
+        thread::spawn(move || {
+            let mut preloaded = 0;
+            for file in files {
+                if preloaded + file.size > disk_cache.size_limit() {
+                    warn!(
+                        file_id = %file.id,
+                        name = %file.name,
+                        preloaded_bytes = preloaded,
+                        "disk cache is full, stop preloading files"
+                    );
+                    return;
+                }
+                // same chunks as reads of a newly opened file
+                let source = ChunkSource {
+                    file_id: file.id.clone(),
+                    drive_id: file.drive_id.clone(),
+                    file_size: file.size,
+                    updated_at: *file.updated_at,
+                    buffer_size,
+                };
+                let mut offset = 0;
+                while (offset as u64) < file.size {
+                    let res = fetch_chunk(
+                        &drive,
+                        &stats,
+                        Some(&disk_cache),
+                        &source,
+                        offset,
+                        streaming_threshold,
+                    );
+                    if let Err(err) = res {
+                        warn!(file_id = %file.id, offset = offset, error = %err, "preload file failed");
+                        break;
+                    }
+                    offset += buffer_size as i64;
+                }
+                preloaded += file.size;
+                info!(file_id = %file.id, name = %file.name, size = file.size, "preloaded file");
+            }
+        });
+    }
+
+    /// Remember the files of a listed folder in the default drive, their download
+    /// URLs are prefetched once one of them is opened
+    pub fn remember_listing(&mut self, parent_file_id: &str, file_ids: Vec<String>) {
+        if self.no_cache || file_ids.is_empty() {
+            self.listed_files.remove(parent_file_id);
+            return;
+        }
+        if self.listed_files.len() >= MAX_LISTED_DIRS
+            && !self.listed_files.contains_key(parent_file_id)
+        {
+            self.listed_files.clear();
+        }
+        self.listed_files
+            .insert(parent_file_id.to_string(), file_ids);
+    }
+
+    /// Fetch download URLs of the files in a listed folder in background with batch
+    /// requests, so that opening them later doesn't need a request each
+    fn prefetch_download_urls(&mut self, parent_file_id: &str) {
+        let Some(mut file_ids) = self.listed_files.remove(parent_file_id) else {
+            return;
+        };
+        file_ids.truncate(MAX_PREFETCH_URLS);
+        let (drive, stats) = (&self.drive, &self.stats);
+        let prefetcher = self
+            .url_prefetcher
+            .get_or_insert_with(|| spawn_url_prefetcher(drive.clone(), stats.clone()));
+        match prefetcher.try_send(file_ids) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                debug!(parent_file_id = %parent_file_id, "download url prefetch busy, skip")
+            }
+            Err(TrySendError::Disconnected(_)) => self.url_prefetcher = None,
+        }
+    }
+
+    /// Download the first chunk of an opened file in background
+    pub fn prefetch_on_open(&mut self, fh: u64) {
+        let cached = match self.cache.get(&fh) {
+            Some(cached) => cached,
+            None => return,
+        };
+        if self.no_cache
+            || cached.file_size == 0
+            || cached.prefetch.is_some()
+            || matches!(cached.mode, CacheMode::Write { .. })
+        {
+            return;
+        }
+        // another handle of the same file has the first chunk or is downloading it
+        let first_chunk = std::cmp::min(self.read_buffer_size as u64, cached.file_size) as i64;
+        let has_first_chunk = |other: &u64| {
+            self.cache.get(other).is_some_and(|sibling| {
+                sibling.prefetch.is_some()
+                    || sibling.covers(0, first_chunk)
+                    || sibling.readahead.contains_key(&0)
+            })
+        };
+        if self.siblings(fh).iter().any(has_first_chunk) {
+            debug!(
+                fh = fh,
+                "first chunk shared with another handle, skip prefetch"
+            );
+            return;
+        }
+        let Some(cached) = self.cache.get_mut(&fh) else {
+            return;
+        };
+        let data = Arc::new(Mutex::new(None));
+        let drive = self.drive.clone();
+        let stats = self.stats.clone();
+        let disk_cache = self.disk_cache.clone();
+        let streaming_threshold = self.streaming_threshold;
+        let source = ChunkSource {
+            file_id: cached.file_id.clone(),
+            drive_id: cached.drive_id.clone(),
+            file_size: cached.file_size,
+            updated_at: cached.updated_at,
+            buffer_size: self.read_buffer_size,
+        };
+        let result = data.clone();
+        let handle = thread::spawn(move || {
+            match fetch_chunk(
+                &drive,
+                &stats,
+                disk_cache.as_ref(),
+                &source,
+                0,
+                streaming_threshold,
+            ) {
+                Ok(chunk) => *result.lock().unwrap_or_else(PoisonError::into_inner) = Some(chunk),
+                Err(_) => error!(file_id = %source.file_id, "prefetch file failed"),
+            }
+        });
+        cached.prefetch = Some(Prefetch { data, handle });
+    }
+
+    /// Move the result of the background prefetch into the buffer of `fh`. Only a read
+    /// at `offset` within the prefetched first chunk waits for it, other reads take
+    /// the result once the prefetch has finished, unless they buffered something else
+    fn harvest_prefetch(&mut self, fh: u64, offset: i64) {
+        let Some(cached) = self.cache.get_mut(&fh) else {
+            return;
+        };
+        let Some(prefetch) = cached.prefetch.as_ref() else {
+            return;
+        };
+        let needed = offset < self.read_buffer_size as i64;
+        if !needed && !prefetch.handle.is_finished() {
+            return;
+        }
+        let Some(prefetch) = cached.prefetch.take() else {
+            return;
+        };
+        debug!(fh = fh, offset = offset, "wait for prefetch");
+        if prefetch.handle.join().is_err() {
+            error!(fh = fh, "prefetch thread panicked");
+        }
+        let chunk = prefetch
+            .data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(chunk) = chunk.filter(|_| needed || cached.buffer_len == 0) {
+            cached.set_buffer(fh, 0, chunk, self.compression, self.chunks.as_mut());
+        }
+    }
+
+    pub fn contains(&self, fh: u64) -> bool {
+        self.cache.contains_key(&fh)
+    }
+
+    /// Number of opened file handles
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Opened file handles and their buffer state, for debug dumps
+    pub fn debug_handles(&self) -> Vec<serde_json::Value> {
+        self.cache
+            .iter()
+            .map(|(fh, cached)| {
+                serde_json::json!({
+                    "fh": fh,
+                    "file_id": cached.file_id,
+                    "writable": matches!(cached.mode, CacheMode::Write { .. }),
+                    "start_pos": cached.start_pos,
+                    "buffer_size": cached.buffer_size,
+                })
+            })
+            .collect()
+    }
+
+    /// Evict cached data of all opened files
+    pub fn clear(&mut self) {
+        for (fh, cached) in self.cache.iter_mut() {
+            cached.clear_buffer(*fh, self.chunks.as_mut());
+        }
+    }
+
+    /// Stage written data, it's uploaded when the file handle is released
+    pub fn write(&mut self, fh: u64, offset: i64, data: &[u8]) -> Result<u32, Error> {
+        let max_staged_size = self.max_staged_size;
+        let cached = self.cache.get_mut(&fh).ok_or(Error::NoEntry)?;
+        // not opened for writing
+        let CacheMode::Write {
+            staged,
+            unloaded,
+            written,
+            offset: last_offset,
+            writes,
+            non_contiguous_writes,
+            flushed,
+        } = &mut cached.mode
+        else {
+            return Err(Error::BadFileHandle);
+        };
+        let Ok(start) = u64::try_from(offset) else {
+            return Err(Error::InvalidArgument);
+        };
+        let end = start
+            .checked_add(data.len() as u64)
+            .filter(|&end| end <= max_staged_size)
+            .and_then(|end| usize::try_from(end).ok())
+            .ok_or(Error::FileTooLarge)?;
+        debug!(
+            fh = fh,
+            offset = offset,
+            size = data.len(),
+            "write file cache"
+        );
+        // writes may arrive out of order with writeback caching,
+        // the gap before a write past the end is zero-filled
+        let start = start as usize;
+        if end > staged.len() {
+            staged.resize(end, 0);
+        }
+        staged[start..end].copy_from_slice(data);
+        if (start as u64) < *unloaded {
+            add_range(written, start as u64..std::cmp::min(end as u64, *unloaded));
+        }
+        if *writes > 0 && offset as u64 != *last_offset {
+            *non_contiguous_writes += 1;
+        }
+        *writes += 1;
+        *last_offset = end as u64;
+        *flushed = false;
+        Ok(data.len() as u32)
+    }
+
+    /// Truncate or zero-extend the staged data of a file opened for writing,
+    /// returns `false` if the file handle isn't opened for writing
+    pub fn truncate(&mut self, fh: u64, size: u64) -> Result<bool, Error> {
+        let max_staged_size = self.max_staged_size;
+        match self.cache.get_mut(&fh).map(|cached| &mut cached.mode) {
+            Some(CacheMode::Write {
+                staged,
+                unloaded,
+                flushed,
+                ..
+            }) => {
+                let size = usize::try_from(size)
+                    .ok()
+                    .filter(|&size| size as u64 <= max_staged_size)
+                    .ok_or(Error::FileTooLarge)?;
+                debug!(fh = fh, size = size, "truncate staged data");
+                *unloaded = std::cmp::min(*unloaded, size as u64);
+                staged.resize(size, 0);
+                *flushed = false;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Stage data of a file opened for writing that matches its current content,
+    /// e.g. read from a local copy
+    pub fn set_staged(&mut self, fh: u64, data: Vec<u8>) {
+        if let Some(CacheMode::Write {
+            staged,
+            unloaded,
+            written,
+            ..
+        }) = self.cache.get_mut(&fh).map(|cached| &mut cached.mode)
+        {
+            *staged = data;
+            *unloaded = 0;
+            written.clear();
+        }
+    }
+
+    /// Stage the current content of a file opened for writing, so that writes
+    /// modify the file instead of replacing it. Nothing is downloaded until
+    /// the staged data is read or uploaded, see `fill_staged`
+    pub fn load_staged(&mut self, fh: u64) -> Result<(), Error> {
+        let max_staged_size = self.max_staged_size;
+        let cached = self.cache.get_mut(&fh).ok_or(Error::NoEntry)?;
+        if cached.file_size > max_staged_size {
+            return Err(Error::FileTooLarge);
+        }
+        if let CacheMode::Write { unloaded, .. } = &mut cached.mode {
+            *unloaded = cached.file_size;
+        }
+        Ok(())
+    }
+
+    /// Download the remote content not staged yet in chunks of the max buffer size,
+    /// ranges written since the open are kept
+    fn fill_staged(&mut self, fh: u64) -> Result<(), Error> {
+        let Some(cached) = self.cache.get_mut(&fh) else {
+            return Ok(());
+        };
+        let CacheMode::Write {
+            staged,
+            unloaded,
+            written,
+            ..
+        } = &mut cached.mode
+        else {
+            return Ok(());
+        };
+        if *unloaded == 0 {
+            return Ok(());
+        }
+        let source = ChunkSource {
+            file_id: cached.file_id.clone(),
+            drive_id: cached.drive_id.clone(),
+            file_size: *unloaded,
+            updated_at: cached.updated_at,
+            buffer_size: self.max_buffer_size,
+        };
+        debug!(
+            fh = fh,
+            size = *unloaded,
+            written = written.len(),
+            "fill staged data"
+        );
+        if staged.len() < *unloaded as usize {
+            staged.resize(*unloaded as usize, 0);
+        }
+        let mut offset = 0;
+        while offset < *unloaded {
+            let chunk = download_chunk(
+                &self.drive,
+                &self.stats,
+                &source,
+                offset as i64,
+                self.streaming_threshold,
+            )?;
+            if chunk.is_empty() {
+                // shorter than expected, the rest stays zero-filled
+                break;
+            }
+            let end = offset + chunk.len() as u64;
+            let mut pos = offset;
+            for range in written.iter().chain(std::iter::once(&(end..end))) {
+                let (gap_start, gap_end) = (pos, std::cmp::min(range.start, end));
+                if gap_start < gap_end {
+                    staged[gap_start as usize..gap_end as usize].copy_from_slice(
+                        &chunk[(gap_start - offset) as usize..(gap_end - offset) as usize],
+                    );
+                }
+                pos = std::cmp::max(pos, range.end);
+                if pos >= end {
+                    break;
+                }
+            }
+            offset = end;
+        }
+        *unloaded = 0;
+        written.clear();
+        Ok(())
+    }
+
+    /// Staged data of a file opened for writing if it changed since it was last
+    /// uploaded or taken, it then counts as uploaded
+    pub fn take_staged(&mut self, fh: u64) -> Result<Option<Vec<u8>>, Error> {
+        if self.is_flushed(fh) {
+            return Ok(None);
+        }
+        self.fill_staged(fh)?;
+        let Some(cached) = self.cache.get_mut(&fh) else {
+            return Ok(None);
+        };
+        Ok(match &mut cached.mode {
+            CacheMode::Write {
+                staged,
+                writes,
+                non_contiguous_writes,
+                flushed,
+                ..
+            } if !*flushed => {
+                *flushed = true;
+                *writes = 0;
+                *non_contiguous_writes = 0;
+                Some(staged.clone())
+            }
+            _ => None,
+        })
+    }
+
+    /// Whether the handle has nothing to upload, also if it isn't opened for writing
+    fn is_flushed(&self, fh: u64) -> bool {
+        !matches!(
+            self.cache.get(&fh).map(|cached| &cached.mode),
+            Some(CacheMode::Write { flushed: false, .. })
+        )
+    }
+
+    /// Size of the staged data of a file opened for writing
+    pub fn staged_size(&self, fh: u64) -> Option<u64> {
+        match &self.cache.get(&fh)?.mode {
+            CacheMode::Write {
+                staged, unloaded, ..
+            } => Some(std::cmp::max(staged.len() as u64, *unloaded)),
+            CacheMode::Read => None,
+        }
+    }
+
+    /// Point an opened file handle to another file, discarding staged data
+    pub fn replace(&mut self, fh: u64, file: &AliyunFile) {
+        self.watched
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&fh);
+        if let Some(chunks) = self.chunks.as_mut() {
+            chunks.remove(fh);
+        }
+        if self.remove_cached(fh).is_some() {
+            self.open(fh, file, false);
+        }
+    }
+
+    /// Release the file handle, returns the file id if staged data was uploaded
+    pub fn release(&mut self, fh: u64) -> Result<Option<String>, Error> {
+        self.watched
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&fh);
+        if let Some(chunks) = self.chunks.as_mut() {
+            chunks.remove(fh);
+        }
+        let uploaded = self.flush(fh);
+        self.remove_cached(fh);
+        uploaded
+    }
+
+    fn remove_cached(&mut self, fh: u64) -> Option<CachedFile> {
+        let cached = self.cache.remove(&fh)?;
+        if let Some(handles) = self.file_id_to_fhs.get_mut(&cached.file_id) {
+            handles.retain(|&other| other != fh);
+            if handles.is_empty() {
+                self.file_id_to_fhs.remove(&cached.file_id);
+            }
+        }
+        Some(cached)
+    }
+
+    /// Upload the staged data of all files opened for writing, failures are logged
+    pub fn flush_all(&mut self) {
+        let handles: Vec<u64> = self.cache.keys().copied().collect();
+        for fh in handles {
+            if let Err(err) = self.flush(fh) {
+                error!(fh = fh, error = %err, "flush file failed");
+            }
+        }
+    }
+
+    /// Upload the staged data of a file opened for writing if it changed since
+    /// the last upload, returns the id of the uploaded file
+    pub fn flush(&mut self, fh: u64) -> Result<Option<String>, Error> {
+        if self.is_flushed(fh) {
+            return Ok(None);
+        }
+        self.fill_staged(fh)?;
+        let cached = match self.cache.get_mut(&fh) {
+            Some(cached) => cached,
+            None => return Ok(None),
+        };
+        let (staged, writes, non_contiguous_writes, flushed) = match &mut cached.mode {
+            CacheMode::Write {
+                staged,
+                writes,
+                non_contiguous_writes,
+                flushed,
+                ..
+            } if !*flushed => (staged, writes, non_contiguous_writes, flushed),
+            _ => return Ok(None),
+        };
+        let parent_file_id = cached
+            .parent_file_id
+            .as_deref()
+            .ok_or(Error::ParentNotFound)?;
+        let size = staged.len() as u64;
+        // all writes since the last upload go out as one upload of the whole file
+        debug!(
+            fh = fh,
+            size = size,
+            writes = *writes,
+            non_contiguous_writes = *non_contiguous_writes,
+            "upload coalesced writes"
+        );
+        let res = self
+            .drive
+            .upload_file(parent_file_id, &cached.name, staged.clone());
+        self.stats.api_call(&res);
+        let file_id = with_context(res, || format!("upload file {} (fh {})", cached.name, fh))?;
+        Stats::incr(&self.stats.bytes_uploaded, size);
+        *flushed = true;
+        *writes = 0;
+        *non_contiguous_writes = 0;
+        // the id changes if the previous file was moved to the recycle bin
+        cached.file_id = file_id.clone();
+        Ok(Some(file_id))
+    }
+}
+
+/// Add `range` to sorted, non-overlapping `ranges`, merging it with those it touches
+fn add_range(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    let start = ranges.partition_point(|other| other.end < range.start);
+    let end = ranges.partition_point(|other| other.start <= range.end);
+    let merged = if start < end {
+        std::cmp::min(range.start, ranges[start].start)
+            ..std::cmp::max(range.end, ranges[end - 1].end)
+    } else {
+        range
+    };
+    ranges.splice(start..end, std::iter::once(merged));
+}
+
+/// Remote file a chunk is fetched from
+#[derive(Clone)]
+struct ChunkSource {
+    file_id: String,
+    drive_id: Option<String>,
+    file_size: u64,
+    updated_at: SystemTime,
+    buffer_size: usize,
+}
+
+impl From<&CachedFile> for ChunkSource {
+    fn from(cached: &CachedFile) -> Self {
+        Self {
+            file_id: cached.file_id.clone(),
+            drive_id: cached.drive_id.clone(),
+            file_size: cached.file_size,
+            updated_at: cached.updated_at,
+            buffer_size: cached.buffer_size,
+        }
+    }
+}
+
+/// Fetch a chunk from the disk cache if enabled, or download it
+fn fetch_chunk<D: Drive>(
+    drive: &D,
+    stats: &Stats,
+    disk_cache: Option<&DiskCache>,
+    source: &ChunkSource,
+    offset: i64,
+    streaming_threshold: usize,
+) -> Result<Bytes, Error> {
+    match disk_cache {
+        Some(disk_cache) => disk_cache.get_or_fetch(
+            &source.file_id,
+            source.updated_at,
+            offset,
+            std::cmp::min(
+                source.buffer_size,
+                source.file_size.saturating_sub(offset as u64) as usize,
+            ),
+            |offset, size, validators| {
+                download_chunk_conditional(drive, stats, source, offset, size, validators)
+            },
+        ),
+        None => download_chunk(drive, stats, source, offset, streaming_threshold),
+    }
+}
+
+/// Fetch a chunk in background, the result is sent to the returned channel
+fn spawn_fetch_chunk<D: Drive>(
+    drive: &D,
+    stats: &Arc<Stats>,
+    disk_cache: Option<&DiskCache>,
+    source: &ChunkSource,
+    offset: i64,
+    streaming_threshold: usize,
+) -> Receiver<Option<Bytes>> {
+    let (tx, rx) = mpsc::channel();
+    let drive = drive.clone();
+    let stats = stats.clone();
+    let disk_cache = disk_cache.cloned();
+    let source = source.clone();
+    thread::spawn(move || {
+        let res = fetch_chunk(
+            &drive,
+            &stats,
+            disk_cache.as_ref(),
+            &source,
+            offset,
+            streaming_threshold,
+        );
+        if res.is_err() {
+            error!(file_id = %source.file_id, offset = offset, "readahead failed");
+        }
+        // the receiver is gone if the chunk is no longer needed
+        let _ = tx.send(res.ok());
+    });
+    rx
+}
+
+fn download_chunk<D: Drive>(
+    drive: &D,
+    stats: &Stats,
+    source: &ChunkSource,
+    offset: i64,
+    streaming_threshold: usize,
+) -> Result<Bytes, Error> {
+    let file_id = &source.file_id;
+    let file_size = source.file_size;
+    let size = std::cmp::min(
+        source.buffer_size,
+        file_size.saturating_sub(offset as u64) as usize,
+    );
+    let download_url = match source.drive_id.as_deref() {
+        Some(drive_id) => drive.get_download_url_in_drive(drive_id, file_id),
+        None => drive.get_download_url(file_id),
+    };
+    stats.api_call(&download_url);
+    let download_url = with_context(download_url, || format!("get download url of {}", file_id))?;
+    let mut retries = 0;
+    loop {
+        let data = if size > streaming_threshold {
+            download_streaming(drive, &download_url, offset as _, size)
+        } else {
+            drive.download(&download_url, offset as _, size)
+        };
+        let data = with_context(data, || {
+            format!("download {} at offset {}", file_id, offset)
+        })?;
+        Stats::incr(&stats.bytes_downloaded, data.len() as u64);
+        match validate_size(data, size, offset, file_size) {
+            Ok(data) => return Ok(data),
+            Err(data) if retries >= MAX_TRUNCATED_RETRIES => return Ok(data),
+            Err(_) => retries += 1,
+        }
+    }
+}
+
+/// Download a chunk unless it's not modified since the response `validators` came from
+fn download_chunk_conditional<D: Drive>(
+    drive: &D,
+    stats: &Stats,
+    source: &ChunkSource,
+    offset: i64,
+    size: usize,
+    validators: Option<&Validators>,
+) -> Result<ConditionalDownload, Error> {
+    let file_id = &source.file_id;
+    let file_size = source.file_size;
+    let size = std::cmp::min(size, file_size.saturating_sub(offset as u64) as usize);
+    let download_url = match source.drive_id.as_deref() {
+        Some(drive_id) => drive.get_download_url_in_drive(drive_id, file_id),
+        None => drive.get_download_url(file_id),
+    };
+    stats.api_call(&download_url);
+    let download_url = with_context(download_url, || format!("get download url of {}", file_id))?;
+    let mut retries = 0;
+    loop {
+        let res = drive.download_conditional(&download_url, offset as _, size, validators);
+        let res = with_context(res, || format!("download {} at offset {}", file_id, offset))?;
+        let (data, validators) = match res {
+            ConditionalDownload::Modified { data, validators } => (data, validators),
+            ConditionalDownload::NotModified => {
+                debug!(file_id = %file_id, offset = offset, "chunk not modified");
+                return Ok(ConditionalDownload::NotModified);
+            }
+        };
+        Stats::incr(&stats.bytes_downloaded, data.len() as u64);
+        let data = match validate_size(data, size, offset, file_size) {
+            Ok(data) => data,
+            Err(data) if retries >= MAX_TRUNCATED_RETRIES => data,
+            Err(_) => {
+                retries += 1;
+                continue;
+            }
+        };
+        return Ok(ConditionalDownload::Modified { data, validators });
+    }
+}
+
+/// Read a chunk into a buffer allocated up front instead of growing it
+/// while collecting the response body
+fn download_streaming<D: Drive>(
+    drive: &D,
+    url: &str,
+    offset: u64,
+    size: usize,
+) -> anyhow::Result<Bytes> {
+    let mut reader = drive.download_streaming(url, offset, size)?;
+    let mut buf = Vec::with_capacity(size);
+    reader.read_to_end(&mut buf)?;
+    Ok(Bytes::from(buf))
+}
+
+/// Check the downloaded chunk size against the requested size,
+/// returns `Err` with the chunk if it was truncated before EOF
+fn validate_size(
+    chunk: Bytes,
+    expected: usize,
+    offset: i64,
+    file_size: u64,
+) -> Result<Bytes, Bytes> {
+    if chunk.len() > expected {
+        error!(
+            offset = offset,
+            expected = expected,
+            actual = chunk.len(),
+            "download returned more data than requested"
+        );
+        return Ok(chunk.slice(..expected));
+    }
+    if chunk.len() < expected && (offset as u64 + chunk.len() as u64) < file_size {
+        warn!(
+            offset = offset,
+            expected = expected,
+            actual = chunk.len(),
+            "download response truncated"
+        );
+        return Err(chunk);
+    }
+    Ok(chunk)
+}
+
+/// Single background worker fetching download URLs, at most one batch is queued
+fn spawn_url_prefetcher<D: Drive>(drive: D, stats: Arc<Stats>) -> SyncSender<Vec<String>> {
+    let (sender, receiver) = mpsc::sync_channel::<Vec<String>>(1);
+    thread::spawn(move || {
+        for file_ids in receiver {
+            let file_ids: Vec<&str> = file_ids.iter().map(String::as_str).collect();
+            let res = drive.batch_get_download_url(&file_ids);
+            stats.api_call(&res);
+            match res {
+                Ok(urls) => debug!(count = urls.len(), "prefetched download urls"),
+                Err(err) => {
+                    warn!(error = %format_args!("{:#}", err), "prefetch download urls failed")
+                }
+            }
+        }
+    });
+    sender
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockDrive;
+
+    fn file_cache(drive: &MockDrive) -> FileCache<MockDrive> {
+        FileCache::new(
+            drive.clone(),
+            Arc::new(Stats::default()),
+            4,
+            4,
+            u32::MAX,
+            CacheCompression::None,
+            None,
+        )
+    }
+
+    fn open_twice(drive: &MockDrive, cache: &mut FileCache<MockDrive>, content: &[u8]) {
+        let file_id = drive.add_file("root", "a.txt", content);
+        let file = drive.get_file(&file_id).unwrap();
+        cache.open(1, &file, false);
+        cache.open(2, &file, false);
+    }
+
+    #[test]
+    fn prefetch_is_shared_by_handles_of_the_same_file() {
+        let drive = MockDrive::new();
+        let mut cache = file_cache(&drive);
+        open_twice(&drive, &mut cache, b"hello world");
+        cache.prefetch_on_open(1);
+        cache.prefetch_on_open(2);
+
+        assert_eq!(&cache.read(2, 0, 4).unwrap()[..], b"hell");
+        assert_eq!(&cache.read(1, 0, 4).unwrap()[..], b"hell");
+        assert_eq!(drive.downloads(), 1);
+    }
+
+    #[test]
+    fn readahead_is_shared_by_handles_of_the_same_file() {
+        let drive = MockDrive::new();
+        let mut cache = file_cache(&drive).with_prefetch_window(1);
+        open_twice(&drive, &mut cache, b"12345678");
+
+        assert_eq!(&cache.read(1, 0, 4).unwrap()[..], b"1234");
+        assert_eq!(&cache.read(2, 0, 4).unwrap()[..], b"1234");
+        assert_eq!(&cache.read(2, 4, 4).unwrap()[..], b"5678");
+        assert_eq!(&cache.read(1, 4, 4).unwrap()[..], b"5678");
+        assert_eq!(drive.downloads(), 2);
+    }
+
+    #[test]
+    fn compressed_buffers_are_read_back() {
+        let drive = MockDrive::new();
+        let mut cache = FileCache::new(
+            drive.clone(),
+            Arc::new(Stats::default()),
+            8192,
+            8192,
+            u32::MAX,
+            CacheCompression::Zstd,
+            None,
+        );
+        let content: Vec<u8> = (0..8192u32).map(|i| b'a' + (i % 7) as u8).collect();
+        let file_id = drive.add_file("root", "a.txt", &content);
+        cache.open(1, &drive.get_file(&file_id).unwrap(), false);
+
+        assert_eq!(&cache.read(1, 0, 100).unwrap()[..], &content[..100]);
+        assert_eq!(&cache.read(1, 4000, 100).unwrap()[..], &content[4000..4100]);
+        let cached = &cache.cache[&1];
+        assert!(cached.compressed);
+        assert!(cached.buffer.len() < content.len());
+        assert_eq!(drive.downloads(), 1);
     }
 }
@@ -0,0 +1,267 @@
+//! Inode table storage
+//!
+//! Inodes are kept in memory by default, very large drives can store them in SQLite
+//! to reduce memory usage.
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+
+use crate::drive::{AliyunFile, FileType};
+use crate::error::Error;
+
+#[derive(Debug, Clone)]
+pub struct Inode {
+    pub parent: u64,
+    /// Modified locally and not uploaded yet
+    pub dirty: bool,
+    /// Total size of the loaded descendants of a directory, `None` if not computed
+    /// or invalidated by a change in the subtree
+    pub cached_size: Option<u64>,
+}
+
+impl Inode {
+    pub fn new(parent: u64) -> Self {
+        Self {
+            parent,
+            dirty: false,
+            cached_size: None,
+        }
+    }
+}
+
+pub trait InodeStore: Send {
+    fn get(&self, ino: u64) -> Result<Option<(Inode, AliyunFile)>, Error>;
+
+    /// Insert or replace an inode, it's filed under `inode.parent` by `file.name`
+    fn insert(&mut self, ino: u64, inode: Inode, file: AliyunFile) -> Result<(), Error>;
+
+    fn remove(&mut self, ino: u64) -> Result<(), Error>;
+
+    /// Children of a directory inode, sorted by name
+    fn children_of(&self, ino: u64) -> Result<BTreeMap<OsString, u64>, Error>;
+
+    /// Number of inodes and how many of them are files
+    fn count(&self) -> Result<(u64, u64), Error>;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryInodeStore {
+    inodes: BTreeMap<u64, (Inode, AliyunFile)>,
+    // parent inode -> name -> child inode
+    children: BTreeMap<u64, BTreeMap<OsString, u64>>,
+}
+
+impl MemoryInodeStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn unlink(&mut self, ino: u64, inode: &Inode, file: &AliyunFile) {
+        if let Some(children) = self.children.get_mut(&inode.parent) {
+            let name = OsString::from(&file.name);
+            if children.get(&name) == Some(&ino) {
+                children.remove(&name);
+            }
+        }
+    }
+}
+
+impl InodeStore for MemoryInodeStore {
+    fn get(&self, ino: u64) -> Result<Option<(Inode, AliyunFile)>, Error> {
+        Ok(self.inodes.get(&ino).cloned())
+    }
+
+    fn insert(&mut self, ino: u64, inode: Inode, file: AliyunFile) -> Result<(), Error> {
+        if let Some((old_inode, old_file)) = self.inodes.remove(&ino) {
+            self.unlink(ino, &old_inode, &old_file);
+        }
+        self.children
+            .entry(inode.parent)
+            .or_default()
+            .insert(OsString::from(&file.name), ino);
+        self.inodes.insert(ino, (inode, file));
+        Ok(())
+    }
+
+    fn remove(&mut self, ino: u64) -> Result<(), Error> {
+        if let Some((inode, file)) = self.inodes.remove(&ino) {
+            self.unlink(ino, &inode, &file);
+        }
+        self.children.remove(&ino);
+        Ok(())
+    }
+
+    fn children_of(&self, ino: u64) -> Result<BTreeMap<OsString, u64>, Error> {
+        Ok(self.children.get(&ino).cloned().unwrap_or_default())
+    }
+
+    fn count(&self) -> Result<(u64, u64), Error> {
+        let files = self
+            .inodes
+            .values()
+            .filter(|(_, file)| matches!(file.r#type, FileType::File))
+            .count();
+        Ok((self.inodes.len() as u64, files as u64))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteInodeStore;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::collections::BTreeMap;
+    use std::ffi::OsString;
+    use std::path::Path;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    use rusqlite::{params, Connection, OptionalExtension};
+    use tracing::error;
+
+    use super::{Inode, InodeStore};
+    use crate::drive::AliyunFile;
+    use crate::error::Error;
+
+    pub struct SqliteInodeStore {
+        conn: Connection,
+    }
+
+    fn storage_error(err: impl std::fmt::Display) -> Error {
+        error!(error = %err, "inode store failed");
+        Error::InodeStoreFailed
+    }
+
+    // `Instant` can't be stored, it's converted to seconds since the Unix epoch
+    fn unix_from_instant(instant: Instant) -> f64 {
+        let time = SystemTime::now() - instant.elapsed();
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+    }
+
+    fn instant_from_unix(secs: f64) -> Instant {
+        let time = UNIX_EPOCH + Duration::from_secs_f64(secs.max(0.0));
+        let elapsed = time.elapsed().unwrap_or_default();
+        Instant::now()
+            .checked_sub(elapsed)
+            .unwrap_or_else(Instant::now)
+    }
+
+    impl SqliteInodeStore {
+        pub fn open(path: &Path) -> anyhow::Result<Self> {
+            let conn = Connection::open(path)?;
+            // inode numbers are only valid for a single mount
+            conn.execute_batch(
+                "PRAGMA journal_mode = WAL;
+                PRAGMA synchronous = OFF;
+                DROP TABLE IF EXISTS inodes;
+                CREATE TABLE inodes (
+                    ino INTEGER PRIMARY KEY,
+                    parent INTEGER NOT NULL,
+                    name TEXT NOT NULL,
+                    dirty INTEGER NOT NULL,
+                    cached_size INTEGER,
+                    fetched_at REAL NOT NULL,
+                    file TEXT NOT NULL
+                );
+                CREATE INDEX inodes_parent_name ON inodes (parent, name);",
+            )?;
+            Ok(Self { conn })
+        }
+    }
+
+    impl InodeStore for SqliteInodeStore {
+        fn get(&self, ino: u64) -> Result<Option<(Inode, AliyunFile)>, Error> {
+            let row = self
+                .conn
+                .prepare_cached(
+                    "SELECT parent, dirty, cached_size, fetched_at, file FROM inodes WHERE ino = ?1",
+                )
+                .and_then(|mut stmt| {
+                    stmt.query_row(params![ino as i64], |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, bool>(1)?,
+                            row.get::<_, Option<i64>>(2)?,
+                            row.get::<_, f64>(3)?,
+                            row.get::<_, String>(4)?,
+                        ))
+                    })
+                    .optional()
+                })
+                .map_err(storage_error)?;
+            match row {
+                Some((parent, dirty, cached_size, fetched_at, file)) => {
+                    let mut file: AliyunFile =
+                        serde_json::from_str(&file).map_err(storage_error)?;
+                    file.fetched_at = instant_from_unix(fetched_at);
+                    let inode = Inode {
+                        parent: parent as u64,
+                        dirty,
+                        cached_size: cached_size.map(|size| size as u64),
+                    };
+                    Ok(Some((inode, file)))
+                }
+                None => Ok(None),
+            }
+        }
+
+        fn insert(&mut self, ino: u64, inode: Inode, file: AliyunFile) -> Result<(), Error> {
+            let data = serde_json::to_string(&file).map_err(storage_error)?;
+            self.conn
+                .prepare_cached(
+                    "INSERT OR REPLACE INTO inodes (ino, parent, name, dirty, cached_size, fetched_at, file)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                )
+                .and_then(|mut stmt| {
+                    stmt.execute(params![
+                        ino as i64,
+                        inode.parent as i64,
+                        file.name,
+                        inode.dirty,
+                        inode.cached_size.map(|size| size as i64),
+                        unix_from_instant(file.fetched_at),
+                        data
+                    ])
+                })
+                .map_err(storage_error)?;
+            Ok(())
+        }
+
+        fn remove(&mut self, ino: u64) -> Result<(), Error> {
+            self.conn
+                .prepare_cached("DELETE FROM inodes WHERE ino = ?1")
+                .and_then(|mut stmt| stmt.execute(params![ino as i64]))
+                .map_err(storage_error)?;
+            Ok(())
+        }
+
+        fn children_of(&self, ino: u64) -> Result<BTreeMap<OsString, u64>, Error> {
+            let mut stmt = self
+                .conn
+                .prepare_cached("SELECT name, ino FROM inodes WHERE parent = ?1")
+                .map_err(storage_error)?;
+            let rows = stmt
+                .query_map(params![ino as i64], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+                })
+                .map_err(storage_error)?;
+            let mut children = BTreeMap::new();
+            for row in rows {
+                let (name, child) = row.map_err(storage_error)?;
+                children.insert(OsString::from(name), child as u64);
+            }
+            Ok(children)
+        }
+
+        fn count(&self) -> Result<(u64, u64), Error> {
+            self.conn
+                .query_row(
+                    "SELECT COUNT(*), COUNT(*) FILTER (WHERE json_extract(file, '$.type') = 'file')
+                    FROM inodes",
+                    [],
+                    |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+                )
+                .map_err(storage_error)
+        }
+    }
+}
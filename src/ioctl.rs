@@ -0,0 +1,40 @@
+//! Custom ioctl commands
+//!
+//! Local tools can issue drive-specific commands by opening `<mount>/__control__`
+//! (or any file or directory inside the mount) and calling `ioctl(2)` with the
+//! request numbers below. Only root and the user who mounted the drive are allowed
+//! to issue them.
+//!
+//! Request numbers follow the Linux `_IOC` encoding with type `'A'`.
+
+/// Name of the virtual control file in the mount root
+pub const CONTROL_FILE_NAME: &str = "__control__";
+
+/// Size of the buffer returned by [`ALIYUN_IOC_GET_FILE_ID`]
+pub const ALIYUN_FILE_ID_MAX_LEN: u32 = 64;
+
+const ALIYUN_IOC_MAGIC: u32 = b'A' as u32;
+
+const IOC_NONE: u32 = 0;
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+const fn ioc(dir: u32, nr: u32, size: u32) -> u32 {
+    (dir << 30) | (size << 16) | (ALIYUN_IOC_MAGIC << 8) | nr
+}
+
+/// `_IO('A', 1)`: force refresh of the directory, the control file refreshes the mount root
+/// and a regular file refreshes its parent directory
+pub const ALIYUN_IOC_REFRESH_DIR: u32 = ioc(IOC_NONE, 1, 0);
+/// `_IO('A', 2)`: evict all cached file data
+pub const ALIYUN_IOC_CLEAR_CACHE: u32 = ioc(IOC_NONE, 2, 0);
+/// `_IO('A', 4)`: write a debug dump of the filesystem state, same as `SIGUSR2`
+pub const ALIYUN_IOC_DEBUG_DUMP: u32 = ioc(IOC_NONE, 4, 0);
+/// `_IOW('A', 5, u32)`: write the paths and sizes of all files up to the given depth
+/// below the mount root as JSON to `index.json` in the working directory or the temp directory,
+/// the index is built in background and the ioctl returns right away
+pub const ALIYUN_IOC_DUMP_INDEX: u32 = ioc(IOC_WRITE, 5, 4);
+/// `_IO('A', 6)`: upload the files in the local overlay directory and remove them locally
+pub const ALIYUN_IOC_SYNC_OVERLAY: u32 = ioc(IOC_NONE, 6, 0);
+/// `_IOR('A', 3, char[64])`: get the remote file_id of the fd as a NUL-padded string
+pub const ALIYUN_IOC_GET_FILE_ID: u32 = ioc(IOC_READ, 3, ALIYUN_FILE_ID_MAX_LEN);
@@ -1,27 +1,74 @@
-use std::{env, io, path::PathBuf};
+use std::{env, io, path::PathBuf, time::Duration};
 
-use clap::Parser;
+use anyhow::Context;
+use clap::{Parser, Subcommand};
 use fuser::MountOption;
+use tracing::{info, warn};
 
-use drive::{AliyunDrive, DriveConfig};
-use vfs::AliyunDriveFileSystem;
+use drive::{AliyunDriveBuilder, DriveConfig, OrderBy, OrderDirection};
+use file_cache::{CacheBackend, CacheCompression};
+use inode_store::{InodeStore, MemoryInodeStore};
+use vfs::{AliyunDriveFileSystem, VfsConfig};
 
+mod chunk_cache;
+mod disk_cache;
 mod drive;
 mod error;
 mod file_cache;
+mod inode_store;
+mod ioctl;
+mod stats;
+#[cfg(test)]
+mod test_utils;
 mod vfs;
 
+// Mounts the drive at `--path` unless a subcommand is given, global options are
+// passed after the subcommand, the others only apply to mounting
 #[derive(Parser, Debug)]
-#[command(name = "aliyundrive-fuse", about, version, author)]
+#[command(
+    name = "aliyundrive-fuse",
+    about,
+    version,
+    author,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Opt {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Mount point
-    #[arg(long)]
-    path: PathBuf,
+    #[arg(long, required = true)]
+    path: Option<PathBuf>,
     /// Aliyun drive refresh token
-    #[arg(short, long, env = "REFRESH_TOKEN")]
-    refresh_token: String,
+    #[arg(short, long, global = true, env = "REFRESH_TOKEN")]
+    refresh_token: Option<String>,
+    /// Read the refresh token from this environment variable instead of `REFRESH_TOKEN`,
+    /// falls back to `--refresh-token` if it's not set
+    #[arg(long, global = true, value_name = "VAR_NAME")]
+    refresh_token_env: Option<String>,
+    /// Shell command printing the refresh token to stdout, run before every token refresh,
+    /// takes precedence over `--refresh-token`
+    #[arg(long, global = true, value_name = "CMD")]
+    refresh_token_command: Option<String>,
+    /// Drive id to mount, overrides the default drive of the account
+    #[arg(long, global = true, value_name = "ID")]
+    drive_id: Option<String>,
+    /// Id of a registered third-party app, sent when refreshing the token
+    #[arg(long, global = true)]
+    app_id: Option<String>,
+    /// Base URL of the drive API, e.g. to go through a local proxy
+    #[arg(long, global = true, default_value = "https://api.aliyundrive.com", value_parser = parse_url)]
+    api_base_url: String,
+    /// URL of the token refresh endpoint
+    #[arg(
+        long,
+        global = true,
+        default_value = "https://api.aliyundrive.com/token/refresh",
+        value_parser = parse_url
+    )]
+    refresh_token_url: String,
     /// Working directory, refresh_token will be stored in there if specified
-    #[arg(short = 'w', long)]
+    #[arg(short = 'w', long, global = true)]
     workdir: Option<PathBuf>,
     /// Allow other users to access the drive
     #[arg(long)]
@@ -29,6 +76,225 @@ struct Opt {
     /// Read/download buffer size in bytes, defaults to 10MB
     #[arg(short = 'S', long, default_value = "10485760")]
     read_buffer_size: usize,
+    /// Max size in bytes of a single read request from the kernel,
+    /// defaults to the read buffer size on Linux
+    #[arg(long, value_name = "BYTES")]
+    kernel_max_read: Option<usize>,
+    /// Max size in bytes of kernel readahead
+    #[arg(long, value_name = "BYTES")]
+    kernel_max_readahead: Option<u32>,
+    /// Max size in bytes of a single write request from the kernel
+    #[arg(long, value_name = "BYTES")]
+    kernel_max_write: Option<u32>,
+    /// Max read/download buffer size in bytes for sequential reads, defaults to 80MB
+    #[arg(long, default_value = "83886080")]
+    max_read_buffer_size: usize,
+    /// Number of contiguous reads before doubling the read buffer size
+    #[arg(long, default_value = "4")]
+    sequential_read_threshold: u32,
+    /// Download chunks larger than this in bytes with a streaming reader, defaults to 16MB
+    #[arg(long, default_value = "16777216")]
+    streaming_threshold: usize,
+    /// Disable the file cache, every read is downloaded from the drive, for debugging
+    #[arg(long)]
+    no_cache: bool,
+    /// Remember runs of zero bytes in downloaded chunks and serve later reads of them
+    /// without downloading, useful for disk images and database files
+    #[arg(long, conflicts_with = "no_cache")]
+    enable_sparse_reads: bool,
+    /// Number of chunks downloaded ahead of sequential reads, 0 to disable
+    #[arg(long, default_value = "2")]
+    prefetch_window: usize,
+    /// API request timeout in seconds
+    #[arg(long, global = true, default_value = "30")]
+    api_timeout: u64,
+    /// File download request timeout in seconds
+    #[arg(long, global = true, default_value = "120")]
+    download_timeout: u64,
+    /// Max time in seconds to wait for the drive to initialize on startup
+    #[arg(long, global = true, default_value = "30")]
+    startup_timeout: u64,
+    /// PEM-encoded CA certificate file to trust in addition to the system roots,
+    /// useful behind HTTPS inspection proxies
+    #[arg(long, global = true)]
+    ca_cert: Option<PathBuf>,
+    /// Disable TLS certificate verification, for testing only.
+    /// This allows anyone on the network path to read and modify traffic including your tokens,
+    /// requires --i-accept-the-security-risk
+    #[arg(long, global = true, requires = "i_accept_the_security_risk")]
+    no_verify_tls: bool,
+    /// Confirm that you understand the risk of --no-verify-tls
+    #[arg(long, global = true)]
+    i_accept_the_security_risk: bool,
+    /// Only show files of the category (plus all directories)
+    #[arg(long, value_parser = ["image", "video", "audio", "doc", "others"])]
+    only_category: Option<String>,
+    /// Allow setting the `user.aliyundrive.updated_at` and `user.aliyundrive.name`
+    /// extended attributes to update the modification time or rename files
+    #[arg(long, requires = "read_write")]
+    enable_write_xattr: bool,
+    /// Show a `.<name>.thumb.jpg` thumbnail file next to images and videos
+    #[arg(long)]
+    expose_thumbnails: bool,
+    /// Show a `<name><suffix>` directory next to every file listing its historical versions,
+    /// e.g. `.versions`
+    #[arg(long, value_name = "SUFFIX", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    version_dir: Option<String>,
+    /// Save written and created files to this directory instead of uploading them,
+    /// files in it shadow those on the drive until synced with the `sync-overlay` subcommand
+    #[arg(long, value_name = "DIR")]
+    local_overlay: Option<PathBuf>,
+    /// Custom HTTP header sent with every request in `name:value` format, can be repeated
+    #[arg(long = "header", global = true, value_name = "NAME:VALUE", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+    /// Don't include the drive nick name in the volume name, macOS only
+    #[arg(long)]
+    no_volname_nick: bool,
+    /// Custom volume name, macOS only
+    #[arg(long, conflicts_with = "no_volname_nick")]
+    volname: Option<String>,
+    /// Pre-fetch directory listings up to this depth on mount, 0 to disable
+    #[arg(long, default_value = "0")]
+    warmup_depth: u32,
+    /// Show directories this many levels below the mount point as empty,
+    /// limits the inodes loaded for very deep directory trees
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_path_depth: Option<u32>,
+    /// Download files matching this glob relative to the mount point into the disk cache
+    /// on mount, `*` and `?` don't match `/` while `**` does, can be repeated
+    #[arg(long, value_name = "GLOB", requires = "cache_dir")]
+    preload_files: Vec<String>,
+    /// Store the inode table in a SQLite database at this path instead of memory,
+    /// reduces memory usage for very large drives
+    #[cfg(feature = "sqlite")]
+    #[arg(long)]
+    inode_db: Option<PathBuf>,
+    /// Bypass kernel page cache, useful for streaming large files
+    #[arg(long)]
+    direct_io: bool,
+    /// Mount read-write, files written are uploaded on close or fsync
+    #[arg(long)]
+    read_write: bool,
+    /// Move an existing file to the recycle bin once a file with the same name is uploaded,
+    /// instead of overwriting it in place
+    #[arg(long, requires = "read_write")]
+    enable_trash_on_overwrite: bool,
+    /// Let the kernel buffer small writes and flush them in larger chunks
+    #[arg(long, requires = "read_write")]
+    writeback_cache: bool,
+    /// Max size in bytes of a file opened for writing, its content is staged in memory
+    /// until uploaded, defaults to 1GB
+    #[arg(long, default_value = "1073741824")]
+    max_staged_size: u64,
+    /// Seconds before cached file attributes are fetched again on getattr
+    #[arg(long, default_value = "30")]
+    attr_cache_ttl: u64,
+    /// Max number of concurrent directory listing requests
+    #[arg(long, default_value = "2")]
+    list_concurrency: usize,
+    /// Max number of concurrent API requests
+    #[arg(long, default_value = "4")]
+    api_concurrency: usize,
+    /// Log every API request and response, the `Authorization` header is redacted
+    #[arg(long, global = true)]
+    log_api_calls: bool,
+    /// Sort field of directory listings
+    #[arg(long, value_enum, default_value = "updated_at")]
+    order_by: OrderBy,
+    /// Sort direction of directory listings
+    #[arg(long, value_enum, default_value = "desc")]
+    order_direction: OrderDirection,
+    /// Log a warning when the number of open file handles exceeds this
+    #[arg(long, default_value = "10000")]
+    max_open_files: usize,
+    /// Interval in seconds of checking opened files for remote changes, 0 to disable
+    #[arg(long, default_value = "30")]
+    open_file_watch_interval: u64,
+    /// Compress cached chunks in memory, trades CPU time for memory
+    #[arg(long, value_enum, default_value = "none")]
+    cache_compression: CacheCompression,
+    /// Directory of the disk-backed chunk cache, disabled if not specified
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+    /// Max total size in bytes of the disk-backed chunk cache, defaults to 1GB
+    #[arg(long, default_value = "1073741824")]
+    cache_dir_size_limit: u64,
+    /// Interval in seconds of evicting chunks from the disk-backed chunk cache
+    #[arg(long, default_value = "300")]
+    cache_evict_interval: u64,
+    /// Storage of the read buffers of opened files
+    #[arg(long, value_enum, default_value = "memory")]
+    cache_backend: CacheBackend,
+    /// Ring buffer file of the disk cache backend, it must not exist and is removed
+    /// once mapped. Defaults to `chunks.<pid>.cache` in the working directory
+    /// or the temp directory
+    #[arg(long)]
+    cache_backend_file: Option<PathBuf>,
+    /// Size in bytes of the ring buffer file of the disk cache backend, defaults to 1GB
+    #[arg(long, default_value = "1073741824")]
+    cache_backend_size: u64,
+    /// Update file access time instead of mounting with noatime
+    #[arg(long, conflicts_with = "relatime")]
+    atime: bool,
+    /// Mount with relatime instead of noatime
+    #[arg(long)]
+    relatime: bool,
+    /// Extra mount option passed through as is, e.g. `fsname=aliyundrive`, can be repeated
+    #[arg(long = "mount-option", value_name = "OPT")]
+    mount_options: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check that the refresh token is valid and exit without mounting
+    Check,
+    /// Print the drives of the account and exit without mounting
+    ListDrives,
+    /// Write the paths and sizes of all files as JSON to a file and exit without mounting
+    DumpIndex {
+        /// Output file
+        #[arg(long, value_name = "OUTPUT")]
+        output: PathBuf,
+        /// Max directory depth below the root
+        #[arg(long, default_value = "3")]
+        depth: u32,
+        /// Only index files of the category (plus all directories)
+        #[arg(long, value_parser = ["image", "video", "audio", "doc", "others"])]
+        only_category: Option<String>,
+    },
+    /// Upload the files of a local overlay directory to the drive and exit without mounting
+    SyncOverlay {
+        /// Local overlay directory, see `--local-overlay`
+        #[arg(long, value_name = "DIR")]
+        local_overlay: PathBuf,
+    },
+}
+
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("invalid header `{}`, expected `name:value`", s))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+fn parse_url(s: &str) -> Result<String, String> {
+    reqwest::Url::parse(s).map_err(|err| format!("invalid URL `{}`: {}", s, err))?;
+    // API paths are appended with a leading slash
+    Ok(s.trim_end_matches('/').to_string())
+}
+
+/// Name of a mount option without its value
+fn mount_option_name(option: &MountOption) -> Option<&str> {
+    let name = match option {
+        MountOption::RO => "ro",
+        MountOption::RW => "rw",
+        MountOption::NoAtime => "noatime",
+        MountOption::AllowOther => "allow_other",
+        MountOption::AutoUnmount => "auto_unmount",
+        MountOption::CUSTOM(option) => option.split('=').next().unwrap_or_default(),
+        _ => return None,
+    };
+    Some(name)
 }
 
 fn main() -> anyhow::Result<()> {
@@ -41,34 +307,203 @@ fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
 
     let opt = Opt::parse();
+    let refresh_token = if opt.refresh_token_command.is_some() {
+        // the command is run on the first token refresh
+        String::new()
+    } else {
+        opt.refresh_token_env
+            .as_deref()
+            .and_then(|name| env::var(name).ok())
+            .or(opt.refresh_token)
+            .ok_or_else(|| anyhow::anyhow!("refresh token is required"))?
+    };
+    let cache_backend_file = opt.cache_backend_file.clone().unwrap_or_else(|| {
+        opt.workdir
+            .clone()
+            .unwrap_or_else(env::temp_dir)
+            .join(format!("chunks.{}.cache", std::process::id()))
+    });
+    let debug_dump_file = opt
+        .workdir
+        .clone()
+        .unwrap_or_else(env::temp_dir)
+        .join("debug_dump.json");
+    let index_file = opt
+        .workdir
+        .clone()
+        .unwrap_or_else(env::temp_dir)
+        .join("index.json");
     let drive_config = DriveConfig {
-        api_base_url: "https://api.aliyundrive.com".to_string(),
-        refresh_token_url: "https://api.aliyundrive.com/token/refresh".to_string(),
+        api_base_url: opt.api_base_url,
+        refresh_token_url: opt.refresh_token_url,
         workdir: opt.workdir,
-        app_id: None,
+        refresh_token_command: opt.refresh_token_command,
+        app_id: opt.app_id,
+        api_timeout: Duration::from_secs(opt.api_timeout),
+        download_timeout: Duration::from_secs(opt.download_timeout),
+        startup_timeout: Duration::from_secs(opt.startup_timeout),
+        ca_cert: opt.ca_cert,
+        no_verify_tls: opt.no_verify_tls,
+        extra_headers: opt.headers.into_iter().collect(),
+        list_concurrency: opt.list_concurrency,
+        api_concurrency: opt.api_concurrency,
+        log_api_calls: opt.log_api_calls,
+        trash_on_overwrite: opt.enable_trash_on_overwrite,
+        order_by: opt.order_by,
+        order_direction: opt.order_direction,
     };
-    let drive = AliyunDrive::new(drive_config, opt.refresh_token).map_err(|_| {
-        io::Error::new(io::ErrorKind::Other, "initialize aliyundrive client failed")
+    let mut builder = AliyunDriveBuilder::new()
+        .with_config(drive_config)
+        .with_credentials(refresh_token, None);
+    if let Some(drive_id) = opt.drive_id {
+        builder = builder.with_drive_id(drive_id);
+    }
+    let drive = builder.build().map_err(|err| {
+        io::Error::other(format!("initialize aliyundrive client failed: {:#}", err))
     })?;
+    match opt.command {
+        Some(Command::Check) => {
+            drive.check_auth()?;
+            info!("refresh token is valid");
+            return Ok(());
+        }
+        Some(Command::ListDrives) => {
+            let drives = drive.list_drives().context("list drives failed")?;
+            println!(
+                "{:<24} {:<10} {:<24} {:>16} {:>16}",
+                "DRIVE_ID", "TYPE", "NAME", "TOTAL_SIZE", "USED_SIZE"
+            );
+            for info in drives {
+                println!(
+                    "{:<24} {:<10} {:<24} {:>16} {:>16}",
+                    info.drive_id,
+                    info.drive_type,
+                    info.drive_name,
+                    info.total_size,
+                    info.used_size
+                );
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
 
     let _nick_name = drive.nick_name.clone();
-    let vfs = AliyunDriveFileSystem::new(drive, opt.read_buffer_size);
-    let mut mount_options = vec![MountOption::AutoUnmount, MountOption::NoAtime];
-    // read only for now
-    mount_options.push(MountOption::RO);
+    let inodes: Box<dyn InodeStore> = match () {
+        #[cfg(feature = "sqlite")]
+        () if opt.inode_db.is_some() => {
+            let path = opt.inode_db.as_ref().unwrap();
+            Box::new(inode_store::SqliteInodeStore::open(path)?)
+        }
+        () => Box::new(MemoryInodeStore::new()),
+    };
+    if opt.no_cache {
+        warn!("file cache is disabled, every read is downloaded from the drive");
+    }
+    let vfs_config = VfsConfig {
+        read_buffer_size: opt.read_buffer_size,
+        max_read_buffer_size: opt.max_read_buffer_size,
+        kernel_max_readahead: opt.kernel_max_readahead,
+        kernel_max_write: opt.kernel_max_write,
+        sequential_read_threshold: opt.sequential_read_threshold,
+        streaming_threshold: opt.streaming_threshold,
+        no_cache: opt.no_cache,
+        sparse_reads: opt.enable_sparse_reads,
+        prefetch_window: opt.prefetch_window,
+        max_staged_size: opt.max_staged_size,
+        only_category: match &opt.command {
+            Some(Command::DumpIndex { only_category, .. }) => only_category.clone(),
+            _ => opt.only_category,
+        },
+        warmup_depth: opt.warmup_depth,
+        max_path_depth: opt.max_path_depth,
+        preload_files: opt.preload_files,
+        direct_io: opt.direct_io,
+        enable_write_xattr: opt.enable_write_xattr,
+        expose_thumbnails: opt.expose_thumbnails,
+        version_dir: opt.version_dir,
+        attr_cache_ttl: Duration::from_secs(opt.attr_cache_ttl),
+        max_open_files: opt.max_open_files,
+        open_file_watch_interval: Duration::from_secs(opt.open_file_watch_interval),
+        cache_compression: opt.cache_compression,
+        cache_dir: opt.cache_dir,
+        cache_dir_size_limit: opt.cache_dir_size_limit,
+        cache_evict_interval: Duration::from_secs(opt.cache_evict_interval),
+        cache_backend: opt.cache_backend,
+        cache_backend_file,
+        cache_backend_size: opt.cache_backend_size,
+        debug_dump_file,
+        index_file,
+        local_overlay: match &opt.command {
+            Some(Command::SyncOverlay { local_overlay }) => Some(local_overlay.clone()),
+            _ => opt.local_overlay,
+        },
+    };
+    let mut vfs = AliyunDriveFileSystem::new(drive, inodes, vfs_config)?;
+    match opt.command {
+        Some(Command::DumpIndex { output, depth, .. }) => {
+            vfs.dump_index(depth, &output)?;
+            return Ok(());
+        }
+        Some(Command::SyncOverlay { .. }) => {
+            let uploaded = vfs.sync_overlay()?;
+            println!("{} files uploaded", uploaded);
+            return Ok(());
+        }
+        _ => {}
+    }
+    vfs::install_debug_dump_handler();
+    let mut mount_options = vec![MountOption::AutoUnmount];
+    if opt.relatime {
+        mount_options.push(MountOption::CUSTOM("relatime".to_string()));
+    } else if !opt.atime {
+        mount_options.push(MountOption::NoAtime);
+    }
+    if opt.read_write {
+        if opt.writeback_cache {
+            mount_options.push(MountOption::CUSTOM("writeback_cache".to_string()));
+        }
+    } else {
+        mount_options.push(MountOption::RO);
+    }
     if opt.allow_other {
         mount_options.push(MountOption::AllowOther);
     }
+    let kernel_max_read = match opt.kernel_max_read {
+        Some(max_read) => Some(max_read),
+        // a read request never needs more than one read buffer
+        None if cfg!(target_os = "linux") => Some(opt.read_buffer_size),
+        None => None,
+    };
+    if let Some(max_read) = kernel_max_read {
+        mount_options.push(MountOption::CUSTOM(format!("max_read={}", max_read)));
+    }
     if cfg!(target_os = "macos") {
         mount_options.push(MountOption::CUSTOM("local".to_string()));
         mount_options.push(MountOption::CUSTOM("noappledouble".to_string()));
-        let volname = if let Some(nick_name) = _nick_name {
+        let volname = if let Some(volname) = opt.volname {
+            format!("volname={}", volname)
+        } else if opt.no_volname_nick {
+            "volname=AliyunDrive".to_string()
+        } else if let Some(nick_name) = _nick_name {
             format!("volname=阿里云盘({})", nick_name)
         } else {
             "volname=阿里云盘".to_string()
         };
         mount_options.push(MountOption::CUSTOM(volname));
     }
-    fuser::mount2(vfs, opt.path, &mount_options)?;
+    for option in opt.mount_options {
+        let name = option.split('=').next().unwrap_or_default();
+        if mount_options
+            .iter()
+            .any(|o| mount_option_name(o) == Some(name))
+        {
+            warn!(option = %option, "mount option is already set, ignored");
+            continue;
+        }
+        mount_options.push(MountOption::CUSTOM(option));
+    }
+    let path = opt.path.context("mount point is required")?;
+    fuser::mount2(vfs, path, &mount_options)?;
     Ok(())
 }
@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
+
+use tracing::info;
+
+/// Number of recent API call timestamps kept for debug dumps
+const MAX_RECENT_API_CALLS: usize = 100;
+
+/// Counters shared between the FUSE adaptor and the file cache
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub total_reads: AtomicU64,
+    pub total_writes: AtomicU64,
+    pub bytes_read: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    pub api_calls: AtomicU64,
+    pub api_errors: AtomicU64,
+    pub inodes_loaded: AtomicU64,
+    pub inodes_evicted: AtomicU64,
+    pub bytes_downloaded: AtomicU64,
+    pub bytes_uploaded: AtomicU64,
+    /// Timestamps of the most recent API calls, oldest first
+    recent_api_calls: Mutex<VecDeque<SystemTime>>,
+}
+
+/// Point-in-time snapshot of [`Stats`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VfsStats {
+    pub total_reads: u64,
+    pub total_writes: u64,
+    pub bytes_read: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub api_calls: u64,
+    pub api_errors: u64,
+    pub inodes_loaded: u64,
+    pub inodes_evicted: u64,
+    pub bytes_downloaded: u64,
+    pub bytes_uploaded: u64,
+    /// Counted by the drive, not part of [`Stats`]
+    pub token_refresh_retries: u64,
+}
+
+impl Stats {
+    pub fn incr(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Record the outcome of an API call
+    pub fn api_call<T, E>(&self, res: &Result<T, E>) {
+        Self::incr(&self.api_calls, 1);
+        if res.is_err() {
+            Self::incr(&self.api_errors, 1);
+        }
+        let mut recent = self
+            .recent_api_calls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if recent.len() >= MAX_RECENT_API_CALLS {
+            recent.pop_front();
+        }
+        recent.push_back(SystemTime::now());
+    }
+
+    pub fn recent_api_calls(&self) -> Vec<SystemTime> {
+        self.recent_api_calls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> VfsStats {
+        VfsStats {
+            total_reads: self.total_reads.load(Ordering::Relaxed),
+            total_writes: self.total_writes.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            api_calls: self.api_calls.load(Ordering::Relaxed),
+            api_errors: self.api_errors.load(Ordering::Relaxed),
+            inodes_loaded: self.inodes_loaded.load(Ordering::Relaxed),
+            inodes_evicted: self.inodes_evicted.load(Ordering::Relaxed),
+            bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed),
+            bytes_uploaded: self.bytes_uploaded.load(Ordering::Relaxed),
+            ..Default::default()
+        }
+    }
+}
+
+impl VfsStats {
+    /// Fraction of reads served from the read buffers
+    pub fn cache_hit_ratio(&self) -> f64 {
+        let total = self.cache_hits + self.cache_misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.cache_hits as f64 / total as f64
+    }
+
+    pub fn log_summary(&self) {
+        info!(
+            total_reads = self.total_reads,
+            total_writes = self.total_writes,
+            bytes_read = self.bytes_read,
+            cache_hits = self.cache_hits,
+            cache_misses = self.cache_misses,
+            cache_hit_ratio = %format_args!("{:.2}", self.cache_hit_ratio()),
+            api_calls = self.api_calls,
+            api_errors = self.api_errors,
+            inodes_loaded = self.inodes_loaded,
+            inodes_evicted = self.inodes_evicted,
+            bytes_downloaded = self.bytes_downloaded,
+            bytes_uploaded = self.bytes_uploaded,
+            token_refresh_retries = self.token_refresh_retries,
+            "filesystem stats"
+        );
+    }
+}
@@ -0,0 +1,372 @@
+//! In-memory [`Drive`] with canned files for tests without network access
+
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+
+use crate::drive::{
+    AliyunFile, ConditionalDownload, DateTime, Drive, FileType, FileVersion, SharedDriveEntry,
+    UserInfo, Validators,
+};
+use crate::file_cache::{CacheBackend, CacheCompression};
+use crate::inode_store::MemoryInodeStore;
+use crate::vfs::{AliyunDriveFileSystem, VfsConfig};
+
+const MOCK_URL_PREFIX: &str = "mock://";
+
+#[derive(Debug, Default)]
+struct MockState {
+    // file_id -> file
+    files: HashMap<String, AliyunFile>,
+    // file_id -> content
+    contents: HashMap<String, Bytes>,
+    next_id: u64,
+}
+
+/// Drive serving files added with [`MockDrive::add_file`] and [`MockDrive::add_folder`]
+#[derive(Debug, Clone, Default)]
+pub struct MockDrive {
+    state: Arc<Mutex<MockState>>,
+    downloads: Arc<AtomicUsize>,
+}
+
+impl MockDrive {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Add a file with `content` to the folder `parent_file_id`, returns its file id
+    pub fn add_file(&self, parent_file_id: &str, name: &str, content: &[u8]) -> String {
+        let mut state = self.state();
+        let file = new_file(&mut state, parent_file_id, name, FileType::File);
+        state
+            .contents
+            .insert(file.id.clone(), Bytes::copy_from_slice(content));
+        let file_id = file.id.clone();
+        state.files.insert(
+            file_id.clone(),
+            AliyunFile {
+                size: content.len() as u64,
+                ..file
+            },
+        );
+        file_id
+    }
+
+    /// Add an empty folder to the folder `parent_file_id`, returns its file id
+    pub fn add_folder(&self, parent_file_id: &str, name: &str) -> String {
+        let mut state = self.state();
+        let file = new_file(&mut state, parent_file_id, name, FileType::Folder);
+        let file_id = file.id.clone();
+        state.files.insert(file_id.clone(), file);
+        file_id
+    }
+
+    /// Content of a file as stored on the drive
+    pub fn content(&self, file_id: &str) -> Option<Bytes> {
+        self.state().contents.get(file_id).cloned()
+    }
+
+    /// Number of download requests served
+    pub fn downloads(&self) -> usize {
+        self.downloads.load(Ordering::SeqCst)
+    }
+
+    fn find(&self, parent_file_id: &str, name: &str) -> Option<AliyunFile> {
+        self.state()
+            .files
+            .values()
+            .find(|f| f.parent_file_id.as_deref() == Some(parent_file_id) && f.name == name)
+            .cloned()
+    }
+}
+
+fn new_file(
+    state: &mut MockState,
+    parent_file_id: &str,
+    name: &str,
+    r#type: FileType,
+) -> AliyunFile {
+    state.next_id += 1;
+    let mut file = AliyunFile::new_root();
+    file.id = format!("mock-{}", state.next_id);
+    file.name = name.to_string();
+    file.r#type = r#type;
+    file.parent_file_id = Some(parent_file_id.to_string());
+    file
+}
+
+impl Drive for MockDrive {
+    fn list_all(&self, parent_file_id: &str) -> Result<Vec<AliyunFile>> {
+        let mut files: Vec<_> = self
+            .state()
+            .files
+            .values()
+            .filter(|f| f.parent_file_id.as_deref() == Some(parent_file_id))
+            .cloned()
+            .collect();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+
+    fn list_all_in_drive(&self, _drive_id: &str, parent_file_id: &str) -> Result<Vec<AliyunFile>> {
+        self.list_all(parent_file_id)
+    }
+
+    fn list_all_trash(&self) -> Result<Vec<AliyunFile>> {
+        Ok(Vec::new())
+    }
+
+    fn list_shared_with_me(&self) -> Result<Vec<SharedDriveEntry>> {
+        Ok(Vec::new())
+    }
+
+    fn list_file_versions(&self, _file_id: &str) -> Result<Vec<FileVersion>> {
+        Ok(Vec::new())
+    }
+
+    fn get_quota(&self) -> Result<(u64, u64)> {
+        let used = self.state().contents.values().map(|c| c.len() as u64).sum();
+        Ok((used, 1 << 40))
+    }
+
+    fn get_user_info(&self) -> Result<UserInfo> {
+        bail!("user info is not available in the mock drive")
+    }
+
+    fn default_drive_id(&self) -> Option<&str> {
+        Some("mock")
+    }
+
+    fn is_revoked(&self) -> bool {
+        false
+    }
+
+    fn token_refresh_retries(&self) -> u64 {
+        0
+    }
+
+    fn nick_name(&self) -> Option<&str> {
+        None
+    }
+
+    fn get_file(&self, file_id: &str) -> Result<AliyunFile> {
+        let mut file = self
+            .state()
+            .files
+            .get(file_id)
+            .cloned()
+            .context("file not found")?;
+        file.fetched_at = Instant::now();
+        Ok(file)
+    }
+
+    fn get_file_in_drive(&self, _drive_id: &str, file_id: &str) -> Result<AliyunFile> {
+        self.get_file(file_id)
+    }
+
+    fn get_file_path(&self, file_id: &str) -> Result<String> {
+        let mut names = Vec::new();
+        let mut file = self.get_file(file_id)?;
+        while let Some(parent_file_id) = file.parent_file_id.clone() {
+            names.push(file.name);
+            if parent_file_id == "root" {
+                break;
+            }
+            file = self.get_file(&parent_file_id)?;
+        }
+        names.reverse();
+        Ok(format!("/{}", names.join("/")))
+    }
+
+    fn get_download_url(&self, file_id: &str) -> Result<String> {
+        Ok(format!("{}{}", MOCK_URL_PREFIX, file_id))
+    }
+
+    fn get_download_url_in_drive(&self, _drive_id: &str, file_id: &str) -> Result<String> {
+        self.get_download_url(file_id)
+    }
+
+    fn batch_get_download_url(&self, file_ids: &[&str]) -> Result<HashMap<String, String>> {
+        file_ids
+            .iter()
+            .map(|id| Ok((id.to_string(), self.get_download_url(id)?)))
+            .collect()
+    }
+
+    fn get_audio_play_url(&self, _file_id: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn download(&self, url: &str, start_pos: u64, size: usize) -> Result<Bytes> {
+        self.downloads.fetch_add(1, Ordering::SeqCst);
+        let file_id = url
+            .strip_prefix(MOCK_URL_PREFIX)
+            .context("invalid download url")?;
+        let content = self.content(file_id).context("file not found")?;
+        let start = std::cmp::min(start_pos as usize, content.len());
+        let end = std::cmp::min(start + size, content.len());
+        Ok(content.slice(start..end))
+    }
+
+    fn download_streaming(&self, url: &str, start_pos: u64, size: usize) -> Result<impl io::Read> {
+        Ok(io::Cursor::new(self.download(url, start_pos, size)?))
+    }
+
+    fn download_conditional(
+        &self,
+        url: &str,
+        start_pos: u64,
+        size: usize,
+        _validators: Option<&Validators>,
+    ) -> Result<ConditionalDownload> {
+        Ok(ConditionalDownload::Modified {
+            data: self.download(url, start_pos, size)?,
+            validators: Validators::default(),
+        })
+    }
+
+    fn upload_file(&self, parent_file_id: &str, name: &str, data: Vec<u8>) -> Result<String> {
+        let file_id = match self.find(parent_file_id, name) {
+            Some(file) => file.id,
+            None => self.add_file(parent_file_id, name, &[]),
+        };
+        let mut state = self.state();
+        let file = state.files.get_mut(&file_id).context("file not found")?;
+        file.size = data.len() as u64;
+        file.updated_at = DateTime::now();
+        state.contents.insert(file_id.clone(), data.into());
+        Ok(file_id)
+    }
+
+    fn move_file(
+        &self,
+        file_id: &str,
+        to_parent_file_id: &str,
+        new_name: Option<&str>,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let file = state.files.get_mut(file_id).context("file not found")?;
+        file.parent_file_id = Some(to_parent_file_id.to_string());
+        if let Some(new_name) = new_name {
+            file.name = new_name.to_string();
+        }
+        Ok(())
+    }
+
+    fn copy_file(
+        &self,
+        file_id: &str,
+        to_parent_file_id: &str,
+        new_name: &str,
+    ) -> Result<AliyunFile> {
+        let content = self.content(file_id).context("file not found")?;
+        let file_id = self.add_file(to_parent_file_id, new_name, &content);
+        self.get_file(&file_id)
+    }
+
+    fn trash_file(&self, file_id: &str) -> Result<()> {
+        let mut state = self.state();
+        state.files.remove(file_id).context("file not found")?;
+        state.contents.remove(file_id);
+        Ok(())
+    }
+
+    fn star_file(&self, file_id: &str) -> Result<()> {
+        let mut state = self.state();
+        state
+            .files
+            .get_mut(file_id)
+            .context("file not found")?
+            .starred = true;
+        Ok(())
+    }
+
+    fn unstar_file(&self, file_id: &str) -> Result<()> {
+        let mut state = self.state();
+        state
+            .files
+            .get_mut(file_id)
+            .context("file not found")?
+            .starred = false;
+        Ok(())
+    }
+
+    fn update_file_attrs(&self, file_id: &str, updated_at: &DateTime) -> Result<()> {
+        let mut state = self.state();
+        state
+            .files
+            .get_mut(file_id)
+            .context("file not found")?
+            .updated_at = updated_at.clone();
+        Ok(())
+    }
+
+    fn create_folder(&self, parent_file_id: &str, name: &str) -> Result<AliyunFile> {
+        let file_id = self.add_folder(parent_file_id, name);
+        self.get_file(&file_id)
+    }
+
+    fn create_file(&self, parent_file_id: &str, name: &str) -> Result<AliyunFile> {
+        let file_id = self.add_file(parent_file_id, name, &[]);
+        self.get_file(&file_id)
+    }
+}
+
+/// Filesystem configuration without background work, caches or a local overlay
+pub fn test_config() -> VfsConfig {
+    let temp_dir = std::env::temp_dir();
+    VfsConfig {
+        read_buffer_size: 4,
+        max_read_buffer_size: 16,
+        kernel_max_readahead: None,
+        kernel_max_write: None,
+        sequential_read_threshold: 2,
+        streaming_threshold: usize::MAX,
+        no_cache: false,
+        sparse_reads: false,
+        prefetch_window: 0,
+        max_staged_size: 1024,
+        only_category: None,
+        warmup_depth: 0,
+        max_path_depth: None,
+        preload_files: Vec::new(),
+        direct_io: false,
+        enable_write_xattr: false,
+        expose_thumbnails: false,
+        version_dir: None,
+        attr_cache_ttl: Duration::from_secs(60),
+        max_open_files: 1024,
+        open_file_watch_interval: Duration::ZERO,
+        cache_compression: CacheCompression::None,
+        cache_dir: None,
+        cache_dir_size_limit: 0,
+        cache_evict_interval: Duration::from_secs(60),
+        cache_backend: CacheBackend::Memory,
+        cache_backend_file: PathBuf::new(),
+        cache_backend_size: 0,
+        debug_dump_file: temp_dir.join("debug_dump.json"),
+        index_file: temp_dir.join("index.json"),
+        local_overlay: None,
+    }
+}
+
+/// Filesystem on top of `drive` with an in-memory inode store
+pub fn mock_fs(drive: &MockDrive) -> AliyunDriveFileSystem<MockDrive> {
+    AliyunDriveFileSystem::new(
+        drive.clone(),
+        Box::new(MemoryInodeStore::new()),
+        test_config(),
+    )
+    .expect("create filesystem")
+}
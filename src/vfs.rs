@@ -1,286 +1,2644 @@
 //! FUSE adaptor
 //!
 //! https://github.com/gz/btfs is used as a reference.
+use std::collections::{btree_map::Entry, HashMap, HashSet, VecDeque};
 use std::ffi::{OsStr, OsString};
-use std::path::Path;
-use std::time::UNIX_EPOCH;
-use std::{collections::BTreeMap, time::Duration};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs, thread};
 
 use bytes::Bytes;
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyOpen, Request, FUSE_ROOT_ID,
+    fuse_forget_one, FileAttr, FileType, Filesystem, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyOpen, ReplyWrite, ReplyXattr, Request,
+    TimeOrNow, FUSE_ROOT_ID,
 };
-use tracing::debug;
+use tracing::{debug, info, warn};
 
-use crate::drive::{AliyunDrive, AliyunFile};
-use crate::error::Error;
-use crate::file_cache::FileCache;
+use crate::chunk_cache::DiskChunkCache;
+use crate::disk_cache::DiskCache;
+use crate::drive::{AliyunDrive, AliyunFile, DateTime, Drive, FileVersion};
+use crate::error::{with_context, Error};
+use crate::file_cache::{CacheBackend, CacheCompression, FileCache};
+use crate::inode_store::{Inode, InodeStore};
+use crate::ioctl::{
+    ALIYUN_FILE_ID_MAX_LEN, ALIYUN_IOC_CLEAR_CACHE, ALIYUN_IOC_DEBUG_DUMP, ALIYUN_IOC_DUMP_INDEX,
+    ALIYUN_IOC_GET_FILE_ID, ALIYUN_IOC_REFRESH_DIR, ALIYUN_IOC_SYNC_OVERLAY, CONTROL_FILE_NAME,
+};
+use crate::stats::{Stats, VfsStats};
 
 const TTL: Duration = Duration::from_secs(1);
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(300);
 const BLOCK_SIZE: u64 = 4194304;
+/// Max number of freed inode numbers kept for reuse
+const MAX_FREE_INODES: usize = 10000;
+/// Virtual top-level directory containing files shared via share links
+const SHARED_DIR_NAME: &str = "__shared__";
+const SHARED_DIR_ID: &str = "__shared__";
+const CONTROL_FILE_ID: &str = "__control__";
+/// File id prefix of virtual thumbnail sidecar files, followed by the original file id
+const THUMBNAIL_ID_PREFIX: &str = "__thumbnail__:";
+/// File id prefix of virtual version history directories, followed by the original file id
+const VERSIONS_DIR_ID_PREFIX: &str = "__versions__:";
+/// File id prefix of files in version history directories,
+/// followed by the original file id and the version id separated by `/`
+const VERSION_ID_PREFIX: &str = "__version__:";
+/// File id prefix of files only in the local overlay,
+/// followed by the parent file id and the name separated by `/`
+const LOCAL_ID_PREFIX: &str = "__local__:";
+/// `renameat2(2)` flag: fail if the destination exists
+const RENAME_NOREPLACE: u32 = 1 << 0;
+/// `renameat2(2)` flag: atomically swap the source and destination
+const RENAME_EXCHANGE: u32 = 1 << 1;
+/// Thumbnails are downloaded in a single request of up to this size
+const MAX_THUMBNAIL_SIZE: usize = 10 * 1024 * 1024;
+/// Virtual top-level directory containing recycle bin contents
+const TRASH_DIR_NAME: &str = "__trash__";
+const TRASH_DIR_ID: &str = "__trash__";
+/// Extended attribute exposing the absolute path on the drive,
+/// for files in `__trash__` it's the original path
+const XATTR_PATH: &str = "user.aliyundrive.path";
+/// Extended attribute of the starred state, `1` or `0`
+const XATTR_STARRED: &str = "user.aliyundrive.starred";
+/// Writable with `--enable-write-xattr`, the modification time in RFC 3339 format
+/// and the file name, setting the name renames the file
+const XATTR_UPDATED_AT: &str = "user.aliyundrive.updated_at";
+const XATTR_NAME: &str = "user.aliyundrive.name";
+/// Extended attributes of video files, duration in seconds and dimensions in pixels
+const XATTR_DURATION: &str = "user.aliyundrive.duration";
+const XATTR_WIDTH: &str = "user.aliyundrive.width";
+const XATTR_HEIGHT: &str = "user.aliyundrive.height";
+/// Extended attributes of image files from their EXIF data
+const XATTR_IMAGE_WIDTH: &str = "user.aliyundrive.image_width";
+const XATTR_IMAGE_HEIGHT: &str = "user.aliyundrive.image_height";
+const XATTR_GPS_LAT: &str = "user.aliyundrive.gps_lat";
+const XATTR_GPS_LON: &str = "user.aliyundrive.gps_lon";
+const XATTR_CAMERA_MAKE: &str = "user.aliyundrive.camera_make";
+const XATTR_CAMERA_MODEL: &str = "user.aliyundrive.camera_model";
+const XATTR_CAPTURE_TIME: &str = "user.aliyundrive.capture_time";
+/// Extended attribute of audio files, a transcoded streaming URL fetched on every read
+const XATTR_AUDIO_URL: &str = "user.aliyundrive.audio_url";
+/// Extended attributes of the account on the root directory, fetched on every read
+const XATTR_USER_ID: &str = "user.aliyundrive.user_id";
+const XATTR_MEMBERSHIP: &str = "user.aliyundrive.membership";
+/// Max number of handles tried by `next_fh` before giving up
+const MAX_FH_ATTEMPTS: usize = 1000;
+/// Min time between warnings about too many open file handles
+const OPEN_FILES_WARN_INTERVAL: Duration = Duration::from_secs(60);
 
-#[derive(Debug, Clone)]
-pub struct Inode {
-    children: BTreeMap<OsString, u64>,
-    parent: u64,
+/// Set by the `SIGUSR2` handler, the dump is written on the next FUSE request
+/// since the filesystem state is only accessible from the session thread
+static DEBUG_DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_debug_dump(_signal: libc::c_int) {
+    DEBUG_DUMP_REQUESTED.store(true, Ordering::Relaxed);
 }
 
-impl Inode {
-    fn new(parent: u64) -> Self {
-        Self {
-            children: BTreeMap::new(),
-            parent,
+/// Match a `/` separated path against a glob, `*` and `?` don't match `/` while `**` does
+fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `**/` also matches no directory at all
+            if let [b'/', after @ ..] = rest {
+                if glob_match(after, path) {
+                    return true;
+                }
+            }
+            (0..=path.len()).any(|i| glob_match(rest, &path[i..]))
+        }
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+            (0..=segment).any(|i| glob_match(rest, &path[i..]))
         }
+        [b'?', rest @ ..] => matches!(path, [c, tail @ ..] if *c != b'/' && glob_match(rest, tail)),
+        [c, rest @ ..] => matches!(path, [p, tail @ ..] if p == c && glob_match(rest, tail)),
     }
+}
+
+/// Replace the file at `path` without following symlinks, the data is written to a
+/// new file next to it first, so a predictable path in a shared directory can't be
+/// used to overwrite another file
+fn write_file_replacing(path: &Path, data: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
 
-    fn add_child(&mut self, name: OsString, inode: u64) {
-        self.children.insert(name, inode);
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "refuse to write through a symlink",
+        ));
     }
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+    let res = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&tmp_path)
+        .and_then(|mut file| file.write_all(data))
+        .and_then(|()| fs::rename(&tmp_path, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+/// Recursively list directories of the drive up to `depth` levels below the root,
+/// returns the path and size of every file found sorted by path
+fn index_drive<D: Drive>(
+    drive: &D,
+    stats: &Stats,
+    depth: u32,
+    only_category: Option<&str>,
+) -> Vec<(String, u64)> {
+    let mut index = Vec::new();
+    let mut dirs = vec![("root".to_string(), String::new(), 1)];
+    while let Some((dir_id, dir_path, level)) = dirs.pop() {
+        if level > depth {
+            continue;
+        }
+        let files = drive.list_all(&dir_id);
+        stats.api_call(&files);
+        let files = match files {
+            Ok(files) => files,
+            Err(err) => {
+                warn!(file_id = %dir_id, path = %dir_path, error = %format_args!("{:#}", err), "index directory failed");
+                continue;
+            }
+        };
+        for file in files {
+            let path = format!("{}/{}", dir_path, file.name);
+            match file.r#type {
+                crate::drive::FileType::Folder => dirs.push((file.id, path, level + 1)),
+                crate::drive::FileType::File => {
+                    if only_category.is_none() || file.category.as_deref() == only_category {
+                        index.push((path, file.size));
+                    }
+                }
+            }
+        }
+    }
+    index.sort_unstable();
+    info!(depth = depth, files = index.len(), "file index built");
+    index
+}
+
+/// Build the file index of the drive and write it to `path` as JSON
+fn write_index<D: Drive>(
+    drive: &D,
+    stats: &Stats,
+    depth: u32,
+    only_category: Option<&str>,
+    path: &Path,
+) -> Result<(), Error> {
+    let index: Vec<_> = index_drive(drive, stats, depth, only_category)
+        .into_iter()
+        .map(|(path, size)| serde_json::json!({ "path": path, "size": size }))
+        .collect();
+    let res = serde_json::to_vec_pretty(&index)
+        .map_err(anyhow::Error::from)
+        .and_then(|data| Ok(write_file_replacing(path, &data)?));
+    with_context(res, || format!("write file index to {}", path.display()))?;
+    info!(path = %path.display(), "file index written");
+    Ok(())
+}
+
+/// Write a debug dump when `SIGUSR2` is received
+pub fn install_debug_dump_handler() {
+    unsafe {
+        libc::signal(
+            libc::SIGUSR2,
+            request_debug_dump as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VfsConfig {
+    /// Read/download buffer size in bytes
+    pub read_buffer_size: usize,
+    /// Max read/download buffer size in bytes for sequential reads
+    pub max_read_buffer_size: usize,
+    /// Max size in bytes of kernel readahead, kernel default if `None`
+    pub kernel_max_readahead: Option<u32>,
+    /// Max size in bytes of a single write request, kernel default if `None`
+    pub kernel_max_write: Option<u32>,
+    /// Number of contiguous reads before doubling the read buffer size
+    pub sequential_read_threshold: u32,
+    /// Chunks larger than this in bytes are downloaded with a streaming reader
+    pub streaming_threshold: usize,
+    /// Bypass the file cache, every read is downloaded from the drive
+    pub no_cache: bool,
+    /// Serve reads of known runs of zero bytes without downloading them
+    pub sparse_reads: bool,
+    /// Number of chunks downloaded ahead of sequential reads, zero to disable
+    pub prefetch_window: usize,
+    /// Max size in bytes of the staged data of a file opened for writing
+    pub max_staged_size: u64,
+    /// Only show files of this category
+    pub only_category: Option<String>,
+    /// Pre-fetch directory listings up to this depth on mount
+    pub warmup_depth: u32,
+    /// Directories this many levels below the root are shown empty, unlimited if `None`
+    pub max_path_depth: Option<u32>,
+    /// Globs of files downloaded into the disk cache on mount
+    pub preload_files: Vec<String>,
+    /// Bypass kernel page cache
+    pub direct_io: bool,
+    /// Allow updating the modification time and name of files with `setxattr`
+    pub enable_write_xattr: bool,
+    /// Add a `.<name>.thumb.jpg` sidecar file for images and videos with a thumbnail
+    pub expose_thumbnails: bool,
+    /// Add a `<name><suffix>` directory listing the historical versions of every file
+    pub version_dir: Option<String>,
+    /// Max age of cached file attributes before `getattr` fetches them again
+    pub attr_cache_ttl: Duration,
+    /// Warn when the number of open file and directory handles exceeds this
+    pub max_open_files: usize,
+    /// Interval of polling opened files for remote changes, zero to disable
+    pub open_file_watch_interval: Duration,
+    /// Compression of cached chunks in memory
+    pub cache_compression: CacheCompression,
+    /// Directory of the disk-backed chunk cache, disabled if `None`
+    pub cache_dir: Option<PathBuf>,
+    /// Max total size in bytes of the disk-backed chunk cache
+    pub cache_dir_size_limit: u64,
+    /// Interval of evicting chunks from the disk-backed chunk cache
+    pub cache_evict_interval: Duration,
+    /// Storage of the read buffers of opened files
+    pub cache_backend: CacheBackend,
+    /// Ring buffer file of the disk cache backend
+    pub cache_backend_file: PathBuf,
+    /// Size in bytes of the ring buffer file of the disk cache backend
+    pub cache_backend_size: u64,
+    /// File debug dumps are written to
+    pub debug_dump_file: PathBuf,
+    /// Output of the file index written by the `ALIYUN_IOC_DUMP_INDEX` ioctl
+    pub index_file: PathBuf,
+    /// Directory mirroring the drive layout, written files are saved here instead of
+    /// being uploaded and files here shadow the drive until synced
+    pub local_overlay: Option<PathBuf>,
+}
+
+/// Opened file in a version history directory
+#[derive(Debug)]
+struct OpenedVersion {
+    url: String,
+    size: u64,
+    /// Offset of `buffer` in the file
+    start_pos: u64,
+    buffer: Bytes,
+}
+
+/// Directory listings fetched by warmup
+#[derive(Debug, Default)]
+struct Warmup {
+    // directory file_id -> fetched at, listing
+    listings: HashMap<String, (Instant, Vec<AliyunFile>)>,
+    // all listings are fetched and their inodes aren't inserted yet
+    finished: bool,
 }
 
-pub struct AliyunDriveFileSystem {
-    drive: AliyunDrive,
-    file_cache: FileCache,
-    files: BTreeMap<u64, AliyunFile>,
-    inodes: BTreeMap<u64, Inode>,
+pub struct AliyunDriveFileSystem<D: Drive = AliyunDrive> {
+    drive: D,
+    file_cache: FileCache<D>,
+    inodes: Box<dyn InodeStore>,
     next_inode: u64,
+    // inode numbers freed by eviction and forgotten by the kernel, reused by `next_inode`
+    free_inodes: VecDeque<u64>,
+    // inode -> number of lookups the kernel hasn't forgotten yet
+    lookups: HashMap<u64, u64>,
     next_fh: u64,
+    // last warning about exceeding `max_open_files`
+    open_files_warned_at: Option<Instant>,
+    // inode -> generation, used to detect recycled inode numbers
+    generations: HashMap<u64, u64>,
+    inode_generation: u64,
+    config: VfsConfig,
+    stats: Arc<Stats>,
+    warmup: Arc<Mutex<Warmup>>,
+    // directory handle -> entries snapshotted at opendir
+    dir_handles: HashMap<u64, Vec<(u64, FileType, String)>>,
+    // file handle -> downloaded thumbnail of an opened sidecar file
+    thumbnails: HashMap<u64, Bytes>,
+    // file handle -> opened historical version of a file
+    versions: HashMap<u64, OpenedVersion>,
+    // file handle -> local overlay copy opened for reading
+    local_files: HashMap<u64, fs::File>,
+    // file handle opened for writing -> local overlay path its data is saved to
+    overlay_writes: HashMap<u64, PathBuf>,
+    // all inodes have been dropped because access to the drive was revoked
+    forgotten: bool,
 }
 
-impl AliyunDriveFileSystem {
-    pub fn new(drive: AliyunDrive, read_buffer_size: usize) -> Self {
-        let file_cache = FileCache::new(drive.clone(), read_buffer_size);
-        Self {
+impl<D: Drive> AliyunDriveFileSystem<D> {
+    pub fn new(drive: D, inodes: Box<dyn InodeStore>, config: VfsConfig) -> io::Result<Self> {
+        let stats = Arc::new(Stats::default());
+        let disk_cache = match &config.cache_dir {
+            Some(dir) => Some(DiskCache::new(
+                dir.clone(),
+                config.read_buffer_size,
+                config.cache_dir_size_limit,
+                config.cache_evict_interval,
+            )?),
+            None => None,
+        };
+        let file_cache = match config.cache_backend {
+            CacheBackend::Memory => FileCache::new(
+                drive.clone(),
+                stats.clone(),
+                config.read_buffer_size,
+                config.max_read_buffer_size,
+                config.sequential_read_threshold,
+                config.cache_compression,
+                disk_cache,
+            ),
+            CacheBackend::Disk => FileCache::new_with_disk_backend(
+                drive.clone(),
+                stats.clone(),
+                config.read_buffer_size,
+                config.max_read_buffer_size,
+                config.sequential_read_threshold,
+                config.cache_compression,
+                disk_cache,
+                DiskChunkCache::open(&config.cache_backend_file, config.cache_backend_size)?,
+            ),
+        }
+        .with_streaming_threshold(config.streaming_threshold)
+        .with_no_cache(config.no_cache)
+        .with_sparse_reads(config.sparse_reads)
+        .with_prefetch_window(config.prefetch_window)
+        .with_max_staged_size(config.max_staged_size);
+        Ok(Self {
             drive,
             file_cache,
-            files: BTreeMap::new(),
-            inodes: BTreeMap::new(),
+            inodes,
             next_inode: 1,
+            free_inodes: VecDeque::new(),
+            lookups: HashMap::new(),
             next_fh: 2,
+            open_files_warned_at: None,
+            generations: HashMap::new(),
+            inode_generation: 0,
+            config,
+            stats,
+            warmup: Arc::new(Mutex::new(Warmup::default())),
+            dir_handles: HashMap::new(),
+            thumbnails: HashMap::new(),
+            versions: HashMap::new(),
+            local_files: HashMap::new(),
+            overlay_writes: HashMap::new(),
+            forgotten: false,
+        })
+    }
+
+    /// Snapshot of the filesystem counters
+    pub fn dump_stats(&self) -> VfsStats {
+        VfsStats {
+            token_refresh_retries: self.drive.token_refresh_retries(),
+            ..self.stats.snapshot()
+        }
+    }
+
+    /// Internal state for support diagnostics, credentials are never included
+    pub fn debug_dump(&self) -> serde_json::Value {
+        let (inodes, files) = match self.inodes.count() {
+            Ok((inodes, files)) => (Some(inodes), Some(files)),
+            Err(_) => (None, None),
+        };
+        let quota = self.drive.get_quota();
+        self.stats.api_call(&quota);
+        let quota = quota.ok().map(|(used, total)| {
+            serde_json::json!({
+                "used_size": used,
+                "total_size": total,
+            })
+        });
+        let recent_api_calls: Vec<u64> = self
+            .stats
+            .recent_api_calls()
+            .iter()
+            .map(|t| {
+                t.duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default()
+            })
+            .collect();
+        serde_json::json!({
+            "inodes": inodes,
+            "files": files,
+            "open_files": self.file_cache.debug_handles(),
+            "drive_id": self.drive.default_drive_id(),
+            "nick_name": self.drive.nick_name(),
+            "quota": quota,
+            "recent_api_calls": recent_api_calls,
+        })
+    }
+
+    /// Write [`AliyunDriveFileSystem::debug_dump`] to the configured file
+    fn write_debug_dump(&self) -> Result<(), Error> {
+        let path = &self.config.debug_dump_file;
+        let res = serde_json::to_vec_pretty(&self.debug_dump())
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(write_file_replacing(path, &data)?));
+        with_context(res, || format!("write debug dump to {}", path.display()))?;
+        info!(path = %path.display(), "debug dump written");
+        Ok(())
+    }
+
+    /// Write the paths and sizes of all files up to `depth` levels below the root
+    /// as JSON, directories are listed from the drive without loading inodes
+    pub fn dump_index(&self, depth: u32, path: &Path) -> Result<(), Error> {
+        write_index(
+            &self.drive,
+            &self.stats,
+            self.index_depth(depth),
+            self.config.only_category.as_deref(),
+            path,
+        )
+    }
+
+    /// Like [`Self::dump_index`], but in background so that the mount stays responsive
+    fn spawn_dump_index(&self, depth: u32, path: PathBuf) {
+        let drive = self.drive.clone();
+        let stats = self.stats.clone();
+        let depth = self.index_depth(depth);
+        let only_category = self.config.only_category.clone();
+        thread::spawn(move || {
+            let _ = write_index(&drive, &stats, depth, only_category.as_deref(), &path);
+        });
+    }
+
+    /// Directories below `--max-path-depth` are shown empty and aren't indexed
+    fn index_depth(&self, depth: u32) -> u32 {
+        self.config
+            .max_path_depth
+            .map_or(depth, |max_depth| depth.min(max_depth))
+    }
+
+    /// Write a debug dump if one was requested by `SIGUSR2`
+    fn check_debug_dump(&self) {
+        if DEBUG_DUMP_REQUESTED.swap(false, Ordering::Relaxed) {
+            let _ = self.write_debug_dump();
+        }
+    }
+
+    /// Drop everything below the root once the drive revoked our access,
+    /// the mount then shows up empty instead of failing every operation
+    fn check_revoked(&mut self) {
+        let revoked = self.drive.is_revoked();
+        if revoked && !self.forgotten {
+            if let Err(err) = self.forget_all() {
+                warn!(error = %err, "forget all inodes failed");
+            }
         }
+        self.forgotten = revoked;
     }
 
-    /// Next inode number
+    /// Remove all inodes except a fresh root and files with pending uploads,
+    /// inode numbers are only reused once the kernel forgets them
+    fn forget_all(&mut self) -> Result<(), Error> {
+        warn!("access to the drive is revoked, forget all files");
+        for child in self.inodes.children_of(FUSE_ROOT_ID)?.into_values() {
+            self.remove_clean_tree(child)?;
+        }
+        self.inodes
+            .insert(FUSE_ROOT_ID, Inode::new(0), AliyunFile::new_root())?;
+        self.dir_handles.clear();
+        *self.warmup_state() = Warmup::default();
+        // fuser 0.11 has no notifier to invalidate the kernel caches,
+        // stale entries expire with the attribute TTL
+        Ok(())
+    }
+
+    /// Next inode number, freed inode numbers are reused first
     fn next_inode(&mut self) -> u64 {
-        self.next_inode = self.next_inode.wrapping_add(1);
-        self.next_inode
+        let ino = match self.free_inodes.pop_front() {
+            Some(ino) => ino,
+            None => {
+                self.next_inode = self.next_inode.wrapping_add(1);
+                self.next_inode
+            }
+        };
+        // a reused inode number gets a new generation
+        self.inode_generation += 1;
+        Stats::incr(&self.stats.inodes_loaded, 1);
+        self.generations.insert(ino, self.inode_generation);
+        ino
     }
 
-    /// Next file handler
-    fn next_fh(&mut self) -> u64 {
-        self.next_fh = self.next_fh.wrapping_add(1);
-        self.next_fh
+    /// The kernel holds one more reference to the inode, replied in an entry
+    fn remember(&mut self, ino: u64) {
+        *self.lookups.entry(ino).or_default() += 1;
     }
 
-    fn init(&mut self) -> Result<(), Error> {
+    /// The kernel dropped `nlookup` references to the inode, its number is reused
+    /// once it has no references left and it was removed
+    fn forget(&mut self, ino: u64, nlookup: u64) -> Result<(), Error> {
+        let Some(count) = self.lookups.get_mut(&ino) else {
+            return Ok(());
+        };
+        *count = count.saturating_sub(nlookup);
+        if *count > 0 {
+            return Ok(());
+        }
+        self.lookups.remove(&ino);
+        if ino != FUSE_ROOT_ID && self.inodes.get(ino)?.is_none() {
+            self.free_inode(ino);
+        }
+        Ok(())
+    }
+
+    /// Reuse the number of a removed inode nobody refers to anymore
+    fn free_inode(&mut self, ino: u64) {
+        if self.free_inodes.len() < MAX_FREE_INODES {
+            self.free_inodes.push_back(ino);
+        }
+    }
+
+    /// Generation of the inode number
+    fn generation(&self, ino: u64) -> u64 {
+        self.generations.get(&ino).copied().unwrap_or(0)
+    }
+
+    /// Allocate a file handle, skipping handles still in use after wrapping around
+    fn next_fh(&mut self) -> Result<u64, Error> {
+        let open_handles = self.file_cache.len()
+            + self.dir_handles.len()
+            + self.thumbnails.len()
+            + self.versions.len()
+            + self.local_files.len();
+        let warned_recently = self
+            .open_files_warned_at
+            .is_some_and(|at| at.elapsed() < OPEN_FILES_WARN_INTERVAL);
+        if open_handles >= self.config.max_open_files && !warned_recently {
+            self.open_files_warned_at = Some(Instant::now());
+            warn!(
+                open_handles = open_handles,
+                max_open_files = self.config.max_open_files,
+                "too many open file handles"
+            );
+        }
+        for _ in 0..MAX_FH_ATTEMPTS {
+            self.next_fh = self.next_fh.wrapping_add(1);
+            let fh = self.next_fh;
+            let in_use = self.file_cache.contains(fh)
+                || self.dir_handles.contains_key(&fh)
+                || self.thumbnails.contains_key(&fh)
+                || self.versions.contains_key(&fh)
+                || self.local_files.contains_key(&fh);
+            if !in_use {
+                return Ok(fh);
+            }
+        }
+        Err(Error::TooManyOpenFiles)
+    }
+
+    /// Insert the root inode, its size is the used quota of the drive
+    fn init_root(&mut self) -> Result<(), Error> {
         let mut root_file = AliyunFile::new_root();
-        let (used_size, _) = self.drive.get_quota().map_err(|_| Error::ApiCallFailed)?;
+        let quota = self.drive.get_quota();
+        self.stats.api_call(&quota);
+        let (used_size, _) = with_context(quota, || "get drive quota")?;
         root_file.size = used_size;
-        let root_inode = Inode::new(0);
-        self.inodes.insert(FUSE_ROOT_ID, root_inode);
-        self.files.insert(FUSE_ROOT_ID, root_file);
+        self.inodes.insert(FUSE_ROOT_ID, Inode::new(0), root_file)
+    }
+
+    fn init(&mut self) -> Result<(), Error> {
+        self.init_root()?;
+
+        self.warmup(self.config.warmup_depth);
+        self.preload(self.config.preload_files.clone());
+        self.file_cache
+            .start_watcher(self.config.open_file_watch_interval);
+
+        let stats = self.stats.clone();
+        let drive = self.drive.clone();
+        thread::spawn(move || loop {
+            thread::sleep(STATS_LOG_INTERVAL);
+            VfsStats {
+                token_refresh_retries: drive.token_refresh_retries(),
+                ..stats.snapshot()
+            }
+            .log_summary();
+        });
         Ok(())
     }
 
-    fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, Error> {
-        let mut parent_inode = self
-            .inodes
-            .get(&parent)
-            .ok_or(Error::ParentNotFound)?
-            .clone();
-        if parent_inode.children.is_empty() {
+    fn file(&self, ino: u64) -> Result<AliyunFile, Error> {
+        let (_, file) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+        Ok(file)
+    }
+
+    /// Attributes of an inode, a directory has a link from its parent, from itself and
+    /// from every subdirectory. Directories whose children aren't loaded keep a link
+    /// count of one so tools like `find` don't take them for leaves
+    fn file_attr(&self, ino: u64, file: &AliyunFile) -> Result<FileAttr, Error> {
+        let mut attr = file.to_file_attr(ino);
+        if !matches!(attr.kind, FileType::Directory) {
+            return Ok(attr);
+        }
+        let children = self.inodes.children_of(ino)?;
+        if children.is_empty() {
+            return Ok(attr);
+        }
+        let mut subdirs = 0;
+        for child in children.into_values() {
+            if let Some((_, child)) = self.inodes.get(child)? {
+                if matches!(child.r#type, crate::drive::FileType::Folder) {
+                    subdirs += 1;
+                }
+            }
+        }
+        attr.nlink = 2 + subdirs;
+        Ok(attr)
+    }
+
+    /// Return the file of an inode, fetching it again if its attributes are stale
+    fn refresh_file(&mut self, ino: u64) -> Result<AliyunFile, Error> {
+        let (inode, file) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+        if ino == FUSE_ROOT_ID
+            || inode.dirty
+            || !file.has_xattrs()
+            || file.fetched_at.elapsed() < self.config.attr_cache_ttl
+        {
+            return Ok(file);
+        }
+        let res = self.drive.get_file(&file.id);
+        self.stats.api_call(&res);
+        let mut fresh = with_context(res, || format!("get file {}", file.id))?;
+        debug!(inode = ino, name = %fresh.name, size = fresh.size, "refresh stale attributes");
+        // keep the virtual attributes set when it was listed
+        fresh.trashed_at = fresh.trashed_at.or(file.trashed_at);
+        self.inodes.insert(ino, inode, fresh.clone())?;
+        Ok(fresh)
+    }
+
+    fn getattr(&mut self, ino: u64) -> Result<(FileAttr, AliyunFile), Error> {
+        let file = self.refresh_file(ino)?;
+        let file = self.with_subtree_size(ino, file)?;
+        Ok((self.file_attr(ino, &file)?, file))
+    }
+
+    fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<(FileAttr, u64), Error> {
+        self.fill_warmed_inodes();
+        self.inodes.get(parent)?.ok_or(Error::ParentNotFound)?;
+        let mut children = self.inodes.children_of(parent)?;
+        if children.is_empty() {
             // Parent inode isn't loaded yet
             debug!(parent = parent, "readdir missing parent in lookup");
-            self.readdir(parent, 0)?;
-            parent_inode = self
-                .inodes
-                .get(&parent)
-                .ok_or(Error::ParentNotFound)?
-                .clone();
-        }
-        let inode = parent_inode
-            .children
-            .get(name)
-            .ok_or(Error::ChildNotFound)?;
-        let file = self.files.get(inode).ok_or(Error::NoEntry)?;
-        Ok(file.to_file_attr(*inode))
+            self.readdir(parent)?;
+            children = self.inodes.children_of(parent)?;
+        }
+        // files added to the local overlay show up without waiting for a refresh
+        if !children.contains_key(name)
+            && self
+                .overlay_path(parent)?
+                .is_some_and(|dir| dir.join(name).is_file())
+        {
+            debug!(parent = parent, name = %Path::new(name).display(), "readdir for local overlay file in lookup");
+            self.readdir(parent)?;
+            children = self.inodes.children_of(parent)?;
+        }
+        let inode = *children.get(name).ok_or(Error::ChildNotFound)?;
+        let file = self.file(inode)?;
+        Ok((self.file_attr(inode, &file)?, self.generation(inode)))
     }
 
-    fn readdir(&mut self, ino: u64, offset: i64) -> Result<Vec<(u64, FileType, String)>, Error> {
+    /// Refresh a directory from the drive and return all of its entries
+    fn readdir(&mut self, ino: u64) -> Result<Vec<(u64, FileType, String)>, Error> {
         let mut entries = Vec::new();
-        let mut inode = self.inodes.get(&ino).ok_or(Error::NoEntry)?.clone();
+        let (inode, file) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+        let mut children = self.inodes.children_of(ino)?;
 
-        if offset == 0 {
-            entries.push((ino, FileType::Directory, ".".to_string()));
-            entries.push((inode.parent, FileType::Directory, String::from("..")));
+        entries.push((ino, FileType::Directory, ".".to_string()));
+        entries.push((inode.parent, FileType::Directory, String::from("..")));
+        if let Some(max_depth) = self.config.max_path_depth {
+            let depth = self.depth(ino)?;
+            if depth >= max_depth {
+                debug!(
+                    inode = ino,
+                    depth = depth,
+                    "max path depth reached, skip listing"
+                );
+                return Ok(entries);
+            }
+        }
 
-            let file = self.files.get(&ino).ok_or(Error::NoEntry)?;
-            let parent_file_id = &file.id;
-            let files = self
-                .drive
-                .list_all(parent_file_id)
-                .map_err(|_| Error::ApiCallFailed)?;
-            debug!(
-                inode = ino,
-                "total {} files in directory {}",
-                files.len(),
-                file.name
-            );
+        let mut files = self.list_files(&file)?;
+        if let Some(dir) = self.overlay_path(ino)? {
+            merge_overlay(&dir, &file, &mut files);
+        }
+        if ino == FUSE_ROOT_ID {
+            for virtual_file in [
+                AliyunFile::new_shared_dir(),
+                AliyunFile::new_control_file(),
+                AliyunFile::new_trash_dir(),
+            ] {
+                // files on the drive take precedence over virtual entries
+                if files.iter().any(|f| f.name == virtual_file.name) {
+                    warn!(name = %virtual_file.name, "file on the drive hides a virtual entry");
+                    continue;
+                }
+                files.push(virtual_file);
+            }
+        }
+        if self.config.expose_thumbnails {
+            let thumbnails: Vec<_> = files.iter().filter_map(AliyunFile::new_thumbnail).collect();
+            files.extend(thumbnails);
+        }
+        if let Some(suffix) = self.config.version_dir.as_deref() {
+            let version_dirs: Vec<_> = files
+                .iter()
+                .filter_map(|f| AliyunFile::new_versions_dir(f, suffix))
+                .collect();
+            files.extend(version_dirs);
+        }
+        debug!(
+            inode = ino,
+            "total {} files in directory {}",
+            files.len(),
+            file.name
+        );
 
-            let mut to_remove = inode.children.keys().cloned().collect::<Vec<_>>();
-            for file in &files {
-                let name = OsString::from(file.name.clone());
-                if inode.children.contains_key(&name) {
+        let mut changed = false;
+        let mut to_remove = Vec::new();
+        for (name, child) in &children {
+            // Don't evict files with pending uploads
+            let dirty = self.inodes.get(*child)?.is_some_and(|(i, _)| i.dirty);
+            if !dirty {
+                to_remove.push(name.clone());
+            }
+        }
+        for file in &files {
+            match children.entry(OsString::from(file.name.clone())) {
+                Entry::Occupied(entry) => {
                     // file already exists
-                    to_remove.retain(|n| n != &name);
-                } else {
+                    to_remove.retain(|n| n != entry.key());
+                    let child = *entry.get();
+                    if let Some((inode, old)) = self.inodes.get(child)? {
+                        // keep local changes of files with pending uploads
+                        if !inode.dirty {
+                            changed |= old.size != file.size;
+                            let mut fresh = file.clone();
+                            fresh.trashed_at = fresh.trashed_at.or(old.trashed_at);
+                            self.inodes.insert(child, inode, fresh)?;
+                        }
+                    }
+                }
+                Entry::Vacant(entry) => {
                     let new_inode = self.next_inode();
-                    inode.add_child(name, new_inode);
-                    self.files.insert(new_inode, file.clone());
                     self.inodes
-                        .entry(new_inode)
-                        .or_insert_with(|| Inode::new(ino));
+                        .insert(new_inode, Inode::new(ino), file.clone())?;
+                    entry.insert(new_inode);
+                    changed = true;
+                }
+            }
+        }
+
+        if !to_remove.is_empty() {
+            changed = true;
+            for name in to_remove {
+                if let Some(ino_remove) = children.remove(&name) {
+                    debug!(inode = ino_remove, name = %Path::new(&name).display(), "remove outdated inode");
+                    self.remove_inode_tree(ino_remove)?;
+                }
+            }
+        }
+
+        if changed {
+            self.invalidate_subtree_size(ino)?;
+        }
+        let file_ids = files
+            .iter()
+            .filter(|f| matches!(f.r#type, crate::drive::FileType::File) && f.has_xattrs())
+            .map(|f| f.id.clone())
+            .collect();
+        self.file_cache.remember_listing(&file.id, file_ids);
+
+        // entries in listing order so that `--order-by` takes effect,
+        // followed by kept files with pending uploads
+        let mut listed = HashSet::new();
+        let ordered = files
+            .iter()
+            .filter_map(|file| children.get(OsStr::new(&file.name)).copied())
+            .chain(children.values().copied());
+        for child_ino in ordered {
+            if !listed.insert(child_ino) {
+                continue;
+            }
+            let (_, file) = self.inodes.get(child_ino)?.ok_or(Error::ChildNotFound)?;
+            entries.push((child_ino, file.r#type.into(), file.name));
+        }
+        Ok(entries)
+    }
+
+    /// Whether the inode or one of its ancestors is in the recycle bin
+    fn is_trashed(&self, mut ino: u64) -> Result<bool, Error> {
+        while ino != FUSE_ROOT_ID {
+            let (inode, file) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+            if file.trashed_at.is_some() || file.id == TRASH_DIR_ID {
+                return Ok(true);
+            }
+            ino = inode.parent;
+        }
+        Ok(false)
+    }
+
+    /// Number of levels of the inode below the root
+    fn depth(&self, mut ino: u64) -> Result<u32, Error> {
+        let mut depth = 0;
+        while ino != FUSE_ROOT_ID {
+            let (inode, _) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+            ino = inode.parent;
+            depth += 1;
+        }
+        Ok(depth)
+    }
+
+    /// Pre-fetch directory listings in background, up to `depth` levels from the root
+    pub fn warmup(&mut self, depth: u32) {
+        if depth == 0 {
+            return;
+        }
+        let drive = self.drive.clone();
+        let stats = self.stats.clone();
+        let warmup = self.warmup.clone();
+        thread::spawn(move || {
+            let mut dirs = vec![("root".to_string(), 1)];
+            while let Some((dir_id, level)) = dirs.pop() {
+                let files = drive.list_all(&dir_id);
+                stats.api_call(&files);
+                let files = match files {
+                    Ok(files) => files,
+                    Err(err) => {
+                        warn!(file_id = %dir_id, error = %format_args!("{:#}", err), "warmup list directory failed");
+                        continue;
+                    }
+                };
+                if level < depth {
+                    dirs.extend(
+                        files
+                            .iter()
+                            .filter(|f| matches!(f.r#type, crate::drive::FileType::Folder))
+                            .map(|f| (f.id.clone(), level + 1)),
+                    );
+                }
+                debug!(file_id = %dir_id, "warmup directory");
+                warmup
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .listings
+                    .insert(dir_id, (Instant::now(), files));
+            }
+            warmup
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .finished = true;
+            info!("warmup finished");
+        });
+    }
+
+    fn warmup_state(&self) -> std::sync::MutexGuard<'_, Warmup> {
+        self.warmup.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Listing of a directory fetched by warmup, unless it's older than the attribute TTL
+    fn take_warmed(&self, file_id: &str) -> Option<Vec<AliyunFile>> {
+        let (fetched_at, files) = self.warmup_state().listings.remove(file_id)?;
+        if fetched_at.elapsed() >= self.config.attr_cache_ttl {
+            debug!(file_id = %file_id, "warmed up directory listing expired");
+            return None;
+        }
+        Some(files)
+    }
+
+    /// Insert the inodes of all listings fetched by warmup once it has finished,
+    /// so that lookups don't list the directories again
+    fn fill_warmed_inodes(&mut self) {
+        if !std::mem::take(&mut self.warmup_state().finished) {
+            return;
+        }
+        let mut dirs = vec![FUSE_ROOT_ID];
+        while let Some(ino) = dirs.pop() {
+            let Ok(file) = self.file(ino) else {
+                continue;
+            };
+            if !self.warmup_state().listings.contains_key(&file.id) {
+                continue;
+            }
+            if let Err(err) = self.readdir(ino) {
+                warn!(inode = ino, error = %err, "insert warmed up directory failed");
+                continue;
+            }
+            if let Ok(children) = self.inodes.children_of(ino) {
+                dirs.extend(children.into_values());
+            }
+        }
+        // listings of directories that are no longer reachable
+        self.warmup_state().listings.clear();
+        debug!("inserted warmed up directories");
+    }
+
+    /// Find files matching `patterns` in background and download them into the disk cache
+    fn preload(&self, patterns: Vec<String>) {
+        if patterns.is_empty() {
+            return;
+        }
+        // only descend as deep as the patterns can match
+        let max_depth = if patterns.iter().any(|p| p.contains("**")) {
+            usize::MAX
+        } else {
+            patterns
+                .iter()
+                .map(|p| p.trim_matches('/').split('/').count())
+                .max()
+                .unwrap_or_default()
+        };
+        let (tx, rx) = mpsc::channel();
+        self.file_cache.spawn_preload(rx);
+        let drive = self.drive.clone();
+        let stats = self.stats.clone();
+        thread::spawn(move || {
+            let mut dirs = vec![("root".to_string(), String::new(), 1)];
+            while let Some((dir_id, dir_path, depth)) = dirs.pop() {
+                let files = drive.list_all(&dir_id);
+                stats.api_call(&files);
+                let files = match files {
+                    Ok(files) => files,
+                    Err(err) => {
+                        warn!(file_id = %dir_id, error = %format_args!("{:#}", err), "preload list directory failed");
+                        continue;
+                    }
+                };
+                for file in files {
+                    let path = if dir_path.is_empty() {
+                        file.name.clone()
+                    } else {
+                        format!("{}/{}", dir_path, file.name)
+                    };
+                    match file.r#type {
+                        crate::drive::FileType::Folder => {
+                            if depth < max_depth {
+                                dirs.push((file.id.clone(), path, depth + 1));
+                            }
+                        }
+                        crate::drive::FileType::File => {
+                            if patterns.iter().any(|p| {
+                                glob_match(p.trim_matches('/').as_bytes(), path.as_bytes())
+                            }) {
+                                debug!(file_id = %file.id, path = %path, "preload file");
+                                if tx.send(file).is_err() {
+                                    // preloading stopped
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Files shared with us, named by their share id,
+    /// shares whose file can't be fetched are skipped
+    fn list_shared(&self) -> anyhow::Result<Vec<AliyunFile>> {
+        let entries = self.drive.list_shared_with_me()?;
+        let mut files = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let res = self
+                .drive
+                .get_file_in_drive(&entry.drive_id, &entry.file_id);
+            self.stats.api_call(&res);
+            match res {
+                Ok(mut file) => {
+                    file.name = entry.share_id;
+                    file.drive_id = Some(entry.drive_id);
+                    file.parent_file_id = None;
+                    files.push(file);
+                }
+                Err(err) => {
+                    warn!(share_id = %entry.share_id, error = %format_args!("{:#}", err), "get shared file failed")
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn list_files(&self, file: &AliyunFile) -> Result<Vec<AliyunFile>, Error> {
+        let mut files = if let Some(files) = self.take_warmed(&file.id) {
+            debug!(file_id = %file.id, "use warmed up directory listing");
+            files
+        } else {
+            let files = if file.id == CONTROL_FILE_ID || self.forgotten {
+                return Ok(Vec::new());
+            } else if file.id == TRASH_DIR_ID {
+                self.drive.list_all_trash()
+            } else if let Some(file_id) = file.id.strip_prefix(VERSIONS_DIR_ID_PREFIX) {
+                self.drive.list_file_versions(file_id).map(|versions| {
+                    versions
+                        .into_iter()
+                        .map(|version| AliyunFile::new_version(file_id, version))
+                        .collect()
+                })
+            } else if file.id == SHARED_DIR_ID {
+                self.list_shared()
+            } else if let Some(drive_id) = file.drive_id.as_deref() {
+                self.drive.list_all_in_drive(drive_id, &file.id)
+            } else {
+                self.drive.list_all(&file.id)
+            };
+            self.stats.api_call(&files);
+            with_context(files, || {
+                format!("list directory {} ({})", file.name, file.id)
+            })?
+        };
+        if let Some(category) = self.config.only_category.as_deref() {
+            files.retain(|f| {
+                matches!(f.r#type, crate::drive::FileType::Folder)
+                    || f.category.as_deref() == Some(category)
+            });
+        }
+        Ok(files)
+    }
+
+    /// Download the thumbnail of a virtual sidecar file
+    fn open_thumbnail(&mut self, fh: u64, file: &AliyunFile) -> Result<(), Error> {
+        let url = file.thumbnail.as_deref().ok_or(Error::NoEntry)?;
+        // the size of thumbnails is unknown, request more than enough
+        let data = self.drive.download(url, 0, MAX_THUMBNAIL_SIZE);
+        let data = with_context(data, || format!("download thumbnail of {}", file.name))?;
+        Stats::incr(&self.stats.bytes_downloaded, data.len() as u64);
+        self.thumbnails.insert(fh, data);
+        Ok(())
+    }
+
+    /// Look up the download URL of a file in a version history directory
+    fn open_version(&mut self, fh: u64, file: &AliyunFile) -> Result<(), Error> {
+        let (file_id, version_id) = file
+            .id
+            .strip_prefix(VERSION_ID_PREFIX)
+            .and_then(|id| id.split_once('/'))
+            .ok_or(Error::NoEntry)?;
+        let versions = self.drive.list_file_versions(file_id);
+        self.stats.api_call(&versions);
+        let versions = with_context(versions, || format!("list versions of {}", file_id))?;
+        let url = versions
+            .into_iter()
+            .find(|version| version.version_id == version_id)
+            .and_then(|version| version.url)
+            .ok_or(Error::NoEntry)?;
+        self.versions.insert(
+            fh,
+            OpenedVersion {
+                url,
+                size: file.size,
+                start_pos: 0,
+                buffer: Bytes::new(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Read a historical version, the last downloaded chunk is kept for sequential reads
+    fn read_version(&mut self, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
+        let read_buffer_size = self.config.read_buffer_size;
+        let version = self.versions.get_mut(&fh).ok_or(Error::NoEntry)?;
+        let offset = offset as u64;
+        if offset >= version.size {
+            return Ok(Bytes::new());
+        }
+        let size = std::cmp::min(size as u64, version.size - offset) as usize;
+        let buffer_end = version.start_pos + version.buffer.len() as u64;
+        if offset < version.start_pos || offset + size as u64 > buffer_end {
+            let chunk_size =
+                std::cmp::min(size.max(read_buffer_size) as u64, version.size - offset);
+            let data = self
+                .drive
+                .download(&version.url, offset, chunk_size as usize);
+            let data = with_context(data, || format!("download version at offset {}", offset))?;
+            Stats::incr(&self.stats.bytes_downloaded, data.len() as u64);
+            version.start_pos = offset;
+            version.buffer = data;
+        }
+        let start = (offset - version.start_pos) as usize;
+        let end = std::cmp::min(start + size, version.buffer.len());
+        Ok(version.buffer.slice(start..end))
+    }
+
+    fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
+        let file = self.file(ino)?;
+        debug!(inode = ino, name = %file.name, fh = fh, offset = offset, size = size, "read");
+        Stats::incr(&self.stats.total_reads, 1);
+        if let Some(data) = self.thumbnails.get(&fh) {
+            let start = std::cmp::min(offset as usize, data.len());
+            let end = std::cmp::min(start + size as usize, data.len());
+            return Ok(data.slice(start..end));
+        }
+        if self.versions.contains_key(&fh) {
+            return self.read_version(fh, offset, size);
+        }
+        if let Some(local) = self.local_files.get(&fh) {
+            let mut data = vec![0; size as usize];
+            let mut read = 0;
+            while read < data.len() {
+                let res = local.read_at(&mut data[read..], offset as u64 + read as u64);
+                match with_context(res.map_err(anyhow::Error::from), || {
+                    format!("read local overlay copy of {}", file.name)
+                })? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+            data.truncate(read);
+            return Ok(data.into());
+        }
+        let file = match self.file_cache.apply_remote_change(fh) {
+            Some((size, updated_at)) => self.apply_remote_change(ino, size, updated_at)?,
+            None => file,
+        };
+        if offset >= file.size as i64 {
+            return Ok(Bytes::new());
+        }
+        let size = std::cmp::min(size, file.size.saturating_sub(offset as u64) as u32);
+        self.file_cache.read_exact(fh, offset, size)
+    }
+
+    /// Update the inode of an opened file the watcher found modified on the drive,
+    /// unless it has local changes
+    fn apply_remote_change(
+        &mut self,
+        ino: u64,
+        size: u64,
+        updated_at: SystemTime,
+    ) -> Result<AliyunFile, Error> {
+        let (inode, mut file) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+        if inode.dirty {
+            return Ok(file);
+        }
+        debug!(inode = ino, size = size, "apply remote change");
+        file.size = size;
+        file.updated_at = updated_at.into();
+        file.fetched_at = Instant::now();
+        let parent = inode.parent;
+        self.inodes.insert(ino, inode, file.clone())?;
+        self.invalidate_subtree_size(parent)?;
+        Ok(file)
+    }
+
+    /// Path of the inode in the local overlay, `None` without an overlay
+    /// or for virtual files, which are never shadowed
+    fn overlay_path(&self, mut ino: u64) -> Result<Option<PathBuf>, Error> {
+        let Some(overlay) = self.config.local_overlay.as_ref() else {
+            return Ok(None);
+        };
+        let mut names = Vec::new();
+        while ino != FUSE_ROOT_ID {
+            let (inode, file) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+            if !file.has_xattrs() && !file.is_local() {
+                return Ok(None);
+            }
+            names.push(file.name);
+            ino = inode.parent;
+        }
+        Ok(Some(
+            names
+                .iter()
+                .rev()
+                .fold(overlay.clone(), |path, name| path.join(name)),
+        ))
+    }
+
+    /// Open the local overlay copy of a file for reading, returns `false` if it has none
+    fn open_local(&mut self, fh: u64, ino: u64) -> Result<bool, Error> {
+        let Some(path) = self.overlay_path(ino)? else {
+            return Ok(false);
+        };
+        match fs::File::open(&path) {
+            Ok(local) => {
+                debug!(inode = ino, fh = fh, path = %path.display(), "open local overlay copy");
+                self.local_files.insert(fh, local);
+                Ok(true)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => with_context(Err(err.into()), || {
+                format!("open local overlay file {}", path.display())
+            }),
+        }
+    }
+
+    /// Save data written to the handle to the local overlay instead of the drive
+    fn redirect_writes(&mut self, fh: u64, ino: u64) -> Result<(), Error> {
+        if let Some(path) = self.overlay_path(ino)? {
+            self.overlay_writes.insert(fh, path);
+        }
+        Ok(())
+    }
+
+    /// Write the staged data of a handle with redirected writes to the local overlay
+    /// if it changed since it was last saved
+    fn save_local(&mut self, fh: u64) -> Result<(), Error> {
+        let Some(path) = self.overlay_writes.get(&fh) else {
+            return Ok(());
+        };
+        if let Some(data) = self.file_cache.take_staged(fh)? {
+            let size = data.len();
+            let res = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|()| fs::write(path, data));
+            with_context(res.map_err(anyhow::Error::from), || {
+                format!("write local overlay file {}", path.display())
+            })?;
+            debug!(fh = fh, path = %path.display(), size = size, "saved to local overlay");
+        }
+        Ok(())
+    }
+
+    /// Upload the files in the local overlay and remove them locally,
+    /// returns the number of files uploaded
+    pub fn sync_overlay(&mut self) -> Result<usize, Error> {
+        let overlay = self
+            .config
+            .local_overlay
+            .clone()
+            .ok_or(Error::NotSupported)?;
+        if self.inodes.get(FUSE_ROOT_ID)?.is_none() {
+            self.init_root()?;
+        }
+        let mut uploaded = 0;
+        let mut failed = 0;
+        let mut dirs = vec![(FUSE_ROOT_ID, overlay)];
+        while let Some((ino, dir)) = dirs.pop() {
+            // local only files get their inodes from the listing
+            self.readdir(ino)?;
+            let children = self.inodes.children_of(ino)?;
+            let entries =
+                fs::read_dir(&dir).and_then(|entries| entries.collect::<io::Result<Vec<_>>>());
+            let entries = with_context(entries.map_err(anyhow::Error::from), || {
+                format!("read local overlay directory {}", dir.display())
+            })?;
+            for entry in entries {
+                let path = entry.path();
+                let Some(&child) = children.get(&entry.file_name()) else {
+                    warn!(path = %path.display(), "no such folder on the drive, skip local overlay entry");
+                    continue;
+                };
+                if path.is_dir() {
+                    dirs.push((child, path));
+                } else if self.sync_local_file(child, &path).is_ok() {
+                    uploaded += 1;
+                } else {
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            warn!(
+                uploaded = uploaded,
+                failed = failed,
+                "local overlay partially synced"
+            );
+        } else {
+            info!(uploaded = uploaded, "local overlay synced");
+        }
+        Ok(uploaded)
+    }
+
+    fn sync_local_file(&mut self, ino: u64, path: &Path) -> Result<(), Error> {
+        let file = self.file(ino)?;
+        let parent_file_id = file.parent_file_id.clone().ok_or(Error::ParentNotFound)?;
+        let data = with_context(fs::read(path).map_err(anyhow::Error::from), || {
+            format!("read local overlay file {}", path.display())
+        })?;
+        let size = data.len() as u64;
+        debug!(inode = ino, path = %path.display(), size = size, "sync local overlay file");
+        let res = self.drive.upload_file(&parent_file_id, &file.name, data);
+        self.stats.api_call(&res);
+        let file_id = with_context(res, || {
+            format!("upload local overlay file {}", path.display())
+        })?;
+        Stats::incr(&self.stats.bytes_uploaded, size);
+        with_context(fs::remove_file(path).map_err(anyhow::Error::from), || {
+            format!("remove synced local overlay file {}", path.display())
+        })?;
+        self.mark_clean(ino, file_id);
+        Ok(())
+    }
+
+    /// Open a file handle for writing with the current content of the file staged,
+    /// with `O_TRUNC` an empty file is staged instead
+    fn open_writable(
+        &mut self,
+        fh: u64,
+        ino: u64,
+        file: &AliyunFile,
+        flags: i32,
+    ) -> Result<(), Error> {
+        self.redirect_writes(fh, ino)?;
+        self.file_cache.open(fh, file, true);
+        let res = if flags & libc::O_TRUNC != 0 {
+            self.truncate(ino, Some(fh), 0)
+        } else {
+            self.load_staged(fh, file)
+        };
+        if res.is_err() {
+            self.overlay_writes.remove(&fh);
+            let _ = self.file_cache.release(fh);
+        }
+        res
+    }
+
+    /// Stage the current content of a file opened for writing,
+    /// its local overlay copy takes precedence over the drive
+    fn load_staged(&mut self, fh: u64, file: &AliyunFile) -> Result<(), Error> {
+        if let Some(path) = self.overlay_writes.get(&fh) {
+            match fs::read(path) {
+                Ok(data) => {
+                    self.file_cache.set_staged(fh, data);
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    return with_context(Err(err.into()), || {
+                        format!("read local overlay file {}", path.display())
+                    })
+                }
+            }
+        }
+        // never uploaded and the local copy is gone
+        if file.is_local() {
+            return Err(Error::NoEntry);
+        }
+        self.file_cache.load_staged(fh)
+    }
+
+    /// Truncate or zero-extend the local overlay copy of a file,
+    /// the copy is made from the drive if there is none yet
+    fn truncate_local(&mut self, file: &AliyunFile, path: &Path, size: u64) -> Result<(), Error> {
+        let res = fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|local| local.set_len(size));
+        match res {
+            Ok(()) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound && !file.is_local() => {}
+            Err(err) => {
+                return with_context(Err(err.into()), || {
+                    format!("truncate local overlay file {}", path.display())
+                })
+            }
+        }
+        let mut data = self.download_prefix(file, std::cmp::min(size, file.size))?;
+        data.resize(size as usize, 0);
+        let res = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(path, data));
+        with_context(res.map_err(anyhow::Error::from), || {
+            format!("write local overlay file {}", path.display())
+        })
+    }
+
+    /// Move the local overlay copy of a file or folder along with a rename
+    fn rename_local(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        let res = to
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::rename(from, to));
+        match res {
+            Ok(()) => {
+                debug!(from = %from.display(), to = %to.display(), "renamed local overlay copy");
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => with_context(Err(err.into()), || {
+                format!(
+                    "rename local overlay copy {} to {}",
+                    from.display(),
+                    to.display()
+                )
+            }),
+        }
+    }
+
+    /// Save or upload the staged data of a file handle if it changed since
+    /// the last time
+    fn flush_file(&mut self, ino: u64, fh: u64) -> Result<(), Error> {
+        self.save_local(fh)?;
+        if let Some(file_id) = self.file_cache.flush(fh)? {
+            self.mark_clean(ino, file_id);
+        }
+        Ok(())
+    }
+
+    /// Staged data of the inode has been uploaded as `file_id`
+    fn mark_clean(&mut self, ino: u64, file_id: String) {
+        if let Ok(Some((mut inode, mut file))) = self.inodes.get(ino) {
+            inode.dirty = false;
+            file.id = file_id;
+            let _ = self.inodes.insert(ino, inode, file);
+        }
+    }
+
+    fn write(&mut self, ino: u64, fh: u64, offset: i64, data: &[u8]) -> Result<u32, Error> {
+        Stats::incr(&self.stats.total_writes, 1);
+        let written = self.file_cache.write(fh, offset, data)?;
+        if let Some((mut inode, mut file)) = self.inodes.get(ino)? {
+            inode.dirty = true;
+            if let Some(size) = self.file_cache.staged_size(fh) {
+                file.size = size;
+                file.updated_at = DateTime::now();
+            }
+            let parent = inode.parent;
+            self.inodes.insert(ino, inode, file)?;
+            self.invalidate_subtree_size(parent)?;
+        }
+        Ok(written)
+    }
+
+    fn getxattr(&mut self, ino: u64, name: &OsStr) -> Result<Vec<u8>, Error> {
+        if ino == FUSE_ROOT_ID && (name == XATTR_USER_ID || name == XATTR_MEMBERSHIP) {
+            let res = self.drive.get_user_info();
+            self.stats.api_call(&res);
+            let user = with_context(res, || "get user info")?;
+            let value = if name == XATTR_USER_ID {
+                user.user_id
+            } else {
+                user.membership_type
+            };
+            return Ok(value.into_bytes());
+        }
+        let file = self.file(ino)?;
+        if !file.has_xattrs() {
+            return Err(Error::NoAttribute);
+        }
+        if name == XATTR_PATH {
+            let path = self.drive.get_file_path(&file.id);
+            self.stats.api_call(&path);
+            let path = with_context(path, || format!("get path of file {}", file.id))?;
+            Ok(path.into_bytes())
+        } else if name == XATTR_AUDIO_URL && file.is_audio() {
+            let url = self.drive.get_audio_play_url(&file.id);
+            self.stats.api_call(&url);
+            let url = with_context(url, || format!("get audio play url of {}", file.id))?;
+            url.map(String::into_bytes).ok_or(Error::NoAttribute)
+        } else if name == XATTR_STARRED {
+            Ok(if file.starred {
+                b"1".to_vec()
+            } else {
+                b"0".to_vec()
+            })
+        } else {
+            file.media_xattrs()
+                .into_iter()
+                .find(|(attr, _)| name == *attr)
+                .map(|(_, value)| value.into_bytes())
+                .ok_or(Error::NoAttribute)
+        }
+    }
+
+    fn setxattr(&mut self, ino: u64, name: &OsStr, value: &[u8]) -> Result<(), Error> {
+        let (inode, mut file) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+        if !file.has_xattrs() {
+            return Err(Error::NotSupported);
+        }
+        if name == XATTR_STARRED {
+            let starred = match value {
+                b"1" | b"true" => true,
+                b"0" | b"false" => false,
+                _ => return Err(Error::InvalidArgument),
+            };
+            let res = if starred {
+                self.drive.star_file(&file.id)
+            } else {
+                self.drive.unstar_file(&file.id)
+            };
+            self.stats.api_call(&res);
+            with_context(res, || format!("update starred of file {}", file.id))?;
+            file.starred = starred;
+            self.inodes.insert(ino, inode, file)
+        } else if self.config.enable_write_xattr && name == XATTR_UPDATED_AT {
+            let updated_at: DateTime = std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .ok_or(Error::InvalidArgument)?;
+            let res = self.drive.update_file_attrs(&file.id, &updated_at);
+            self.stats.api_call(&res);
+            with_context(res, || {
+                format!("update modification time of file {}", file.id)
+            })?;
+            file.updated_at = updated_at;
+            self.inodes.insert(ino, inode, file)
+        } else if self.config.enable_write_xattr && name == XATTR_NAME {
+            let new_name = std::str::from_utf8(value).map_err(|_| Error::InvalidArgument)?;
+            if new_name.is_empty() || new_name.contains('/') || new_name == "." || new_name == ".."
+            {
+                return Err(Error::InvalidArgument);
+            }
+            let old_name = OsString::from(&file.name);
+            self.rename(
+                inode.parent,
+                &old_name,
+                inode.parent,
+                OsStr::new(new_name),
+                0,
+            )
+        } else {
+            Err(Error::NotSupported)
+        }
+    }
+
+    fn rename(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), Error> {
+        if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0 {
+            return Err(Error::InvalidArgument);
+        }
+        // the drive API can't swap two files atomically
+        if flags & RENAME_EXCHANGE != 0 {
+            return Err(Error::NotSupported);
+        }
+        let ino = *self
+            .inodes
+            .children_of(parent)?
+            .get(name)
+            .ok_or(Error::ChildNotFound)?;
+        if flags & RENAME_NOREPLACE != 0 {
+            if let Some(&existing) = self.inodes.children_of(newparent)?.get(newname) {
+                if existing != ino {
+                    return Err(Error::AlreadyExists);
+                }
+            }
+        }
+        let (mut inode, mut file) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+        let new_parent_file = self.file(newparent)?;
+        let is_virtual = |file: &AliyunFile| !file.has_xattrs() && !file.is_local();
+        // virtual entries have no file on the drive to move
+        if is_virtual(&file) || is_virtual(&new_parent_file) {
+            return Err(Error::PermissionDenied);
+        }
+        let existing = match self.inodes.children_of(newparent)?.get(newname) {
+            Some(&existing) if existing != ino => Some((existing, self.file(existing)?)),
+            _ => None,
+        };
+        if let Some((_, existing)) = &existing {
+            if is_virtual(existing) {
+                return Err(Error::PermissionDenied);
+            }
+            // never trash a directory with its contents
+            if matches!(existing.r#type, crate::drive::FileType::Folder) {
+                return Err(Error::AlreadyExists);
+            }
+        }
+        let new_name = newname.to_string_lossy().into_owned();
+        let local_paths = self
+            .overlay_path(ino)?
+            .zip(self.overlay_path(newparent)?)
+            .map(|(from, dir)| (from, dir.join(&new_name)));
+        if file.is_local() {
+            // only exists in the local overlay, there is nothing to move on the drive
+            let (from, to) = local_paths.as_ref().ok_or(Error::NoEntry)?;
+            self.rename_local(from, to)?;
+            file = AliyunFile::new_local(
+                &new_parent_file,
+                new_name.clone(),
+                file.size,
+                file.updated_at,
+            );
+        } else {
+            match existing.as_ref().map(|(_, existing)| existing) {
+                Some(existing) if existing.has_xattrs() => {
+                    self.replace_file(&file, &new_parent_file.id, existing)?
+                }
+                _ => self.move_file(&file, &new_parent_file.id, &new_name)?,
+            }
+            if let Some((from, to)) = local_paths.as_ref() {
+                self.rename_local(from, to)?;
+            }
+        }
+
+        // the destination has been replaced
+        if let Some((existing, _)) = existing {
+            self.remove_inode_tree(existing)?;
+        }
+        inode.parent = newparent;
+        file.name = new_name;
+        file.parent_file_id = Some(new_parent_file.id);
+        if matches!(file.r#type, crate::drive::FileType::Folder) {
+            self.rename_directory(ino, inode, file)?;
+        } else {
+            self.inodes.insert(ino, inode, file)?;
+        }
+        self.invalidate_subtree_size(parent)?;
+        self.invalidate_subtree_size(newparent)
+    }
+
+    fn mkdir(&mut self, parent: u64, name: &OsStr) -> Result<(FileAttr, u64), Error> {
+        let parent_file = self.file(parent)?;
+        if !parent_file.has_xattrs() || self.is_trashed(parent)? {
+            return Err(Error::PermissionDenied);
+        }
+        if self.inodes.children_of(parent)?.contains_key(name) {
+            return Err(Error::AlreadyExists);
+        }
+        let name = name.to_string_lossy();
+        let res = self.drive.create_folder(&parent_file.id, &name);
+        self.stats.api_call(&res);
+        let folder = with_context(res, || {
+            format!("create folder {} in {}", name, parent_file.id)
+        })?;
+        let ino = self.next_inode();
+        self.inodes
+            .insert(ino, Inode::new(parent), folder.clone())?;
+        Ok((folder.to_file_attr(ino), self.generation(ino)))
+    }
+
+    /// Create a file and open it for writing, with `O_EXCL` it fails if the name exists
+    /// locally or on the drive
+    fn create(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        flags: i32,
+    ) -> Result<(FileAttr, u64, u64), Error> {
+        let parent_file = self.file(parent)?;
+        if !parent_file.has_xattrs() || self.is_trashed(parent)? {
+            return Err(Error::PermissionDenied);
+        }
+        let exclusive = flags & libc::O_EXCL != 0;
+        if exclusive {
+            // the kernel only checked its own dentry cache, which may be stale
+            self.readdir(parent)?;
+        }
+        if let Some(&ino) = self.inodes.children_of(parent)?.get(name) {
+            if exclusive {
+                return Err(Error::AlreadyExists);
+            }
+            let file = self.file(ino)?;
+            if !matches!(file.r#type, crate::drive::FileType::File) {
+                return Err(Error::InvalidArgument);
+            }
+            let fh = self.next_fh()?;
+            self.open_writable(fh, ino, &file, flags)?;
+            // `O_TRUNC` changed the size
+            let file = self.file(ino)?;
+            return Ok((file.to_file_attr(ino), self.generation(ino), fh));
+        }
+        let name = name.to_string_lossy();
+        let local_path = self.overlay_path(parent)?.map(|dir| dir.join(&*name));
+        let file = if let Some(path) = local_path.as_ref() {
+            let res = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|()| {
+                    fs::OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(path)
+                });
+            with_context(res.map_err(anyhow::Error::from), || {
+                format!("create local overlay file {}", path.display())
+            })?;
+            AliyunFile::new_local(&parent_file, name.into_owned(), 0, DateTime::now())
+        } else {
+            let res = self.drive.create_file(&parent_file.id, &name);
+            self.stats.api_call(&res);
+            with_context(res, || {
+                format!("create file {} in {}", name, parent_file.id)
+            })?
+        };
+        let fh = self.next_fh()?;
+        let ino = self.next_inode();
+        self.inodes.insert(ino, Inode::new(parent), file.clone())?;
+        self.invalidate_subtree_size(parent)?;
+        self.file_cache.open(fh, &file, true);
+        if let Some(path) = local_path {
+            self.overlay_writes.insert(fh, path);
+        }
+        Ok((file.to_file_attr(ino), self.generation(ino), fh))
+    }
+
+    /// Change the size or modification time of a file, ownership and permissions are fixed
+    #[allow(clippy::too_many_arguments)]
+    fn setattr(
+        &mut self,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        mtime: Option<TimeOrNow>,
+        fh: Option<u64>,
+    ) -> Result<AliyunFile, Error> {
+        if mode.is_some() || uid.is_some() || gid.is_some() {
+            return Err(Error::PermissionDenied);
+        }
+        if let Some(size) = size {
+            self.truncate(ino, fh, size)?;
+        }
+        let (inode, mut file) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+        if let Some(mtime) = mtime {
+            file.updated_at = match mtime {
+                TimeOrNow::SpecificTime(time) => DateTime::from(time),
+                TimeOrNow::Now => DateTime::now(),
+            };
+            // virtual and local only files just keep it until the inode is refreshed
+            if file.has_xattrs() {
+                let res = self.drive.update_file_attrs(&file.id, &file.updated_at);
+                self.stats.api_call(&res);
+                with_context(res, || format!("update modification time of {}", file.id))?;
+            }
+            self.inodes.insert(ino, inode, file.clone())?;
+        }
+        Ok(file)
+    }
+
+    /// Truncate or zero-extend a file, files not opened for writing
+    /// are downloaded and uploaded again
+    fn truncate(&mut self, ino: u64, fh: Option<u64>, size: u64) -> Result<(), Error> {
+        let (mut inode, mut file) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+        if !matches!(file.r#type, crate::drive::FileType::File) {
+            return Err(Error::InvalidArgument);
+        }
+        if (!file.has_xattrs() && !file.is_local()) || self.is_trashed(ino)? {
+            return Err(Error::PermissionDenied);
+        }
+        debug!(inode = ino, name = %file.name, size = size, "truncate");
+        let truncated = match fh {
+            Some(fh) => self.file_cache.truncate(fh, size)?,
+            None => false,
+        };
+        if truncated {
+            inode.dirty = true;
+        } else if let Some(path) = self.overlay_path(ino)? {
+            self.truncate_local(&file, &path, size)?;
+            inode.dirty = true;
+        } else if size != file.size {
+            // the whole file goes through memory like staged data
+            if size > self.config.max_staged_size {
+                return Err(Error::FileTooLarge);
+            }
+            let mut data = self.download_prefix(&file, std::cmp::min(size, file.size))?;
+            data.resize(size as usize, 0);
+            let parent_file_id = file.parent_file_id.clone().ok_or(Error::ParentNotFound)?;
+            let res = self.drive.upload_file(&parent_file_id, &file.name, data);
+            self.stats.api_call(&res);
+            file.id = with_context(res, || format!("upload truncated file {}", file.name))?;
+            Stats::incr(&self.stats.bytes_uploaded, size);
+        }
+        file.size = size;
+        file.updated_at = DateTime::now();
+        let parent = inode.parent;
+        self.inodes.insert(ino, inode, file)?;
+        self.invalidate_subtree_size(parent)
+    }
+
+    /// Download the first `size` bytes of a file in chunks of the max read buffer size
+    fn download_prefix(&self, file: &AliyunFile, size: u64) -> Result<Vec<u8>, Error> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let url = self.drive.get_download_url(&file.id);
+        self.stats.api_call(&url);
+        let url = with_context(url, || format!("get download url of {}", file.id))?;
+        let chunk_size = self.config.max_read_buffer_size.max(1) as u64;
+        let mut data = Vec::with_capacity(size as usize);
+        while (data.len() as u64) < size {
+            let offset = data.len() as u64;
+            let len = std::cmp::min(chunk_size, size - offset) as usize;
+            let chunk = self.drive.download(&url, offset, len);
+            let chunk = with_context(chunk, || {
+                format!("download {} at offset {}", file.id, offset)
+            })?;
+            Stats::incr(&self.stats.bytes_downloaded, chunk.len() as u64);
+            if chunk.is_empty() {
+                // shorter than expected, the rest is zero-filled
+                break;
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    /// Server-side copy of a whole file, the destination is moved to the recycle bin
+    /// once the copy succeeded
+    fn copy_file_range(
+        &mut self,
+        ino_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+    ) -> Result<u32, Error> {
+        let src = self.file(ino_in)?;
+        let (inode, dest) = self.inodes.get(ino_out)?.ok_or(Error::NoEntry)?;
+        let is_file = |file: &AliyunFile| matches!(file.r#type, crate::drive::FileType::File);
+        if !is_file(&src) || !is_file(&dest) {
+            return Err(Error::InvalidArgument);
+        }
+        if !src.has_xattrs() || !dest.has_xattrs() || src.size > u64::from(u32::MAX) {
+            return Err(Error::NotSupported);
+        }
+        // the whole file has been copied by a previous call
+        if offset_in as u64 >= src.size && offset_out as u64 >= src.size {
+            return Ok(0);
+        }
+        // Aliyun drive can only copy whole files
+        if offset_in != 0 || offset_out != 0 || len < src.size {
+            return Err(Error::InvalidArgument);
+        }
+        let parent_file_id = dest.parent_file_id.clone().ok_or(Error::ParentNotFound)?;
+        // copy under a temporary name first, the destination is kept if the copy fails
+        let tmp_name = format!(".{}.{}.copy", dest.name, src.id);
+        let res = self.drive.copy_file(&src.id, &parent_file_id, &tmp_name);
+        self.stats.api_call(&res);
+        let mut copied = with_context(res, || format!("copy file {} to {}", src.id, tmp_name))?;
+        let res = self.drive.trash_file(&dest.id);
+        self.stats.api_call(&res);
+        if let Err(e) = with_context(res, || format!("trash file {}", dest.id)) {
+            let res = self.drive.trash_file(&copied.id);
+            self.stats.api_call(&res);
+            return Err(e);
+        }
+        let res = self
+            .drive
+            .move_file(&copied.id, &parent_file_id, Some(&dest.name));
+        self.stats.api_call(&res);
+        with_context(res, || {
+            format!("rename copied file {} to {}", copied.id, dest.name)
+        })?;
+        copied.name = dest.name.clone();
+        let size = copied.size;
+        self.file_cache.replace(fh_out, &copied);
+        self.inodes
+            .insert(ino_out, Inode::new(inode.parent), copied)?;
+        self.invalidate_subtree_size(inode.parent)?;
+        Ok(size as u32)
+    }
+
+    /// Remove an inode and all of its loaded descendants
+    fn remove_inode_tree(&mut self, ino: u64) -> Result<(), Error> {
+        let mut to_remove = vec![ino];
+        while let Some(ino) = to_remove.pop() {
+            to_remove.extend(self.inodes.children_of(ino)?.into_values());
+            self.inodes.remove(ino)?;
+            self.generations.remove(&ino);
+            // the kernel may still refer to it, reused once it's forgotten
+            if !self.lookups.contains_key(&ino) {
+                self.free_inode(ino);
+            }
+            Stats::incr(&self.stats.inodes_evicted, 1);
+        }
+        Ok(())
+    }
+
+    /// Remove an inode and its loaded descendants except files with pending uploads
+    /// and their ancestors, returns whether anything was kept
+    fn remove_clean_tree(&mut self, ino: u64) -> Result<bool, Error> {
+        let mut kept = false;
+        for child in self.inodes.children_of(ino)?.into_values() {
+            kept |= self.remove_clean_tree(child)?;
+        }
+        let dirty = self.inodes.get(ino)?.is_some_and(|(inode, _)| inode.dirty);
+        if kept || dirty {
+            return Ok(true);
+        }
+        self.remove_inode_tree(ino)?;
+        Ok(false)
+    }
+
+    /// Report the total size of the loaded descendants as the size of a directory,
+    /// the root keeps reporting the used quota
+    fn with_subtree_size(&mut self, ino: u64, mut file: AliyunFile) -> Result<AliyunFile, Error> {
+        if ino != FUSE_ROOT_ID && matches!(file.r#type, crate::drive::FileType::Folder) {
+            file.size = self.get_subtree_size(ino)?;
+        }
+        Ok(file)
+    }
+
+    /// Sum the sizes of all loaded descendants of a directory, the result is
+    /// cached on the inodes of the directory and its subdirectories
+    fn get_subtree_size(&mut self, ino: u64) -> Result<u64, Error> {
+        let (mut inode, file) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+        if let Some(size) = inode.cached_size {
+            return Ok(size);
+        }
+        let mut size = 0;
+        for child in self.inodes.children_of(ino)?.into_values() {
+            let child_file = self.file(child)?;
+            size += match child_file.r#type {
+                crate::drive::FileType::Folder => self.get_subtree_size(child)?,
+                crate::drive::FileType::File => child_file.size,
+            };
+        }
+        inode.cached_size = Some(size);
+        self.inodes.insert(ino, inode, file)?;
+        Ok(size)
+    }
+
+    /// Drop the cached subtree size of a directory and all of its ancestors
+    fn invalidate_subtree_size(&mut self, mut ino: u64) -> Result<(), Error> {
+        while let Some((mut inode, file)) = self.inodes.get(ino)? {
+            let parent = inode.parent;
+            if inode.cached_size.take().is_some() {
+                self.inodes.insert(ino, inode, file)?;
+            }
+            if ino == FUSE_ROOT_ID {
+                break;
+            }
+            ino = parent;
+        }
+        Ok(())
+    }
+
+    fn move_file(&self, file: &AliyunFile, parent_file_id: &str, name: &str) -> Result<(), Error> {
+        let res = self.drive.move_file(&file.id, parent_file_id, Some(name));
+        self.stats.api_call(&res);
+        with_context(res, || {
+            format!("move file {} to {} as {}", file.id, parent_file_id, name)
+        })
+    }
+
+    /// Move a file over an existing file on the drive, which doesn't overwrite on move.
+    /// The file is moved under a temporary name first, the existing file is only
+    /// moved to the recycle bin once that succeeded
+    fn replace_file(
+        &self,
+        file: &AliyunFile,
+        parent_file_id: &str,
+        existing: &AliyunFile,
+    ) -> Result<(), Error> {
+        let tmp_name = format!(".{}.{}.rename", existing.name, file.id);
+        self.move_file(file, parent_file_id, &tmp_name)?;
+        let res = self.drive.trash_file(&existing.id);
+        self.stats.api_call(&res);
+        if let Err(e) = with_context(res, || format!("trash file {}", existing.id)) {
+            // put the file back where it was
+            if let Some(parent_file_id) = file.parent_file_id.as_deref() {
+                let _ = self.move_file(file, parent_file_id, &file.name);
+            }
+            return Err(e);
+        }
+        self.move_file(file, parent_file_id, &existing.name)
+    }
+
+    /// Update the local inode tree after a directory is moved server-side
+    ///
+    /// Re-inserting the directory re-keys it under its new parent. Loaded descendants
+    /// are filed under the directory's inode number which doesn't change, so `..`
+    /// of its children keeps pointing to the moved directory.
+    fn rename_directory(&mut self, ino: u64, inode: Inode, file: AliyunFile) -> Result<(), Error> {
+        debug!(inode = ino, parent = inode.parent, name = %file.name, "rename directory");
+        self.inodes.insert(ino, inode, file)
+    }
+
+    fn ioctl(&mut self, uid: u32, ino: u64, cmd: u32, in_data: &[u8]) -> Result<Vec<u8>, Error> {
+        if uid != 0 && uid != unsafe { libc::getuid() } {
+            return Err(Error::PermissionDenied);
+        }
+        let (inode, file) = self.inodes.get(ino)?.ok_or(Error::NoEntry)?;
+        match cmd {
+            ALIYUN_IOC_REFRESH_DIR => {
+                let dir_ino = if file.id == CONTROL_FILE_ID {
+                    FUSE_ROOT_ID
+                } else if matches!(file.r#type, crate::drive::FileType::Folder) {
+                    ino
+                } else {
+                    inode.parent
+                };
+                debug!(inode = dir_ino, "ioctl refresh dir");
+                self.readdir(dir_ino)?;
+                Ok(Vec::new())
+            }
+            ALIYUN_IOC_CLEAR_CACHE => {
+                debug!("ioctl clear cache");
+                self.file_cache.clear();
+                Ok(Vec::new())
+            }
+            ALIYUN_IOC_DEBUG_DUMP => {
+                debug!("ioctl debug dump");
+                self.write_debug_dump()?;
+                Ok(Vec::new())
+            }
+            ALIYUN_IOC_DUMP_INDEX => {
+                let depth = in_data
+                    .get(..4)
+                    .and_then(|depth| depth.try_into().ok())
+                    .map(u32::from_ne_bytes)
+                    .ok_or(Error::InvalidArgument)?;
+                debug!(depth = depth, "ioctl dump index");
+                self.spawn_dump_index(depth, self.config.index_file.clone());
+                Ok(Vec::new())
+            }
+            ALIYUN_IOC_SYNC_OVERLAY => {
+                debug!("ioctl sync local overlay");
+                self.sync_overlay()?;
+                Ok(Vec::new())
+            }
+            ALIYUN_IOC_GET_FILE_ID => {
+                let mut data = file.id.into_bytes();
+                data.resize(ALIYUN_FILE_ID_MAX_LEN as usize, 0);
+                Ok(data)
+            }
+            _ => Err(Error::InvalidIoctl),
+        }
+    }
+}
+
+impl<D: Drive> Filesystem for AliyunDriveFileSystem<D> {
+    fn init(
+        &mut self,
+        _req: &Request<'_>,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        if let Some(max_readahead) = self.config.kernel_max_readahead {
+            if let Err(nearest) = config.set_max_readahead(max_readahead) {
+                warn!(
+                    max_readahead = max_readahead,
+                    nearest = nearest,
+                    "unsupported max readahead, use the nearest value"
+                );
+                let _ = config.set_max_readahead(nearest);
+            }
+        }
+        if let Some(max_write) = self.config.kernel_max_write {
+            if let Err(nearest) = config.set_max_write(max_write) {
+                warn!(
+                    max_write = max_write,
+                    nearest = nearest,
+                    "unsupported max write, use the nearest value"
+                );
+                let _ = config.set_max_write(nearest);
+            }
+        }
+        if let Err(e) = self.init() {
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        info!("unmounting, flush pending writes");
+        let redirected: Vec<u64> = self.overlay_writes.keys().copied().collect();
+        for fh in redirected {
+            let _ = self.save_local(fh);
+        }
+        self.file_cache.flush_all();
+        self.dump_stats().log_summary();
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.check_debug_dump();
+        self.check_revoked();
+        let dirname = Path::new(name);
+        debug!(parent = parent, name = %dirname.display(), "lookup");
+        match self.lookup(parent, name) {
+            Ok((attr, generation)) => {
+                self.remember(attr.ino);
+                reply.entry(&TTL, &attr, generation)
+            }
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        debug!(inode = ino, nlookup = nlookup, "forget");
+        if let Err(err) = self.forget(ino, nlookup) {
+            warn!(inode = ino, error = %err, "forget inode failed");
+        }
+    }
+
+    fn batch_forget(&mut self, _req: &Request<'_>, nodes: &[fuse_forget_one]) {
+        debug!(count = nodes.len(), "batch forget");
+        for node in nodes {
+            if let Err(err) = self.forget(node.nodeid, node.nlookup) {
+                warn!(inode = node.nodeid, error = %err, "forget inode failed");
+            }
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        self.check_debug_dump();
+        self.check_revoked();
+        match self.getattr(ino) {
+            Ok((attr, file)) => {
+                debug!(inode = ino, name = %file.name, nlink = attr.nlink, "getattr");
+                reply.attr(&TTL, &attr)
+            }
+            Err(e) => {
+                debug!(inode = ino, "getattr");
+                reply.error(e.into());
+            }
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        debug!(inode = ino, size = ?size, fh = ?fh, "setattr");
+        match self
+            .setattr(ino, mode, uid, gid, size, mtime, fh)
+            .and_then(|file| self.file_attr(ino, &file))
+        {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        self.check_debug_dump();
+        self.check_revoked();
+        match self
+            .readdir(ino)
+            .and_then(|entries| self.next_fh().map(|fh| (entries, fh)))
+        {
+            Ok((entries, fh)) => {
+                debug!(inode = ino, fh = fh, entries = entries.len(), "opendir");
+                self.dir_handles.insert(fh, entries);
+                reply.opened(fh, 0);
+            }
+            Err(e) => {
+                debug!(inode = ino, "opendir");
+                reply.error(e.into());
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        debug!(inode = ino, fh = fh, offset = offset, "readdir");
+        let entries = match self.dir_handles.get(&fh) {
+            Some(entries) => entries,
+            None => {
+                reply.error(libc::EBADF);
+                return;
+            }
+        };
+        // The offset of an entry is the offset of the next one,
+        // so a non-zero offset means entries before it have been seen.
+        for (i, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            let buffer_full = reply.add(*ino, i as i64 + 1, *kind, name);
+            if buffer_full {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        debug!(inode = ino, fh = fh, "releasedir");
+        self.dir_handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.check_debug_dump();
+        self.check_revoked();
+        let file = match self.file(ino) {
+            Ok(file) => file,
+            Err(e) => {
+                debug!(inode = ino, "open file");
+                reply.error(e.into());
+                return;
+            }
+        };
+        let writable = flags & libc::O_ACCMODE != libc::O_RDONLY;
+        if writable && self.is_trashed(ino).unwrap_or(true) {
+            debug!(inode = ino, "open trashed file for writing");
+            reply.error(libc::EPERM);
+            return;
+        }
+        let fh = match self.next_fh() {
+            Ok(fh) => fh,
+            Err(e) => {
+                debug!(inode = ino, "open file");
+                reply.error(e.into());
+                return;
+            }
+        };
+        debug!(inode = ino, name = %file.name, "open file");
+        if file.is_thumbnail() {
+            if writable {
+                reply.error(libc::EROFS);
+                return;
+            }
+            // reported size is zero, reads must bypass the page cache
+            match self.open_thumbnail(fh, &file) {
+                Ok(()) => reply.opened(fh, fuser::consts::FOPEN_DIRECT_IO),
+                Err(e) => reply.error(e.into()),
+            }
+            return;
+        }
+        if file.is_version() {
+            if writable {
+                reply.error(libc::EROFS);
+                return;
+            }
+            match self.open_version(fh, &file) {
+                Ok(()) => reply.opened(fh, 0),
+                Err(e) => reply.error(e.into()),
+            }
+            return;
+        }
+        let open_flags = if self.config.direct_io {
+            fuser::consts::FOPEN_DIRECT_IO
+        } else {
+            0
+        };
+        if !writable {
+            match self.open_local(fh, ino) {
+                Ok(true) => {
+                    reply.opened(fh, open_flags);
+                    return;
                 }
+                // never uploaded, there is nothing on the drive to fall back to
+                Ok(false) if file.is_local() => {
+                    reply.error(libc::ENOENT);
+                    return;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    reply.error(e.into());
+                    return;
+                }
+            }
+        }
+        if writable {
+            if let Err(e) = self.open_writable(fh, ino, &file, flags) {
+                reply.error(e.into());
+                return;
             }
+        } else {
+            self.file_cache.open(fh, &file, false);
+            self.file_cache.prefetch_on_open(fh);
+        }
+        reply.opened(fh, open_flags);
+    }
 
-            if !to_remove.is_empty() {
-                for name in to_remove {
-                    if let Some(ino_remove) = inode.children.remove(&name) {
-                        debug!(inode = ino_remove, name = %Path::new(&name).display(), "remove outdated inode");
-                        self.files.remove(&ino_remove);
-                        self.inodes.remove(&ino_remove);
-                    }
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        debug!(inode = ino, fh = fh, "release file");
+        if self.thumbnails.remove(&fh).is_some()
+            || self.versions.remove(&fh).is_some()
+            || self.local_files.remove(&fh).is_some()
+        {
+            reply.ok();
+            return;
+        }
+        let saved = self.save_local(fh);
+        self.overlay_writes.remove(&fh);
+        if let Err(e) = saved {
+            let _ = self.file_cache.release(fh);
+            reply.error(e.into());
+            return;
+        }
+        match self.file_cache.release(fh) {
+            Ok(uploaded) => {
+                if let Some(file_id) = uploaded {
+                    self.mark_clean(ino, file_id);
                 }
+                reply.ok()
             }
-            self.inodes.insert(ino, inode.clone());
+            Err(e) => reply.error(e.into()),
         }
+    }
 
-        for child_ino in inode.children.values().skip(offset as usize) {
-            let file = self.files.get(child_ino).ok_or(Error::ChildNotFound)?;
-            entries.push((*child_ino, file.r#type.into(), file.name.clone()));
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        debug!(inode = ino, fh = fh, "flush");
+        // upload on every close, so that failures are reported by close(2)
+        match self.flush_file(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
         }
-        Ok(entries)
     }
 
-    fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
-        let file = self.files.get(&ino).ok_or(Error::NoEntry)?;
-        debug!(inode = ino, name = %file.name, fh = fh, offset = offset, size = size, "read");
-        if offset >= file.size as i64 {
-            return Ok(Bytes::new());
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        debug!(inode = ino, fh = fh, "fsync");
+        match self.flush_file(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
         }
-        let size = std::cmp::min(size, file.size.saturating_sub(offset as u64) as u32);
-        self.file_cache.read(fh, offset, size)
     }
-}
 
-impl Filesystem for AliyunDriveFileSystem {
-    fn init(
+    fn read(
         &mut self,
         _req: &Request<'_>,
-        _config: &mut fuser::KernelConfig,
-    ) -> Result<(), libc::c_int> {
-        if let Err(e) = self.init() {
-            return Err(e.into());
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read(ino, fh, offset, size) {
+            Ok(data) => {
+                Stats::incr(&self.stats.bytes_read, data.len() as u64);
+                reply.data(&data)
+            }
+            Err(e) => reply.error(e.into()),
         }
-        Ok(())
     }
 
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let dirname = Path::new(name);
-        debug!(parent = parent, name = %dirname.display(), "lookup");
-        match self.lookup(parent, name) {
-            Ok(attr) => reply.entry(&TTL, &attr, 0),
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        debug!(
+            inode = ino,
+            fh = fh,
+            offset = offset,
+            size = data.len(),
+            "write"
+        );
+        match self.write(ino, fh, offset, data) {
+            Ok(written) => reply.written(written),
             Err(e) => reply.error(e.into()),
         }
     }
 
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        if let Some(file) = self.files.get(&ino) {
-            debug!(inode = ino, name = %file.name, "getattr");
-            reply.attr(&TTL, &file.to_file_attr(ino))
-        } else {
-            debug!(inode = ino, "getattr");
-            reply.error(libc::ENOENT);
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        debug!(inode = ino, name = %Path::new(name).display(), size = size, "getxattr");
+        match self.getxattr(ino, name) {
+            Ok(data) if size == 0 => reply.size(data.len() as u32),
+            Ok(data) if data.len() > size as usize => reply.error(libc::ERANGE),
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e.into()),
         }
     }
 
-    fn readdir(
+    fn setxattr(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
+        name: &OsStr,
+        value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
     ) {
-        debug!(inode = ino, offset = offset, "readdir");
-        match self.readdir(ino, offset) {
-            Ok(entries) => {
-                // Offset of 0 means no offset.
-                // Non-zero offset means the passed offset has already been seen,
-                // and we should start after it.
-                let offset_add = if offset == 0 { 0 } else { offset + 1 };
-                for (i, (ino, kind, name)) in entries.into_iter().enumerate() {
-                    let buffer_full = reply.add(ino, offset_add + i as i64, kind, name);
-                    if buffer_full {
-                        break;
-                    }
-                }
-                reply.ok();
-            }
+        debug!(inode = ino, name = %Path::new(name).display(), "setxattr");
+        match self.setxattr(ino, name, value) {
+            Ok(_) => reply.ok(),
             Err(e) => reply.error(e.into()),
         }
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
-        if let Some((file_id, file_name, file_size)) = self
-            .files
-            .get(&ino)
-            .map(|f| (f.id.clone(), f.name.clone(), f.size))
-        {
-            debug!(inode = ino, name = %file_name, "open file");
-            let fh = self.next_fh();
-            self.file_cache.open(fh, file_id, file_size);
-            reply.opened(fh, 0);
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!(inode = ino, size = size, "listxattr");
+        let mut names = match self.file(ino) {
+            Ok(file) => file.xattr_names(),
+            Err(e) => return reply.error(e.into()),
+        };
+        if ino == FUSE_ROOT_ID {
+            names.extend([XATTR_USER_ID, XATTR_MEMBERSHIP]);
+        }
+        let data = names
+            .iter()
+            .flat_map(|name| name.bytes().chain([0]))
+            .collect::<Vec<u8>>();
+        if size == 0 {
+            reply.size(data.len() as u32);
+        } else if data.len() > size as usize {
+            reply.error(libc::ERANGE);
         } else {
-            debug!(inode = ino, "open file");
-            reply.error(libc::ENOENT);
+            reply.data(&data);
         }
     }
 
-    fn release(
+    fn rename(
         &mut self,
         _req: &Request<'_>,
-        ino: u64,
-        fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
-        debug!(inode = ino, fh = fh, "release file");
-        self.file_cache.release(fh);
-        reply.ok();
+        debug!(
+            parent = parent,
+            name = %Path::new(name).display(),
+            newparent = newparent,
+            newname = %Path::new(newname).display(),
+            flags = flags,
+            "rename"
+        );
+        match self.rename(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
     }
 
-    fn read(
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        debug!(parent = parent, name = %Path::new(name).display(), "mkdir");
+        match self.mkdir(parent, name) {
+            Ok((attr, generation)) => {
+                self.remember(attr.ino);
+                reply.entry(&TTL, &attr, generation)
+            }
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        debug!(parent = parent, name = %Path::new(name).display(), flags = flags, "create");
+        match self.create(parent, name, flags) {
+            Ok((attr, generation, fh)) => {
+                self.remember(attr.ino);
+                let flags = if self.config.direct_io {
+                    fuser::consts::FOPEN_DIRECT_IO
+                } else {
+                    0
+                };
+                reply.created(&TTL, &attr, generation, fh, flags)
+            }
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn copy_file_range(
         &mut self,
         _req: &Request<'_>,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        debug!(
+            ino_in = ino_in,
+            offset_in = offset_in,
+            ino_out = ino_out,
+            offset_out = offset_out,
+            len = len,
+            "copy_file_range"
+        );
+        match self.copy_file_range(ino_in, offset_in, ino_out, fh_out, offset_out, len) {
+            Ok(size) => reply.written(size),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn bmap(&mut self, _req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        debug!(inode = ino, blocksize = blocksize, idx = idx, "bmap");
+        // there are no physical blocks, map to 512-byte sectors of BLOCK_SIZE blocks
+        reply.bmap(idx * (BLOCK_SIZE / 512));
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
         ino: u64,
         fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
     ) {
-        match self.read(ino, fh, offset, size) {
-            Ok(data) => reply.data(&data),
+        debug!(inode = ino, fh = fh, cmd = cmd, "ioctl");
+        match self.ioctl(req.uid(), ino, cmd, in_data) {
+            Ok(data) => reply.ioctl(0, &data),
             Err(e) => reply.error(e.into()),
         }
     }
 }
 
+/// Shadow listed files with the regular files of the same name in a local overlay
+/// directory, local files missing from the drive are added
+fn merge_overlay(dir: &Path, parent: &AliyunFile, files: &mut Vec<AliyunFile>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // nothing has been written below this directory yet
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+        Err(err) => {
+            warn!(path = %dir.display(), error = %err, "read local overlay directory failed");
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let updated_at = metadata
+            .modified()
+            .map(DateTime::from)
+            .unwrap_or_else(|_| DateTime::now());
+        match files.iter_mut().find(|file| file.name == name) {
+            Some(file) if matches!(file.r#type, crate::drive::FileType::File) => {
+                file.size = metadata.len();
+                file.updated_at = updated_at;
+            }
+            Some(_) => {
+                warn!(path = %entry.path().display(), "local overlay file shadows a folder, ignored")
+            }
+            None => files.push(AliyunFile::new_local(
+                parent,
+                name,
+                metadata.len(),
+                updated_at,
+            )),
+        }
+    }
+}
+
 impl From<crate::drive::FileType> for FileType {
     fn from(typ: crate::drive::FileType) -> Self {
         use crate::drive::FileType as AliyunFileType;
@@ -293,10 +2651,158 @@ impl From<crate::drive::FileType> for FileType {
 }
 
 impl AliyunFile {
+    fn new_shared_dir() -> Self {
+        let mut file = Self::new_root();
+        file.name = SHARED_DIR_NAME.to_string();
+        file.id = SHARED_DIR_ID.to_string();
+        file
+    }
+
+    fn new_trash_dir() -> Self {
+        let mut file = Self::new_root();
+        file.name = TRASH_DIR_NAME.to_string();
+        file.id = TRASH_DIR_ID.to_string();
+        file
+    }
+
+    fn new_control_file() -> Self {
+        let mut file = Self::new_root();
+        file.name = CONTROL_FILE_NAME.to_string();
+        file.id = CONTROL_FILE_ID.to_string();
+        file.r#type = crate::drive::FileType::File;
+        file
+    }
+
+    /// Virtual `.<name>.thumb.jpg` sidecar file of an image or video with a thumbnail
+    fn new_thumbnail(file: &AliyunFile) -> Option<Self> {
+        if !matches!(file.category.as_deref(), Some("image" | "video")) {
+            return None;
+        }
+        let mut thumbnail = file.clone();
+        thumbnail.thumbnail = Some(file.thumbnail.clone()?);
+        thumbnail.name = format!(".{}.thumb.jpg", file.name);
+        thumbnail.id = format!("{}{}", THUMBNAIL_ID_PREFIX, file.id);
+        thumbnail.size = 0;
+        thumbnail.category = Some("image".to_string());
+        Some(thumbnail)
+    }
+
+    fn is_thumbnail(&self) -> bool {
+        self.id.starts_with(THUMBNAIL_ID_PREFIX)
+    }
+
+    /// Virtual `<name><suffix>` directory listing the historical versions of a file
+    fn new_versions_dir(file: &AliyunFile, suffix: &str) -> Option<Self> {
+        if !matches!(file.r#type, crate::drive::FileType::File)
+            || !file.has_xattrs()
+            || file.trashed_at.is_some()
+        {
+            return None;
+        }
+        let mut dir = file.clone();
+        dir.name = format!("{}{}", file.name, suffix);
+        dir.id = format!("{}{}", VERSIONS_DIR_ID_PREFIX, file.id);
+        dir.r#type = crate::drive::FileType::Folder;
+        dir.size = 0;
+        dir.category = None;
+        dir.thumbnail = None;
+        dir.video_media_metadata = None;
+        dir.image_media_metadata = None;
+        Some(dir)
+    }
+
+    /// Historical version of a file, named by the time it was created
+    fn new_version(file_id: &str, version: FileVersion) -> Self {
+        let mut file = Self::new_root();
+        file.name = version.created_at.to_rfc3339();
+        file.id = format!("{}{}/{}", VERSION_ID_PREFIX, file_id, version.version_id);
+        file.r#type = crate::drive::FileType::File;
+        file.size = version.size;
+        file.created_at = version.created_at.clone();
+        file.updated_at = version.created_at;
+        file
+    }
+
+    fn is_version(&self) -> bool {
+        self.id.starts_with(VERSIONS_DIR_ID_PREFIX) || self.id.starts_with(VERSION_ID_PREFIX)
+    }
+
+    /// File only in the local overlay, not uploaded yet
+    fn new_local(parent: &AliyunFile, name: String, size: u64, updated_at: DateTime) -> Self {
+        let mut file = Self::new_root();
+        file.id = format!("{}{}/{}", LOCAL_ID_PREFIX, parent.id, name);
+        file.name = name;
+        file.r#type = crate::drive::FileType::File;
+        file.parent_file_id = Some(parent.id.clone());
+        file.size = size;
+        file.created_at = updated_at.clone();
+        file.updated_at = updated_at;
+        file
+    }
+
+    fn is_local(&self) -> bool {
+        self.id.starts_with(LOCAL_ID_PREFIX)
+    }
+
+    /// Names of the extended attributes available on the file
+    fn xattr_names(&self) -> Vec<&'static str> {
+        if !self.has_xattrs() {
+            return Vec::new();
+        }
+        // the audio URL is not listed, it needs an API call and may not exist yet
+        let mut names = vec![XATTR_PATH, XATTR_STARRED];
+        names.extend(self.media_xattrs().into_iter().map(|(name, _)| name));
+        names
+    }
+
+    fn is_audio(&self) -> bool {
+        matches!(self.r#type, crate::drive::FileType::File)
+            && self.category.as_deref() == Some("audio")
+    }
+
+    /// Extended attributes from the video or image metadata, only those present
+    fn media_xattrs(&self) -> Vec<(&'static str, String)> {
+        let mut attrs = Vec::new();
+        let mut push = |name, value: Option<String>| {
+            if let Some(value) = value {
+                attrs.push((name, value));
+            }
+        };
+        if let Some(video) = self.video_media_metadata.as_ref() {
+            push(XATTR_DURATION, video.duration.map(|v| v.to_string()));
+            push(XATTR_WIDTH, video.width.map(|v| v.to_string()));
+            push(XATTR_HEIGHT, video.height.map(|v| v.to_string()));
+        }
+        if let Some(image) = self.image_media_metadata.as_ref() {
+            push(XATTR_IMAGE_WIDTH, image.width.map(|v| v.to_string()));
+            push(XATTR_IMAGE_HEIGHT, image.height.map(|v| v.to_string()));
+            if let Some(exif) = image.exif_info.as_ref() {
+                push(XATTR_GPS_LAT, exif.gps_latitude.map(|v| v.to_string()));
+                push(XATTR_GPS_LON, exif.gps_longitude.map(|v| v.to_string()));
+                push(XATTR_CAMERA_MAKE, exif.make.clone());
+                push(XATTR_CAMERA_MODEL, exif.model.clone());
+                push(XATTR_CAPTURE_TIME, exif.date_time_original.clone());
+            }
+        }
+        attrs
+    }
+
+    /// Virtual files and files in shared drives have no extended attributes
+    fn has_xattrs(&self) -> bool {
+        let is_virtual = [SHARED_DIR_ID, TRASH_DIR_ID, CONTROL_FILE_ID].contains(&self.id.as_str());
+        !is_virtual
+            && !self.is_thumbnail()
+            && !self.is_version()
+            && !self.is_local()
+            && self.drive_id.is_none()
+    }
+
     fn to_file_attr(&self, ino: u64) -> FileAttr {
         let kind = self.r#type.into();
         let perm = if matches!(kind, FileType::Directory) {
             0o755
+        } else if self.trashed_at.is_some() {
+            0o444
         } else {
             0o644
         };
@@ -310,7 +2816,7 @@ impl AliyunFile {
             size: self.size,
             blocks,
             atime: UNIX_EPOCH,
-            mtime: *self.updated_at,
+            mtime: **self.trashed_at.as_ref().unwrap_or(&self.updated_at),
             ctime: *self.created_at,
             crtime: *self.created_at,
             kind,
@@ -324,3 +2830,411 @@ impl AliyunFile {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{mock_fs, MockDrive};
+
+    fn mount(drive: &MockDrive) -> AliyunDriveFileSystem<MockDrive> {
+        let mut fs = mock_fs(drive);
+        fs.init_root().unwrap();
+        fs
+    }
+
+    fn names(entries: Vec<(u64, FileType, String)>) -> Vec<String> {
+        entries.into_iter().map(|(_, _, name)| name).collect()
+    }
+
+    #[test]
+    fn write_file_replacing_refuses_symlinks() {
+        let dir = std::env::temp_dir().join(format!("vfs-test.{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("target");
+        let link = dir.join("dump.json");
+        fs::write(&target, b"keep").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        assert!(write_file_replacing(&link, b"dump").is_err());
+        assert_eq!(fs::read(&target).unwrap(), b"keep");
+        fs::remove_file(&link).unwrap();
+        write_file_replacing(&link, b"dump").unwrap();
+        assert_eq!(fs::read(&link).unwrap(), b"dump");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn index_drive_lists_files_up_to_depth() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        let deep = drive.add_folder(&docs, "deep");
+        drive.add_file("root", "a.txt", b"a");
+        drive.add_file(&docs, "b.txt", b"bb");
+        drive.add_file(&deep, "c.txt", b"ccc");
+        let stats = Stats::default();
+
+        let index = index_drive(&drive, &stats, 2, None);
+        assert_eq!(
+            index,
+            vec![("/a.txt".to_string(), 1), ("/docs/b.txt".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn readdir_lists_drive_and_virtual_entries() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", b"hello");
+        drive.add_folder("root", "docs");
+        let mut fs = mount(&drive);
+
+        let names = names(fs.readdir(FUSE_ROOT_ID).unwrap());
+        for name in [".", "..", "a.txt", "docs", SHARED_DIR_NAME, TRASH_DIR_NAME] {
+            assert!(names.iter().any(|n| n == name), "missing {}", name);
+        }
+    }
+
+    #[test]
+    fn drive_files_hide_virtual_entries() {
+        let drive = MockDrive::new();
+        let trash_id = drive.add_folder("root", TRASH_DIR_NAME);
+        let mut fs = mount(&drive);
+        let entries = names(fs.readdir(FUSE_ROOT_ID).unwrap());
+        let count = entries
+            .iter()
+            .filter(|name| *name == TRASH_DIR_NAME)
+            .count();
+        assert_eq!(count, 1);
+        let (attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new(TRASH_DIR_NAME)).unwrap();
+        assert_eq!(fs.file(attr.ino).unwrap().id, trash_id);
+    }
+
+    #[test]
+    fn readdir_keeps_listing_order() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_file(&docs, "b.txt", b"b");
+        drive.add_file(&docs, "a.txt", b"a");
+        drive.add_file(&docs, "c.txt", b"c");
+        let mut fs = mount(&drive);
+        let (attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("docs")).unwrap();
+
+        // the mock drive lists by name, a drive listing by another order is
+        // simulated with a warmed listing
+        let mut listing = drive.list_all(&docs).unwrap();
+        listing.reverse();
+        fs.warmup_state()
+            .listings
+            .insert(docs.clone(), (Instant::now(), listing));
+        assert_eq!(
+            names(fs.readdir(attr.ino).unwrap()),
+            [".", "..", "c.txt", "b.txt", "a.txt"]
+        );
+    }
+
+    #[test]
+    fn warmup_inserts_inodes() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_file(&docs, "b.txt", b"hello");
+        let mut fs = mount(&drive);
+        fs.warmup(2);
+        while !fs.warmup_state().finished {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let (attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("docs")).unwrap();
+        let children = fs.inodes.children_of(attr.ino).unwrap();
+        assert!(children.contains_key(OsStr::new("b.txt")));
+        assert!(fs.warmup_state().listings.is_empty());
+    }
+
+    #[test]
+    fn expired_warmed_listing_is_not_used() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", b"a");
+        let mut fs = mount(&drive);
+        let expired = Instant::now() - fs.config.attr_cache_ttl;
+        fs.warmup_state()
+            .listings
+            .insert("root".to_string(), (expired, Vec::new()));
+        assert!(names(fs.readdir(FUSE_ROOT_ID).unwrap()).contains(&"a.txt".to_string()));
+    }
+
+    #[test]
+    fn readdir_drops_files_removed_on_the_drive() {
+        let drive = MockDrive::new();
+        let file_id = drive.add_file("root", "a.txt", b"hello");
+        let mut fs = mount(&drive);
+        assert!(names(fs.readdir(FUSE_ROOT_ID).unwrap()).contains(&"a.txt".to_string()));
+
+        drive.trash_file(&file_id).unwrap();
+        assert!(!names(fs.readdir(FUSE_ROOT_ID).unwrap()).contains(&"a.txt".to_string()));
+    }
+
+    #[test]
+    fn removed_inode_is_reused_only_after_forget() {
+        let drive = MockDrive::new();
+        let file_id = drive.add_file("root", "a.txt", b"hello");
+        let mut fs = mount(&drive);
+        let (attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("a.txt")).unwrap();
+        fs.remember(attr.ino);
+
+        drive.trash_file(&file_id).unwrap();
+        fs.readdir(FUSE_ROOT_ID).unwrap();
+        assert!(matches!(fs.getattr(attr.ino), Err(Error::NoEntry)));
+        assert_ne!(fs.next_inode(), attr.ino);
+
+        fs.forget(attr.ino, 1).unwrap();
+        assert_eq!(fs.next_inode(), attr.ino);
+    }
+
+    #[test]
+    fn forget_all_keeps_pending_uploads() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_file(&docs, "a.txt", b"hello");
+        drive.add_file("root", "b.txt", b"hello");
+        let mut fs = mount(&drive);
+        let (docs_attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("docs")).unwrap();
+        let (a_attr, _) = fs.lookup(docs_attr.ino, OsStr::new("a.txt")).unwrap();
+        let (b_attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("b.txt")).unwrap();
+        fs.remember(b_attr.ino);
+        let (mut inode, file) = fs.inodes.get(a_attr.ino).unwrap().unwrap();
+        inode.dirty = true;
+        fs.inodes.insert(a_attr.ino, inode, file).unwrap();
+
+        fs.forget_all().unwrap();
+        assert!(fs.getattr(a_attr.ino).is_ok());
+        assert!(fs.getattr(docs_attr.ino).is_ok());
+        assert!(matches!(fs.getattr(b_attr.ino), Err(Error::NoEntry)));
+        assert!(!fs.free_inodes.contains(&b_attr.ino));
+    }
+
+    #[test]
+    fn copy_file_range_replaces_destination() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", b"hello");
+        let old_id = drive.add_file("root", "b.txt", b"old");
+        let mut fs = mount(&drive);
+        let (src, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("a.txt")).unwrap();
+        let (dest, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("b.txt")).unwrap();
+        let fh = fs.next_fh().unwrap();
+        fs.file_cache.open(fh, &fs.file(dest.ino).unwrap(), true);
+
+        assert_eq!(
+            fs.copy_file_range(src.ino, 0, dest.ino, fh, 0, 5).unwrap(),
+            5
+        );
+        let copied = fs.file(dest.ino).unwrap();
+        assert_eq!(copied.name, "b.txt");
+        assert_eq!(&drive.content(&copied.id).unwrap()[..], b"hello");
+        assert!(drive.get_file(&old_id).is_err());
+        let names: Vec<_> = drive
+            .list_all("root")
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn rename_replaces_destination_on_the_drive() {
+        let drive = MockDrive::new();
+        let src_id = drive.add_file("root", "a.txt", b"hello");
+        let old_id = drive.add_file("root", "b.txt", b"old");
+        let mut fs = mount(&drive);
+        fs.lookup(FUSE_ROOT_ID, OsStr::new("b.txt")).unwrap();
+
+        fs.rename(
+            FUSE_ROOT_ID,
+            OsStr::new("a.txt"),
+            FUSE_ROOT_ID,
+            OsStr::new("b.txt"),
+            0,
+        )
+        .unwrap();
+        assert!(drive.get_file(&old_id).is_err());
+        assert_eq!(drive.get_file(&src_id).unwrap().name, "b.txt");
+        let (attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("b.txt")).unwrap();
+        assert_eq!(fs.file(attr.ino).unwrap().id, src_id);
+    }
+
+    #[test]
+    fn rename_refuses_virtual_entries() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", b"hello");
+        let mut fs = mount(&drive);
+        fs.readdir(FUSE_ROOT_ID).unwrap();
+
+        let res = fs.rename(
+            FUSE_ROOT_ID,
+            OsStr::new(TRASH_DIR_NAME),
+            FUSE_ROOT_ID,
+            OsStr::new("trash"),
+            0,
+        );
+        assert!(matches!(res, Err(Error::PermissionDenied)));
+        let res = fs.rename(
+            FUSE_ROOT_ID,
+            OsStr::new("a.txt"),
+            FUSE_ROOT_ID,
+            OsStr::new(TRASH_DIR_NAME),
+            0,
+        );
+        assert!(matches!(res, Err(Error::PermissionDenied)));
+        assert!(fs.lookup(FUSE_ROOT_ID, OsStr::new(TRASH_DIR_NAME)).is_ok());
+    }
+
+    #[test]
+    fn files_below_trashed_folders_are_read_only() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        let sub = drive.add_folder(&docs, "sub");
+        let mut fs = mount(&drive);
+        let (docs_attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("docs")).unwrap();
+        let (sub_attr, _) = fs.lookup(docs_attr.ino, OsStr::new("sub")).unwrap();
+        let (inode, mut file) = fs.inodes.get(docs_attr.ino).unwrap().unwrap();
+        file.trashed_at = Some(DateTime::now());
+        fs.inodes.insert(docs_attr.ino, inode, file).unwrap();
+
+        let res = fs.mkdir(sub_attr.ino, OsStr::new("new"));
+        assert!(matches!(res, Err(Error::PermissionDenied)));
+        let res = fs.create(sub_attr.ino, OsStr::new("new.txt"), libc::O_WRONLY);
+        assert!(matches!(res, Err(Error::PermissionDenied)));
+        assert!(drive.list_all(&sub).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncate_keeps_prefix_downloaded_in_chunks() {
+        let drive = MockDrive::new();
+        let file_id = drive.add_file("root", "a.txt", b"hello world, hello drive");
+        let mut fs = mount(&drive);
+        let (attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("a.txt")).unwrap();
+
+        fs.truncate(attr.ino, None, 20).unwrap();
+        assert_eq!(
+            &drive.content(&file_id).unwrap()[..],
+            b"hello world, hello d"
+        );
+        assert_eq!(drive.downloads(), 2);
+        assert_eq!(fs.file(attr.ino).unwrap().size, 20);
+    }
+
+    #[test]
+    fn writes_beyond_max_staged_size_are_refused() {
+        let drive = MockDrive::new();
+        let mut fs = mount(&drive);
+        let (attr, _, fh) = fs
+            .create(FUSE_ROOT_ID, OsStr::new("a.txt"), libc::O_WRONLY)
+            .unwrap();
+
+        let res = fs.write(attr.ino, fh, 1 << 40, b"x");
+        assert!(matches!(res, Err(Error::FileTooLarge)));
+        let res = fs.write(attr.ino, fh, i64::MAX, b"x");
+        assert!(matches!(res, Err(Error::FileTooLarge)));
+        let res = fs.truncate(attr.ino, Some(fh), 1 << 40);
+        assert!(matches!(res, Err(Error::FileTooLarge)));
+        assert_eq!(fs.write(attr.ino, fh, 1020, b"abcd").unwrap(), 4);
+        assert_eq!(fs.file(attr.ino).unwrap().size, 1024);
+    }
+
+    #[test]
+    fn writes_to_existing_files_are_merged_on_flush() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", b"hello world, hello drive");
+        let mut fs = mount(&drive);
+        fs.lookup(FUSE_ROOT_ID, OsStr::new("a.txt")).unwrap();
+        let (attr, _, fh) = fs
+            .create(FUSE_ROOT_ID, OsStr::new("a.txt"), libc::O_WRONLY)
+            .unwrap();
+
+        fs.write(attr.ino, fh, 6, b"WORLD").unwrap();
+        fs.write(attr.ino, fh, 19, b"DRIVE!").unwrap();
+        assert_eq!(drive.downloads(), 0);
+        assert_eq!(fs.file(attr.ino).unwrap().size, 25);
+        fs.flush_file(attr.ino, fh).unwrap();
+        let file_id = fs.file(attr.ino).unwrap().id;
+        assert_eq!(
+            &drive.content(&file_id).unwrap()[..],
+            b"hello WORLD, hello DRIVE!"
+        );
+        assert_eq!(drive.downloads(), 2);
+    }
+
+    #[test]
+    fn setattr_updates_modification_time_on_the_drive() {
+        let drive = MockDrive::new();
+        let file_id = drive.add_file("root", "a.txt", b"hello");
+        let mut fs = mount(&drive);
+        let (attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("a.txt")).unwrap();
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        let file = fs
+            .setattr(
+                attr.ino,
+                None,
+                None,
+                None,
+                None,
+                Some(TimeOrNow::SpecificTime(mtime)),
+                None,
+            )
+            .unwrap();
+        assert_eq!(*file.updated_at, mtime);
+        assert_eq!(*drive.get_file(&file_id).unwrap().updated_at, mtime);
+    }
+
+    #[test]
+    fn lookup_lists_parent_on_demand() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_file(&docs, "b.txt", b"hello world");
+        let mut fs = mount(&drive);
+
+        let (docs_attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("docs")).unwrap();
+        assert_eq!(docs_attr.kind, FileType::Directory);
+        let (attr, _) = fs.lookup(docs_attr.ino, OsStr::new("b.txt")).unwrap();
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.size, 11);
+        assert!(matches!(
+            fs.lookup(docs_attr.ino, OsStr::new("missing")),
+            Err(Error::ChildNotFound)
+        ));
+    }
+
+    #[test]
+    fn getattr_counts_subdirectory_links() {
+        let drive = MockDrive::new();
+        let docs = drive.add_folder("root", "docs");
+        drive.add_folder(&docs, "a");
+        drive.add_folder(&docs, "b");
+        drive.add_file(&docs, "c.txt", b"c");
+        let mut fs = mount(&drive);
+
+        let (attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("docs")).unwrap();
+        fs.readdir(attr.ino).unwrap();
+        let (attr, file) = fs.getattr(attr.ino).unwrap();
+        assert_eq!(file.name, "docs");
+        assert_eq!(attr.nlink, 4);
+        assert!(matches!(fs.getattr(u64::MAX), Err(Error::NoEntry)));
+    }
+
+    #[test]
+    fn read_spans_multiple_chunks() {
+        let drive = MockDrive::new();
+        drive.add_file("root", "a.txt", b"hello world, hello drive");
+        let mut fs = mount(&drive);
+
+        let (attr, _) = fs.lookup(FUSE_ROOT_ID, OsStr::new("a.txt")).unwrap();
+        let file = fs.file(attr.ino).unwrap();
+        let fh = fs.next_fh().unwrap();
+        fs.file_cache.open(fh, &file, false);
+        assert_eq!(drive.downloads(), 0);
+
+        assert_eq!(&fs.read(attr.ino, fh, 0, 11).unwrap()[..], b"hello world");
+        assert!(drive.downloads() > 0);
+        assert_eq!(&fs.read(attr.ino, fh, 13, 100).unwrap()[..], b"hello drive");
+        assert!(fs.read(attr.ino, fh, 100, 10).unwrap().is_empty());
+    }
+}